@@ -0,0 +1,157 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Benchmark the effect of response compression on the time it takes a zkEVM node to sync.
+//!
+//! A zkEVM node syncs by fetching pages of blocks from the adaptor's query service. This benchmark
+//! serves a page of blocks full of signed transfers through the adaptor's compression middleware,
+//! once for each supported encoding and once uncompressed, and measures the time to produce each
+//! response and its size on the wire. Since requests are handled in memory, the time to transfer
+//! each response is estimated for a few link speeds, giving the time for a node to sync the page
+//! over that link.
+//!
+//! Run with `cargo bench --bench sync-compression`.
+
+use async_std::task::block_on;
+use ethers::{
+    prelude::*,
+    types::{transaction::eip2718::TypedTransaction, TransactionRequest},
+};
+use http_types::{
+    headers::{ACCEPT_ENCODING, CONTENT_ENCODING},
+    Method, Request, Response, Url,
+};
+use polygon_zkevm_adaptor::{
+    compression::{Compression, CompressionOptions},
+    query_service::PolygonZkevmBlock,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::time::{Duration, Instant};
+use zkevm::{polygon_zkevm::encode_transactions, EvmTransaction};
+
+/// Number of blocks in each page, the default page size of the query service.
+const BLOCKS_PER_PAGE: u64 = 100;
+
+/// Number of transactions in each block.
+const TRANSACTIONS_PER_BLOCK: u64 = 50;
+
+/// Number of distinct accounts sending transactions.
+const SENDERS: usize = 20;
+
+/// Number of times to serve the page with each encoding.
+const ITERATIONS: u32 = 20;
+
+/// Link speeds to estimate sync times for, in megabits per second.
+const LINK_SPEEDS_MBPS: [f64; 3] = [10., 100., 1000.];
+
+const CHAIN_ID: u64 = 1001;
+
+/// A page of blocks, as served by the query service's block range endpoint.
+fn page(rng: &mut StdRng) -> Vec<PolygonZkevmBlock> {
+    let senders: Vec<LocalWallet> = (0..SENDERS)
+        .map(|_| LocalWallet::new(&mut *rng).with_chain_id(CHAIN_ID))
+        .collect();
+    let recipients: Vec<Address> = (0..SENDERS).map(|_| rng.gen()).collect();
+    let mut nonces = vec![0u64; SENDERS];
+    (0..BLOCKS_PER_PAGE)
+        .map(|height| {
+            let transactions = (0..TRANSACTIONS_PER_BLOCK)
+                .map(|_| {
+                    let sender = rng.gen_range(0..SENDERS);
+                    let tx: TypedTransaction = TransactionRequest::new()
+                        .from(senders[sender].address())
+                        .to(recipients[rng.gen_range(0..SENDERS)])
+                        .value(rng.gen_range(1u64..1_000_000_000_000_000_000))
+                        .nonce(nonces[sender])
+                        .gas(21000)
+                        .gas_price(rng.gen_range(1_000_000_000u64..2_000_000_000))
+                        .chain_id(CHAIN_ID)
+                        .into();
+                    nonces[sender] += 1;
+                    let sig = senders[sender].sign_transaction_sync(&tx).unwrap();
+                    EvmTransaction::new(tx, sig)
+                })
+                .collect::<Vec<_>>();
+            PolygonZkevmBlock {
+                timestamp: 1_700_000_000 + height,
+                height,
+                l1_block: 1000 + height / 10,
+                transactions: encode_transactions(transactions).to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Serve `page` with `accept_encoding`, returning the response body and how long it took.
+async fn serve(page: &[PolygonZkevmBlock], accept_encoding: Option<&str>) -> (Vec<u8>, Duration) {
+    let body = serde_json::to_string(page).unwrap();
+    let mut app = tide::new();
+    app.with(Compression::new(&CompressionOptions::default()));
+    app.at("/blocks").get(move |_: tide::Request<()>| {
+        let body = body.clone();
+        async move {
+            Ok(tide::Response::builder(200)
+                .body(body)
+                .content_type(tide::http::mime::JSON)
+                .build())
+        }
+    });
+
+    let mut req = Request::new(Method::Get, Url::parse("http://localhost/blocks").unwrap());
+    if let Some(encoding) = accept_encoding {
+        req.insert_header(ACCEPT_ENCODING, encoding);
+    }
+    let start = Instant::now();
+    let mut res: Response = app.respond(req).await.unwrap();
+    let bytes = res.body_bytes().await.unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(
+        res.header(CONTENT_ENCODING).map(|value| value.as_str()),
+        accept_encoding,
+        "unexpected content encoding"
+    );
+    (bytes, elapsed)
+}
+
+fn transfer_time(bytes: usize, mbps: f64) -> Duration {
+    Duration::from_secs_f64(bytes as f64 * 8. / (mbps * 1_000_000.))
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let page = page(&mut rng);
+
+    let mut baseline = None;
+    println!(
+        "page of {BLOCKS_PER_PAGE} blocks with {TRANSACTIONS_PER_BLOCK} transactions each, \
+         {ITERATIONS} iterations"
+    );
+    for encoding in [None, Some("gzip"), Some("br")] {
+        let mut size = 0;
+        let mut total = Duration::ZERO;
+        for _ in 0..ITERATIONS {
+            let (bytes, elapsed) = block_on(serve(&page, encoding));
+            size = bytes.len();
+            total += elapsed;
+        }
+        let encode = total / ITERATIONS;
+        let (baseline_size, baseline_encode) = *baseline.get_or_insert((size, encode));
+        println!(
+            "{:<8} {size:>9} bytes ({:>5.1}% of uncompressed), served in {encode:?}",
+            encoding.unwrap_or("identity"),
+            100. * size as f64 / baseline_size as f64
+        );
+        for mbps in LINK_SPEEDS_MBPS {
+            let sync = encode + transfer_time(size, mbps);
+            let uncompressed = baseline_encode + transfer_time(baseline_size, mbps);
+            println!(
+                "    {mbps:>6} Mbit/s: {sync:>12?} per page ({:.1}x faster than uncompressed)",
+                uncompressed.as_secs_f64() / sync.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Pools of accounts for multi-wallet load tests.
+//!
+//! The `fund-accounts` binary derives a pool of accounts from a mnemonic, funds them and saves the
+//! pool to a file, which load tests can then [load](AccountPool::load) and
+//! [connect](AccountPool::connect) to get one [`FailoverSigner`] per account.
+
+#![cfg(any(test, feature = "testing"))]
+
+use crate::{connect_rpc_failover, FailoverOptions, FailoverSigner};
+use ethers::{
+    prelude::{MnemonicBuilder, Signer as _},
+    signers::{coins_bip39::English, WalletError},
+    types::Address,
+};
+use futures::future::join_all;
+use http_types::Url;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PoolAccount {
+    /// Index of the account in the pool's mnemonic.
+    pub index: u32,
+    pub address: Address,
+}
+
+/// A set of accounts derived from a single mnemonic.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AccountPool {
+    pub mnemonic: String,
+    pub accounts: Vec<PoolAccount>,
+}
+
+impl AccountPool {
+    /// Derive `count` accounts from `mnemonic`, starting at index `first_index`.
+    pub fn derive(mnemonic: &str, first_index: u32, count: u32) -> Result<Self, WalletError> {
+        let accounts = (first_index..first_index + count)
+            .map(|index| {
+                let wallet = MnemonicBuilder::<English>::default()
+                    .phrase(mnemonic)
+                    .index(index)?
+                    .build()?;
+                Ok(PoolAccount {
+                    index,
+                    address: wallet.address(),
+                })
+            })
+            .collect::<Result<_, WalletError>>()?;
+        Ok(Self {
+            mnemonic: mnemonic.into(),
+            accounts,
+        })
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = Address> + '_ {
+        self.accounts.iter().map(|account| account.address)
+    }
+
+    /// Connect a signer for each account in the pool to `providers`, in order of preference.
+    pub async fn connect(&self, providers: &[Url], opt: FailoverOptions) -> Vec<FailoverSigner> {
+        join_all(self.accounts.iter().map(|account| async move {
+            connect_rpc_failover(providers, &self.mnemonic, account.index, None, opt)
+                .await
+                .unwrap_or_else(|| panic!("unable to connect account {}", account.index))
+        }))
+        .await
+    }
+
+    pub fn save(&self, path: &PathBuf) {
+        let data = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    pub fn load(path: &PathBuf) -> Self {
+        let data = std::fs::read_to_string(path).unwrap();
+        serde_json::from_str(&data).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MNEMONIC: &str = "test test test test test test test test test test test junk";
+
+    #[test]
+    fn test_account_pool() {
+        let pool = AccountPool::derive(MNEMONIC, 1, 3).unwrap();
+        assert_eq!(
+            pool.accounts.iter().map(|a| a.index).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        // The second account of the well-known development mnemonic.
+        assert_eq!(
+            pool.accounts[0].address,
+            "0x70997970C51812dc3A010C7d01b50e0d17dc79C8"
+                .parse::<Address>()
+                .unwrap()
+        );
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("accounts.json");
+        pool.save(&path);
+        assert_eq!(AccountPool::load(&path), pool);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Authentication for transaction submission.
+//!
+//! When enabled, requests which submit transactions must carry a credential, either as an
+//! `X-Api-Key` header or as an `Authorization: Bearer` header. A credential is accepted if it is
+//! one of the configured API keys, or a JWT signed (HS256) with the configured secret. Read-only
+//! methods do not require a credential.
+//!
+//! Note that the zkEVM node forwards `eth_sendRawTransaction` to the adaptor without any
+//! credentials, so enabling authentication means users have to submit transactions to the adaptor
+//! directly.
+
+use crate::json_rpc::{is_submission, rpc_error_response};
+use clap::Args;
+use http_types::{headers::AUTHORIZATION, Method, StatusCode};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use snafu::Snafu;
+use std::sync::Arc;
+
+/// JSON-RPC error code for unauthorized requests.
+pub const UNAUTHORIZED: i64 = -32001;
+
+/// Header which can be used to provide an API key.
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct AuthOptions {
+    /// API keys which are allowed to submit transactions.
+    #[clap(
+        long = "api-key",
+        env = "ESPRESSO_ZKEVM_ADAPTOR_API_KEYS",
+        value_delimiter = ','
+    )]
+    pub api_keys: Vec<String>,
+
+    /// Secret used to verify JWTs (HS256) which are allowed to submit transactions.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_JWT_SECRET")]
+    pub jwt_secret: Option<String>,
+}
+
+impl AuthOptions {
+    pub fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty() || self.jwt_secret.is_some()
+    }
+}
+
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum AuthError {
+    #[snafu(display("missing credentials"))]
+    MissingCredentials,
+
+    #[snafu(display("invalid credentials"))]
+    InvalidCredentials,
+}
+
+/// Claims we read from a JWT, for logging purposes.
+///
+/// Expiration is checked by [`jsonwebtoken`] and does not need to be included here.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    #[serde(default)]
+    sub: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Authenticator {
+    api_keys: Vec<String>,
+    jwt: Option<(DecodingKey, Validation)>,
+}
+
+impl Authenticator {
+    pub fn new(opt: &AuthOptions) -> Self {
+        Self {
+            api_keys: opt.api_keys.clone(),
+            jwt: opt.jwt_secret.as_ref().map(|secret| {
+                (
+                    DecodingKey::from_secret(secret.as_bytes()),
+                    Validation::new(Algorithm::HS256),
+                )
+            }),
+        }
+    }
+
+    /// Check a credential provided by a client.
+    pub fn authorize(&self, credential: Option<&str>) -> Result<(), AuthError> {
+        let credential = credential.ok_or(AuthError::MissingCredentials)?;
+        if self
+            .api_keys
+            .iter()
+            .any(|key| constant_time_eq(key.as_bytes(), credential.as_bytes()))
+        {
+            return Ok(());
+        }
+        if let Some((key, validation)) = &self.jwt {
+            match decode::<Claims>(credential, key, validation) {
+                Ok(token) => {
+                    tracing::debug!("authorized JWT for {:?}", token.claims.sub);
+                    return Ok(());
+                }
+                Err(err) => tracing::debug!("invalid JWT: {err}"),
+            }
+        }
+        Err(AuthError::InvalidCredentials)
+    }
+}
+
+/// Compare secrets in time independent of the position of the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware requiring an [`Authenticator`] to accept transaction submissions.
+#[derive(Clone)]
+pub struct Auth {
+    authenticator: Arc<Authenticator>,
+}
+
+impl Auth {
+    pub fn new(authenticator: Authenticator) -> Self {
+        Self {
+            authenticator: Arc::new(authenticator),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for Auth {
+    async fn handle(
+        &self,
+        mut req: tide::Request<State>,
+        next: tide::Next<'_, State>,
+    ) -> tide::Result {
+        if req.method() != Method::Post || !is_submission(&mut req).await? {
+            return Ok(next.run(req).await);
+        }
+
+        let credential = match req.header(API_KEY_HEADER) {
+            Some(key) => Some(key.last().as_str()),
+            None => req
+                .header(AUTHORIZATION)
+                .and_then(|auth| auth.last().as_str().strip_prefix("Bearer ")),
+        };
+        match self.authenticator.authorize(credential) {
+            Ok(()) => Ok(next.run(req).await),
+            Err(err) => {
+                tracing::info!(
+                    "rejecting unauthorized submission from {:?}: {err}",
+                    req.peer_addr()
+                );
+                Ok(rpc_error_response(
+                    StatusCode::Unauthorized,
+                    UNAUTHORIZED,
+                    &err.to_string(),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[derive(Serialize)]
+    struct TestClaims {
+        sub: String,
+        exp: u64,
+    }
+
+    fn token(secret: &str, expires_in: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = TestClaims {
+            sub: "test".into(),
+            exp: (now as i64 + expires_in) as u64,
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let auth = Authenticator::new(&AuthOptions {
+            api_keys: vec!["key1".into(), "key2".into()],
+            jwt_secret: None,
+        });
+        auth.authorize(Some("key1")).unwrap();
+        auth.authorize(Some("key2")).unwrap();
+        assert_eq!(
+            auth.authorize(Some("key3")),
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(auth.authorize(None), Err(AuthError::MissingCredentials));
+    }
+
+    #[test]
+    fn test_jwt() {
+        let auth = Authenticator::new(&AuthOptions {
+            api_keys: vec![],
+            jwt_secret: Some("secret".into()),
+        });
+        auth.authorize(Some(&token("secret", 3600))).unwrap();
+        assert_eq!(
+            auth.authorize(Some(&token("wrong secret", 3600))),
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(
+            auth.authorize(Some(&token("secret", -3600))),
+            Err(AuthError::InvalidCredentials)
+        );
+    }
+}
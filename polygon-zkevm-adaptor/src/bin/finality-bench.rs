@@ -0,0 +1,361 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Measure the latency of each stage of finality for an L2 transaction.
+//!
+//! A marker transaction is submitted through the adaptor, and then we track when it is
+//!  1. included in an Espresso block,
+//!  2. available to the zkEVM node from the adaptor's query service,
+//!  3. executed by the zkEVM node (a receipt is available),
+//!  4. sequenced on the L1 (the HotShot commitment for its block is posted), and
+//!  5. verified on the L1 (a proof for its batch is accepted by the rollup contract).
+//!
+//! Stages 2-5 are tracked concurrently, since they don't necessarily complete in that order. Each
+//! stage must complete within the stage timeout. Stages which don't are left out of the report, and
+//! the benchmark exits with an error after writing it.
+
+use async_compatibility_layer::logging::{setup_backtrace, setup_logging};
+use async_std::{future::timeout, task::sleep};
+use clap::Parser;
+use ethers::{prelude::*, types::transaction::eip2718::TypedTransaction};
+use futures::{join, TryStreamExt};
+use hotshot_query_service::availability::BlockQueryData;
+use polygon_zkevm_adaptor::{config, query_service::PolygonZkevmBlock};
+use sequencer::SeqTypes;
+use sequencer_utils::connect_rpc;
+use serde::Serialize;
+use std::{
+    fmt::Display,
+    fs,
+    future::Future,
+    num::ParseIntError,
+    path::PathBuf,
+    process::exit,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use surf_disco::Url;
+use tide_disco::{error::ServerError, StatusCode};
+use zkevm::ZkEvm;
+use zkevm_contract_bindings::{i_hot_shot::IHotShot, PolygonZkEVM};
+
+/// How long to wait between checks of the zkEVM node and the adaptor.
+const L2_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Data attached to the marker transaction, to make it easy to find in logs and block explorers.
+const MARKER: &[u8] = b"espresso-zkevm-finality-bench";
+
+/// Run a finality benchmark against a running deployment.
+#[derive(Parser)]
+pub struct Options {
+    /// URL of a HotShot sequencer node.
+    #[arg(long, env = "ESPRESSO_SEQUENCER_URL")]
+    pub sequencer_url: Url,
+
+    /// URL of the adaptor JSON-RPC service, used to submit the marker transaction.
+    #[arg(long, env = "ESPRESSO_ZKEVM_FINALITY_BENCH_ADAPTOR_RPC")]
+    pub adaptor_rpc: Url,
+
+    /// URL of the adaptor query service.
+    #[arg(long, env = "ESPRESSO_ZKEVM_FINALITY_BENCH_ADAPTOR_QUERY")]
+    pub adaptor_query: Url,
+
+    /// URL of the L1 JSON-RPC provider.
+    #[arg(long, env = "ESPRESSO_ZKEVM_L1_PROVIDER")]
+    pub l1_provider: Url,
+
+    /// URL of the L2 zkEVM node JSON-RPC provider.
+    #[arg(long, env = "ESPRESSO_ZKEVM_L2_PROVIDER")]
+    pub l2_provider: Url,
+
+    /// Address of the HotShot contract on the L1.
+    #[arg(long, env = "ESPRESSO_SEQUENCER_HOTSHOT_ADDRESS")]
+    pub hotshot_address: Address,
+
+    /// Address of the Polygon zkEVM rollup contract on the L1.
+    #[arg(long, env = "ESPRESSO_ZKEVM_FINALITY_BENCH_ROLLUP_ADDRESS")]
+    pub rollup_address: Address,
+
+    /// Mnemonic for a funded L2 account, used to send the marker transaction.
+    #[arg(long, env = "ESPRESSO_ZKEVM_FINALITY_BENCH_MNEMONIC")]
+    pub mnemonic: String,
+
+    /// Interval in milliseconds between checks of the L1 contracts.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_FINALITY_BENCH_L1_POLL_INTERVAL",
+        default_value = "1000",
+        value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(Duration::from_millis(arg.parse()?)) }
+    )]
+    pub l1_poll_interval: Duration,
+
+    /// Maximum time in milliseconds to wait for each stage, measured from submission.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_FINALITY_BENCH_STAGE_TIMEOUT",
+        default_value = "1800000",
+        value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(Duration::from_millis(arg.parse()?)) }
+    )]
+    pub stage_timeout: Duration,
+
+    /// Where to write the latency breakdown as JSON.
+    #[arg(long, env = "ESPRESSO_ZKEVM_FINALITY_BENCH_OUTPUT")]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Stage {
+    EspressoBlock,
+    AdaptorAvailable,
+    L2Receipt,
+    L1Sequenced,
+    L1Verified,
+}
+
+#[derive(Debug, Serialize)]
+struct StageLatency {
+    stage: Stage,
+    /// Time since the marker transaction was submitted.
+    latency_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    transaction: H256,
+    block_height: u64,
+    stages: Vec<StageLatency>,
+    /// Stages which did not complete within the stage timeout.
+    timed_out: Vec<Stage>,
+}
+
+impl Report {
+    fn print(&self) {
+        tracing::info!(
+            "finality breakdown for {:?} in block {}",
+            self.transaction,
+            self.block_height
+        );
+        let mut previous = 0;
+        for stage in &self.stages {
+            tracing::info!(
+                "{:<20} {:>8} ms (+{} ms)",
+                format!("{:?}", stage.stage),
+                stage.latency_ms,
+                stage.latency_ms.saturating_sub(previous)
+            );
+            previous = previous.max(stage.latency_ms);
+        }
+        for stage in &self.timed_out {
+            tracing::error!("{:<20} timed out", format!("{stage:?}"));
+        }
+    }
+}
+
+#[async_std::main]
+async fn main() {
+    setup_logging();
+    setup_backtrace();
+
+    let opt: Options = config::parse("finality-bench", |_| Ok(()));
+
+    let l2 = connect_rpc(&opt.l2_provider, &opt.mnemonic, 0, None)
+        .await
+        .expect("unable to connect to L2");
+    let adaptor = Provider::<Http>::try_from(opt.adaptor_rpc.as_str()).unwrap();
+    let l1 = Arc::new(Provider::<Http>::try_from(opt.l1_provider.as_str()).unwrap());
+    let hotshot = IHotShot::new(opt.hotshot_address, l1.clone());
+    let rollup = PolygonZkEVM::new(opt.rollup_address, l1);
+    let zkevm = ZkEvm {
+        chain_id: l2.get_chainid().await.unwrap().as_u64(),
+    };
+
+    let sequencer = surf_disco::Client::<hotshot_query_service::Error>::new(opt.sequencer_url);
+    sequencer.connect(None).await;
+    let adaptor_query =
+        surf_disco::Client::<ServerError>::new(opt.adaptor_query.join("availability").unwrap());
+    adaptor_query.connect(None).await;
+
+    // Subscribe to blocks before submitting, so we can't miss the block with the marker.
+    let from = sequencer
+        .get::<u64>("status/block-height")
+        .send()
+        .await
+        .unwrap();
+    let mut blocks = sequencer
+        .socket(&format!("availability/stream/blocks/{from}"))
+        .subscribe::<BlockQueryData<SeqTypes>>()
+        .await
+        .unwrap();
+
+    // Sign the marker transaction using the L2 node to fill in the nonce and gas, but submit it
+    // through the adaptor, like a user would.
+    let mut tx: TypedTransaction = TransactionRequest::new()
+        .to(l2.address())
+        .value(0)
+        .data(MARKER.to_vec())
+        .into();
+    l2.fill_transaction(&mut tx, None).await.unwrap();
+    let signature = l2.signer().sign_transaction(&tx).await.unwrap();
+    let submitted = Instant::now();
+    let hash = adaptor
+        .send_raw_transaction(tx.rlp_signed(&signature))
+        .await
+        .unwrap()
+        .tx_hash();
+    tracing::info!("submitted marker transaction {hash:?}");
+
+    let find_block = async {
+        loop {
+            let block = match blocks.try_next().await {
+                Ok(Some(block)) => block,
+                Ok(None) => panic!("sequencer block stream ended"),
+                Err(err) => panic!("error in sequencer block stream: {err}"),
+            };
+            for txn in zkevm.vm_transactions(block.payload()) {
+                if txn.hash() == hash {
+                    return block.height();
+                }
+            }
+        }
+    };
+    let Ok(block_height) = timeout(opt.stage_timeout, find_block).await else {
+        tracing::error!(
+            "marker transaction was not sequenced in {:?}",
+            opt.stage_timeout
+        );
+        exit(1);
+    };
+    let mut stages = vec![stage(Stage::EspressoBlock, submitted)];
+    tracing::info!("marker transaction sequenced in block {block_height}");
+
+    // Each stage has the rest of the stage timeout, measured from submission.
+    let deadline = opt.stage_timeout.saturating_sub(submitted.elapsed());
+    let (adaptor_query, l2, hotshot, rollup) = (&adaptor_query, &l2, &hotshot, &rollup);
+    let adaptor_available = wait_for(
+        Stage::AdaptorAvailable,
+        submitted,
+        deadline,
+        L2_POLL_INTERVAL,
+        move || async move {
+            match adaptor_query
+                .get::<PolygonZkevmBlock>(&format!("block/{block_height}"))
+                .send()
+                .await
+            {
+                Ok(_) => Ok(true),
+                // The adaptor has not caught up yet.
+                Err(err) if err.status == StatusCode::NotFound => Ok(false),
+                Err(err) => Err(err),
+            }
+        },
+    );
+    let l2_receipt = wait_for(
+        Stage::L2Receipt,
+        submitted,
+        deadline,
+        L2_POLL_INTERVAL,
+        move || async move {
+            l2.get_transaction_receipt(hash)
+                .await
+                .map(|receipt| receipt.is_some())
+        },
+    );
+    let l1_sequenced = wait_for(
+        Stage::L1Sequenced,
+        submitted,
+        deadline,
+        opt.l1_poll_interval,
+        move || async move {
+            hotshot
+                .commitments(block_height.into())
+                .call()
+                .await
+                .map(|commitment| !commitment.is_zero())
+        },
+    );
+    let l1_verified = wait_for(
+        Stage::L1Verified,
+        submitted,
+        deadline,
+        opt.l1_poll_interval,
+        move || async move {
+            // Batch numbers on L1 are 1-indexed, while HotShot block numbers are 0-indexed.
+            rollup
+                .last_verified_batch()
+                .call()
+                .await
+                .map(|batch| batch > block_height)
+        },
+    );
+    let (adaptor_available, l2_receipt, l1_sequenced, l1_verified) =
+        join!(adaptor_available, l2_receipt, l1_sequenced, l1_verified);
+    let mut timed_out = vec![];
+    for result in [adaptor_available, l2_receipt, l1_sequenced, l1_verified] {
+        match result {
+            Ok(latency) => stages.push(latency),
+            Err(stage) => timed_out.push(stage),
+        }
+    }
+    stages.sort_by_key(|stage| stage.latency_ms);
+
+    let report = Report {
+        transaction: hash,
+        block_height,
+        stages,
+        timed_out,
+    };
+    report.print();
+    if let Some(path) = &opt.output {
+        fs::write(path, serde_json::to_string_pretty(&report).unwrap())
+            .unwrap_or_else(|err| panic!("unable to write {}: {err}", path.display()));
+    }
+    if !report.timed_out.is_empty() {
+        exit(1);
+    }
+}
+
+/// Poll `check` every `interval` until it reports that `stage` is complete.
+///
+/// Errors are logged, and polling continues until `deadline` has passed, in which case `stage` is
+/// returned as the error.
+async fn wait_for<F, Fut, E>(
+    stage: Stage,
+    submitted: Instant,
+    deadline: Duration,
+    interval: Duration,
+    mut check: F,
+) -> Result<StageLatency, Stage>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool, E>>,
+    E: Display,
+{
+    let poll = async {
+        loop {
+            match check().await {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(err) => tracing::warn!("error checking {stage:?}: {err}"),
+            }
+            sleep(interval).await;
+        }
+    };
+    match timeout(deadline, poll).await {
+        Ok(()) => Ok(self::stage(stage, submitted)),
+        Err(_) => {
+            tracing::error!("{stage:?} timed out");
+            Err(stage)
+        }
+    }
+}
+
+fn stage(stage: Stage, submitted: Instant) -> StageLatency {
+    let latency_ms = submitted.elapsed().as_millis();
+    tracing::info!("{stage:?} after {latency_ms} ms");
+    StageLatency { stage, latency_ms }
+}
@@ -0,0 +1,320 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Provision a pool of funded accounts for multi-wallet load tests.
+//!
+//! Accounts are derived from a mnemonic and topped up from a master account, first on the L1 (if
+//! requested) and then on the L2, either with a direct L2 transfer or with a deposit through the L1
+//! bridge. Accounts which already have enough funds are skipped, so it is safe to run this again
+//! against the same pool. Once all balances are confirmed, the pool is written to `--output`, for
+//! use with `load-test-deployment --accounts`.
+
+use async_compatibility_layer::logging::{setup_backtrace, setup_logging};
+use async_std::task::sleep;
+use clap::{Parser, ValueEnum};
+use ethers::{
+    prelude::*,
+    utils::{parse_ether, ConversionError},
+};
+use futures::future::join_all;
+use http_types::Url;
+use polygon_zkevm_adaptor::{config, connect_rpc_simple, AccountPool};
+use sequencer_utils::{NonceManager, Signer};
+use std::{
+    num::ParseIntError,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use zkevm_contract_bindings::PolygonZkEVMBridge;
+
+/// How long to wait between balance and receipt checks.
+const BALANCE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Funding {
+    /// Transfer funds to each account on the L2.
+    Direct,
+    /// Deposit funds for each account through the L1 bridge.
+    ///
+    /// The deposits must be claimed on the L2, e.g. by a zkEVM bridge service, before the accounts
+    /// are funded.
+    Bridge,
+}
+
+/// Derive a pool of accounts from a mnemonic and fund them.
+#[derive(Parser)]
+struct Options {
+    /// Mnemonic for the master account which funds the pool.
+    #[arg(long, env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_MASTER_MNEMONIC")]
+    master_mnemonic: String,
+
+    /// Index of the master account in `--master-mnemonic`.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_MASTER_INDEX",
+        default_value = "0"
+    )]
+    master_index: u32,
+
+    /// Mnemonic to derive the pool from.
+    ///
+    /// Defaults to `--master-mnemonic`.
+    #[arg(long, env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_MNEMONIC")]
+    mnemonic: Option<String>,
+
+    /// Index of the first account in the pool.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_FIRST_INDEX",
+        default_value = "1"
+    )]
+    first_index: u32,
+
+    /// Number of accounts in the pool.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_NUM_ACCOUNTS",
+        default_value = "10"
+    )]
+    num_accounts: u32,
+
+    /// URL for the L1 JSON-RPC service.
+    ///
+    /// Required for L1 funding and for bridge deposits.
+    #[arg(long, env = "ESPRESSO_ZKEVM_L1_PROVIDER")]
+    l1_provider: Option<Url>,
+
+    /// URL for the L2 JSON-RPC service.
+    #[arg(long, env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_L2_PROVIDER")]
+    l2_provider: Url,
+
+    /// Balance, in ETH, to fund each account with on the L1.
+    ///
+    /// If not specified, accounts are not funded on the L1.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_L1_AMOUNT",
+        value_parser = |arg: &str| -> Result<U256, ConversionError> { parse_ether(arg) }
+    )]
+    l1_amount: Option<U256>,
+
+    /// Balance, in ETH, to fund each account with on the L2.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_L2_AMOUNT",
+        default_value = "1",
+        value_parser = |arg: &str| -> Result<U256, ConversionError> { parse_ether(arg) }
+    )]
+    l2_amount: U256,
+
+    /// How to fund the accounts on the L2.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_FUNDING",
+        value_enum,
+        default_value = "direct"
+    )]
+    funding: Funding,
+
+    /// Address of the bridge contract on the L1, for `--funding bridge`.
+    #[arg(long, env = "ESPRESSO_ZKEVM_1_BRIDGE_ADDRESS")]
+    bridge_address: Option<Address>,
+
+    /// Network ID of the L2 in the bridge, for `--funding bridge`.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_BRIDGE_NETWORK",
+        default_value = "1"
+    )]
+    bridge_network: u32,
+
+    /// How long in milliseconds to wait for all accounts to be funded.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_TIMEOUT",
+        default_value = "600000",
+        value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(Duration::from_millis(arg.parse()?)) }
+    )]
+    timeout: Duration,
+
+    /// Where to save the funded account pool.
+    #[arg(long, env = "ESPRESSO_ZKEVM_FUND_ACCOUNTS_OUTPUT")]
+    output: PathBuf,
+}
+
+impl Options {
+    fn check(&self) -> Result<(), String> {
+        if self.l1_provider.is_none() {
+            if self.l1_amount.is_some() {
+                return Err("--l1-amount requires --l1-provider".into());
+            }
+            if self.funding == Funding::Bridge {
+                return Err("--funding bridge requires --l1-provider".into());
+            }
+        }
+        if self.funding == Funding::Bridge && self.bridge_address.is_none() {
+            return Err("--funding bridge requires --bridge-address".into());
+        }
+        Ok(())
+    }
+}
+
+#[async_std::main]
+async fn main() {
+    setup_logging();
+    setup_backtrace();
+
+    let opt: Options = config::parse("fund-accounts", Options::check);
+
+    let mnemonic = opt.mnemonic.as_ref().unwrap_or(&opt.master_mnemonic);
+    let pool = AccountPool::derive(mnemonic, opt.first_index, opt.num_accounts)
+        .expect("unable to derive accounts");
+    let timeout = opt.timeout;
+    let l2 = connect(&opt.l2_provider, &opt).await;
+    let l1 = match &opt.l1_provider {
+        Some(provider) => Some(connect(provider, &opt).await),
+        None => None,
+    };
+
+    if let (Some(l1), Some(amount)) = (&l1, opt.l1_amount) {
+        tracing::info!("funding {} accounts on L1", opt.num_accounts);
+        let needed = top_ups(l1.as_ref(), &pool, amount).await;
+        join_all(needed.into_iter().map(|(address, value)| {
+            let l1 = l1.clone();
+            async move {
+                let tx = TransactionRequest::new().to(address).value(value);
+                send(l1.as_ref(), l1.send_transaction(tx, None).await, timeout).await;
+            }
+        }))
+        .await;
+        wait_for_balances(l1.as_ref(), &pool, amount, timeout).await;
+    }
+
+    tracing::info!(
+        "funding {} accounts on L2 ({:?})",
+        opt.num_accounts,
+        opt.funding
+    );
+    let needed = top_ups(l2.as_ref(), &pool, opt.l2_amount).await;
+    tracing::info!("{} accounts need L2 funds", needed.len());
+    match opt.funding {
+        Funding::Direct => {
+            join_all(needed.into_iter().map(|(address, value)| {
+                let l2 = l2.clone();
+                async move {
+                    let tx = TransactionRequest::new().to(address).value(value);
+                    send(l2.as_ref(), l2.send_transaction(tx, None).await, timeout).await;
+                }
+            }))
+            .await;
+        }
+        Funding::Bridge => {
+            let l1 = l1.unwrap();
+            let bridge = PolygonZkEVMBridge::new(opt.bridge_address.unwrap(), l1.clone());
+            for (address, value) in needed {
+                // Deposit native ETH, which the bridge identifies by the zero token address.
+                let call = bridge
+                    .bridge_asset(
+                        Address::zero(),
+                        opt.bridge_network,
+                        address,
+                        value,
+                        Bytes::default(),
+                    )
+                    .value(value);
+                send(l1.as_ref(), call.send().await, timeout).await;
+            }
+        }
+    }
+    wait_for_balances(l2.as_ref(), &pool, opt.l2_amount, timeout).await;
+
+    pool.save(&opt.output);
+    tracing::info!(
+        "saved {} funded accounts to {}",
+        opt.num_accounts,
+        opt.output.display()
+    );
+}
+
+async fn connect(provider: &Url, opt: &Options) -> Arc<NonceManager> {
+    let signer: Signer = connect_rpc_simple(provider, &opt.master_mnemonic, opt.master_index, None)
+        .await
+        .unwrap_or_else(|| panic!("unable to connect to {provider}"));
+    tracing::info!("master account {:?} on {provider}", signer.address());
+    Arc::new(NonceManager::new(signer.clone(), signer.address()))
+}
+
+/// The amount each account in `pool` needs to reach a balance of `target`.
+async fn top_ups(
+    client: &impl Middleware,
+    pool: &AccountPool,
+    target: U256,
+) -> Vec<(Address, U256)> {
+    let mut needed = vec![];
+    for address in pool.addresses() {
+        let balance = client.get_balance(address, None).await.unwrap();
+        if balance < target {
+            needed.push((address, target - balance));
+        }
+    }
+    needed
+}
+
+/// Wait for a transaction to be mined, panicking if it fails or is not mined within `timeout`.
+///
+/// We poll for the receipt rather than awaiting the pending transaction, which resolves to `None`
+/// if the node does not know about the transaction yet, as is the case for L2 transactions which
+/// are still on their way through the sequencer.
+async fn send<P: JsonRpcClient, E: std::fmt::Display>(
+    client: &impl Middleware,
+    tx: Result<PendingTransaction<'_, P>, E>,
+    timeout: Duration,
+) {
+    let tx = tx.unwrap_or_else(|err| panic!("error sending transaction: {err}"));
+    let hash = tx.tx_hash();
+    let start = Instant::now();
+    let receipt = loop {
+        match client.get_transaction_receipt(hash).await {
+            Ok(Some(receipt)) => break receipt,
+            Ok(None) => {}
+            Err(err) => tracing::warn!("error getting receipt for {hash:?}: {err}"),
+        }
+        assert!(
+            start.elapsed() < timeout,
+            "transaction {hash:?} not mined after {timeout:?}"
+        );
+        sleep(BALANCE_POLL_INTERVAL).await;
+    };
+    assert_eq!(
+        receipt.status,
+        Some(1.into()),
+        "transaction {hash:?} failed: {receipt:?}"
+    );
+    tracing::info!("transaction {hash:?} succeeded");
+}
+
+async fn wait_for_balances(
+    client: &impl Middleware,
+    pool: &AccountPool,
+    target: U256,
+    timeout: Duration,
+) {
+    let start = Instant::now();
+    loop {
+        let remaining = top_ups(client, pool, target).await.len();
+        if remaining == 0 {
+            return;
+        }
+        assert!(
+            start.elapsed() < timeout,
+            "{remaining} accounts not funded after {timeout:?}"
+        );
+        tracing::info!("waiting for {remaining} accounts to be funded");
+        sleep(BALANCE_POLL_INTERVAL).await;
+    }
+}
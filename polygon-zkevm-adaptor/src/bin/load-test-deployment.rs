@@ -6,13 +6,16 @@
 // You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use async_compatibility_layer::logging::{setup_backtrace, setup_logging};
-use async_std::task::sleep;
+use async_std::task::{sleep, spawn};
 use clap::Parser;
 use ethers::prelude::*;
-use futures::join;
+use futures::{future::join_all, join};
 use http_types::Url;
-use polygon_zkevm_adaptor::{connect_rpc_simple, CombinedOperations, Run};
-use std::{num::ParseIntError, path::PathBuf, time::Duration};
+use polygon_zkevm_adaptor::{
+    config, connect_rpc_failover, connect_rpc_simple, events::EventStream, AccountPool,
+    CombinedOperations, FailoverOptions, FailoverSigner, Run, Scenario,
+};
+use std::{num::ParseIntError, path::PathBuf, process::exit, sync::Arc, time::Duration};
 
 /// Run a load test against an existing ZkEVM node.
 ///
@@ -26,8 +29,9 @@ pub struct Options {
     /// executed.
     #[arg(
         long,
-        required_unless_present = "load_plan",
-        conflicts_with = "load_plan"
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_SAVE_PLAN",
+        required_unless_present_any = ["load_plan", "scenario"],
+        conflicts_with_all = ["load_plan", "scenario"]
     )]
     pub save_plan: Option<PathBuf>,
 
@@ -36,16 +40,30 @@ pub struct Options {
     /// If specified, the test plan will be loaded from this file and executed.
     #[arg(
         long,
-        required_unless_present = "save_plan",
-        conflicts_with = "save_plan"
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_LOAD_PLAN",
+        required_unless_present_any = ["save_plan", "scenario"],
+        conflicts_with_all = ["save_plan", "scenario"]
     )]
     pub load_plan: Option<PathBuf>,
 
+    /// Where to load a TOML scenario file from.
+    ///
+    /// If specified, the scripted operations in the scenario are executed via the regular node
+    /// instead of a randomly generated test plan, and the run fails if any of the scenario's
+    /// assertions do not hold.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_SCENARIO",
+        conflicts_with_all = ["save_plan", "load_plan", "compare_l2_provider"]
+    )]
+    pub scenario: Option<PathBuf>,
+
     /// Sum of sleep time between transactions.
     ///
     /// The runtime of the test will be lower bounded by this value.
     #[arg(
         long,
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_MINS",
         default_value = "1",
         conflicts_with = "load_plan",
         value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(60 * Duration::from_secs(arg.parse()?)) }
@@ -53,16 +71,116 @@ pub struct Options {
     pub mins: Duration,
 
     /// URL for the L2 JSON-RPC service.
-    #[arg(long)]
+    #[arg(long, env = "ESPRESSO_ZKEVM_LOAD_TEST_L2_PROVIDER")]
     pub l2_provider: Url,
 
+    /// URLs for additional L2 JSON-RPC services to fail over to, separated by commas.
+    ///
+    /// Load test transactions are sent to `--l2-provider` first, moving on to the next provider in
+    /// this list if the current one fails repeatedly.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_L2_FALLBACK_PROVIDERS",
+        value_delimiter = ','
+    )]
+    pub l2_fallback_providers: Vec<Url>,
+
+    /// How many times to retry an L2 request which failed with a transient error.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_RPC_MAX_RETRIES",
+        default_value = "5"
+    )]
+    pub rpc_max_retries: u32,
+
+    /// How many consecutive transient errors from an L2 provider trigger failover to the next one.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_RPC_FAILURES_BEFORE_ROTATE",
+        default_value = "3"
+    )]
+    pub rpc_failures_before_rotate: u32,
+
     /// URL for an optional L2 JSON-RPC service using preconfirmations.
-    #[arg(long)]
+    #[arg(long, env = "ESPRESSO_ZKEVM_LOAD_TEST_PRECONFIRMATIONS_L2_PROVIDER")]
     pub preconfirmations_l2_provider: Option<Url>,
 
     /// Mnemonic for a funded L2 account, which the load test will drain.
-    #[arg(long)]
+    #[arg(long, env = "ESPRESSO_ZKEVM_LOAD_TEST_MNEMONIC")]
     pub mnemonic: String,
+
+    /// URL for a second L2 JSON-RPC service to compare against.
+    ///
+    /// If specified, instead of running a load test, the regular node operations in the plan are
+    /// replayed one at a time against `--l2-provider` and then against this service, and any
+    /// differences in the resulting receipts, transactions, blocks and balances are reported. The
+    /// two services must run independent chains which start from the same state, with the account
+    /// derived from `--mnemonic` funded.
+    #[arg(long, env = "ESPRESSO_ZKEVM_LOAD_TEST_COMPARE_L2_PROVIDER")]
+    pub compare_l2_provider: Option<Url>,
+
+    /// Where to load a pool of funded accounts from, as saved by `fund-accounts`.
+    ///
+    /// If specified, the regular node operations in the plan are run concurrently from every
+    /// account in the pool, instead of only from the account derived from `--mnemonic`.
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_ACCOUNTS",
+        conflicts_with_all = ["scenario", "compare_l2_provider"]
+    )]
+    pub accounts: Option<PathBuf>,
+
+    /// Do not fail the run if any transfers are reverted.
+    ///
+    /// The load test only submits plain transfers, which are not expected to revert, so by
+    /// default the run fails if any of them do.
+    #[arg(long, env = "ESPRESSO_ZKEVM_LOAD_TEST_ALLOW_REVERTS")]
+    pub allow_reverts: bool,
+
+    /// Path of a Unix socket on which to stream structured events as JSON lines.
+    ///
+    /// Every client which connects to the socket receives events for each transfer submitted or
+    /// completed by the load test from then on.
+    #[arg(long, env = "ESPRESSO_ZKEVM_LOAD_TEST_EVENTS_SOCKET")]
+    pub events_socket: Option<PathBuf>,
+}
+
+impl Options {
+    fn l2_providers(&self) -> Vec<Url> {
+        let mut providers = vec![self.l2_provider.clone()];
+        providers.extend(self.l2_fallback_providers.iter().cloned());
+        providers
+    }
+
+    fn failover(&self) -> FailoverOptions {
+        FailoverOptions {
+            max_retries: self.rpc_max_retries,
+            failures_before_rotate: self.rpc_failures_before_rotate,
+            ..Default::default()
+        }
+    }
+
+    /// Connect the funded account to the L2 providers, with failover.
+    async fn connect(&self) -> FailoverSigner {
+        connect_rpc_failover(
+            &self.l2_providers(),
+            &self.mnemonic,
+            0,
+            None,
+            self.failover(),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Start streaming events to `--events-socket`, if configured.
+    fn event_stream(&self) -> Arc<EventStream> {
+        let events = Arc::new(EventStream::default());
+        if let Some(path) = &self.events_socket {
+            spawn(events.clone().serve(path.clone()));
+        }
+        events
+    }
 }
 
 #[async_std::main]
@@ -70,16 +188,51 @@ async fn main() {
     setup_logging();
     setup_backtrace();
 
-    let opt = Options::parse();
+    let opt: Options = config::parse("load-test-deployment", |_| Ok(()));
+    let events = opt.event_stream();
 
-    let operations = if let Some(path) = opt.load_plan {
+    if let Some(path) = &opt.scenario {
+        tracing::info!("Loading scenario from {}", path.display());
+        let operations = Scenario::load(path)
+            .and_then(|scenario| scenario.compile(&opt.mnemonic))
+            .unwrap_or_else(|err| panic!("invalid scenario {}: {err}", path.display()));
+        let mut accounts = vec![];
+        for index in operations.senders() {
+            let signer = connect_rpc_failover(
+                &opt.l2_providers(),
+                &opt.mnemonic,
+                index,
+                None,
+                opt.failover(),
+            )
+            .await
+            .unwrap();
+            accounts.push((index, signer));
+        }
+        let run = Run::with_failover("scenario", operations, opt.connect().await)
+            .with_accounts(accounts)
+            .with_events(events.clone());
+        let report = run.wait().await;
+        tracing::info!("Scenario complete!");
+        report.log("scenario");
+        let failed = run.failed_assertions().await;
+        for assertion in &failed {
+            tracing::error!("assertion failed: {assertion}");
+        }
+        if !failed.is_empty() || (report.reverted() > 0 && !opt.allow_reverts) {
+            exit(1);
+        }
+        return;
+    }
+
+    let operations = if let Some(path) = &opt.load_plan {
         tracing::info!("Loading plan from {}", path.display());
-        CombinedOperations::load(&path)
+        CombinedOperations::load(path)
     } else {
         let operations = CombinedOperations::generate(opt.mins);
-        let path = opt.save_plan.unwrap();
+        let path = opt.save_plan.as_ref().unwrap();
         tracing::info!("Saved plan to {}", path.display());
-        operations.save(&path);
+        operations.save(path);
         operations
     };
 
@@ -87,6 +240,51 @@ async fn main() {
     let signer = connect_rpc_simple(&opt.l2_provider, &opt.mnemonic, 0, None)
         .await
         .unwrap();
+
+    if let Some(provider) = &opt.compare_l2_provider {
+        let compare_signer = connect_rpc_simple(provider, &opt.mnemonic, 0, None)
+            .await
+            .unwrap();
+        // Both replays use the same account, so they must not run on the same chain, or they
+        // would compete for its nonces. Replay one after the other, and check that the first
+        // replay did not touch the account on the second endpoint.
+        let address = signer.address();
+        let nonce = compare_signer
+            .get_transaction_count(address, None)
+            .await
+            .unwrap();
+        let left = operations.regular_node.replay("left", signer).await;
+        if compare_signer
+            .get_transaction_count(address, None)
+            .await
+            .unwrap()
+            != nonce
+        {
+            tracing::error!(
+                "{} and {provider} share a chain, replays must run on independent chains",
+                opt.l2_provider
+            );
+            exit(1);
+        }
+        let right = operations
+            .regular_node
+            .replay("right", compare_signer)
+            .await;
+        let divergences = left.diff(&right);
+        if divergences.is_empty() {
+            tracing::info!("Replay complete, no divergences");
+            return;
+        }
+        for divergence in &divergences {
+            tracing::error!("divergence: {divergence:?}");
+        }
+        tracing::error!(
+            "Replay complete, {} divergences between {} and {provider}",
+            divergences.len(),
+            opt.l2_provider
+        );
+        exit(1);
+    }
     // Use the second account for the second connection. Even though the two signers will be
     // _submitting_ transactions to different RPCs, both RPCs will see the transactions from both
     // signers come out of the sequencer, which means using the same account for both could cause
@@ -126,11 +324,37 @@ async fn main() {
         None => None,
     };
 
-    let run = Run::new("regular", operations.regular_node, signer);
-    let preconf_run =
-        preconf_signer.map(|signer| Run::new("preconf", operations.preconf_node, signer));
-    let ((regular_submitted, regular_successful), preconf_results) =
-        join!(run.wait(), async move {
+    let runs = match &opt.accounts {
+        Some(path) => {
+            let pool = AccountPool::load(path);
+            tracing::info!(
+                "Running plan from {} accounts in {}",
+                pool.accounts.len(),
+                path.display()
+            );
+            pool.accounts
+                .iter()
+                .zip(pool.connect(&opt.l2_providers(), opt.failover()).await)
+                .map(|(account, signer)| {
+                    Run::with_failover(
+                        format!("regular-{}", account.index),
+                        operations.regular_node.clone(),
+                        signer,
+                    )
+                    .with_events(events.clone())
+                })
+                .collect()
+        }
+        None => vec![
+            Run::with_failover("regular", operations.regular_node, opt.connect().await)
+                .with_events(events.clone()),
+        ],
+    };
+    let preconf_run = preconf_signer.map(|signer| {
+        Run::new("preconf", operations.preconf_node, signer).with_events(events.clone())
+    });
+    let (regular_results, preconf_results) =
+        join!(join_all(runs.iter().map(|run| run.wait())), async move {
             if let Some(run) = preconf_run {
                 Some(run.wait().await)
             } else {
@@ -138,13 +362,31 @@ async fn main() {
             }
         });
 
+    let (regular_submitted, regular_successful) =
+        regular_results
+            .iter()
+            .fold((0, 0), |(submitted, successful), report| {
+                (submitted + report.submitted, successful + report.successful)
+            });
+
     tracing::info!("Run complete!");
+    for (run, report) in runs.iter().zip(&regular_results) {
+        report.log(run.name());
+    }
     tracing::info!(
         "{regular_successful}/{regular_submitted} transactions successful via regular node"
     );
-    if let Some((preconf_submitted, preconf_successful)) = preconf_results {
-        tracing::info!(
-            "{preconf_successful}/{preconf_submitted} transactions successful via preconf node"
-        );
+    if let Some(report) = &preconf_results {
+        report.log("preconf");
+    }
+
+    let reverted = regular_results
+        .iter()
+        .chain(&preconf_results)
+        .map(|report| report.reverted())
+        .sum::<usize>();
+    if reverted > 0 && !opt.allow_reverts {
+        tracing::error!("{reverted} transfers reverted");
+        exit(1);
     }
 }
@@ -6,15 +6,16 @@
 // You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use async_compatibility_layer::logging::{setup_backtrace, setup_logging};
-use async_std::task::sleep;
+use async_std::task::{sleep, spawn};
 use clap::Parser;
 use ethers::prelude::*;
 use futures::join;
 use polygon_zkevm_adaptor::{
-    connect_rpc_simple, CombinedOperations, Layer1Backend, Run, SequencerZkEvmDemoOptions,
+    config, connect_rpc_simple, events::EventStream, CombinedOperations, Layer1Backend, Run,
+    SequencerZkEvmDemoOptions,
 };
 use sequencer_utils::wait_for_http;
-use std::{num::ParseIntError, path::PathBuf, time::Duration};
+use std::{num::ParseIntError, path::PathBuf, process::exit, sync::Arc, time::Duration};
 
 /// Run a load test on the ZkEVM node.
 ///
@@ -28,6 +29,7 @@ pub struct Options {
     /// executed.
     #[arg(
         long,
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_SAVE_PLAN",
         required_unless_present = "load_plan",
         conflicts_with = "load_plan"
     )]
@@ -38,6 +40,7 @@ pub struct Options {
     /// If specified, the test plan will be loaded from this file and executed.
     #[arg(
         long,
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_LOAD_PLAN",
         required_unless_present = "save_plan",
         conflicts_with = "save_plan"
     )]
@@ -48,6 +51,7 @@ pub struct Options {
     /// The runtime of the test will be lower bounded by this value.
     #[arg(
         long,
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_MINS",
         default_value = "1",
         conflicts_with = "load_plan",
         value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(60 * Duration::from_secs(arg.parse()?)) }
@@ -55,8 +59,37 @@ pub struct Options {
     pub mins: Duration,
 
     /// Layer 1 backend to use.
-    #[arg(long, default_value = "geth")]
+    #[arg(
+        long,
+        env = "ESPRESSO_ZKEVM_LOAD_TEST_L1_BACKEND",
+        default_value = "geth"
+    )]
     pub l1_backend: Layer1Backend,
+
+    /// Do not fail the run if any transfers are reverted.
+    ///
+    /// The load test only submits plain transfers, which are not expected to revert, so by
+    /// default the run fails if any of them do.
+    #[arg(long, env = "ESPRESSO_ZKEVM_LOAD_TEST_ALLOW_REVERTS")]
+    pub allow_reverts: bool,
+
+    /// Path of a Unix socket on which to stream structured events as JSON lines.
+    ///
+    /// Every client which connects to the socket receives events for each transfer submitted or
+    /// completed by the load test from then on.
+    #[arg(long, env = "ESPRESSO_ZKEVM_LOAD_TEST_EVENTS_SOCKET")]
+    pub events_socket: Option<PathBuf>,
+}
+
+impl Options {
+    /// Start streaming events to `--events-socket`, if configured.
+    fn event_stream(&self) -> Arc<EventStream> {
+        let events = Arc::new(EventStream::default());
+        if let Some(path) = &self.events_socket {
+            spawn(events.clone().serve(path.clone()));
+        }
+        events
+    }
 }
 
 #[async_std::main]
@@ -64,7 +97,8 @@ async fn main() {
     setup_logging();
     setup_backtrace();
 
-    let opt = Options::parse();
+    let opt: Options = config::parse("load-test", |_| Ok(()));
+    let events = opt.event_stream();
 
     let operations = if let Some(path) = opt.load_plan {
         tracing::info!("Loading plan from {}", path.display());
@@ -130,16 +164,20 @@ async fn main() {
         sleep(Duration::from_secs(1)).await;
     }
 
-    let run = Run::new("regular", operations.regular_node, signer);
-    let preconf_run = Run::new("preconf", operations.preconf_node, preconf_signer);
-    let ((regular_submitted, regular_successful), (preconf_submitted, preconf_successful)) =
-        join!(run.wait(), preconf_run.wait());
+    let run = Run::new("regular", operations.regular_node, signer).with_events(events.clone());
+    let preconf_run =
+        Run::new("preconf", operations.preconf_node, preconf_signer).with_events(events);
+    let (regular_report, preconf_report) = join!(run.wait(), preconf_run.wait());
 
     tracing::info!("Run complete!");
-    tracing::info!(
-        "{regular_successful}/{regular_submitted} transactions successful via regular node"
-    );
-    tracing::info!(
-        "{preconf_successful}/{preconf_submitted} transactions successful via preconf node"
-    );
+    regular_report.log("regular");
+    preconf_report.log("preconf");
+
+    let reverted = regular_report.reverted() + preconf_report.reverted();
+    if reverted > 0 && !opt.allow_reverts {
+        tracing::error!("{reverted} transfers reverted");
+        // Shut down the demo before exiting, since `exit` does not run destructors.
+        drop(demo);
+        exit(1);
+    }
 }
@@ -0,0 +1,142 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! In-memory cache of recently requested blocks.
+//!
+//! The zkEVM node tends to request the same recent blocks repeatedly while it syncs. Caching them
+//! saves reading and deserializing them from the block store, or fetching them from the sequencer
+//! if they have not been synced yet.
+
+use crate::{metrics::QueryMetrics, query_service::PolygonZkevmBlock};
+use lru::LruCache;
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug)]
+pub struct BlockCache {
+    /// `None` if caching is disabled.
+    inner: Option<Mutex<Inner>>,
+    metrics: Arc<QueryMetrics>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    blocks: LruCache<u64, PolygonZkevmBlock>,
+    /// The number of rewinds of the block store when the cached blocks were read.
+    rewinds: u64,
+}
+
+impl Inner {
+    /// Drop all cached blocks if the store has been rewound since they were cached.
+    ///
+    /// Returns `false` if the caller's view of the store is older than the cached blocks.
+    fn sync_rewinds(&mut self, rewinds: u64) -> bool {
+        if rewinds > self.rewinds {
+            self.blocks.clear();
+            self.rewinds = rewinds;
+        }
+        rewinds == self.rewinds
+    }
+}
+
+impl BlockCache {
+    /// A cache holding up to `size` blocks.
+    ///
+    /// If `size` is 0, caching is disabled and every lookup is a miss.
+    pub fn new(size: usize, metrics: Arc<QueryMetrics>) -> Self {
+        Self {
+            inner: NonZeroUsize::new(size).map(|size| {
+                Mutex::new(Inner {
+                    blocks: LruCache::new(size),
+                    rewinds: 0,
+                })
+            }),
+            metrics,
+        }
+    }
+
+    /// Look up the block at `height`.
+    ///
+    /// `rewinds` is the current number of rewinds of the block store (see
+    /// [`BlockStore::rewinds`](crate::storage::BlockStore::rewinds)), which is used to invalidate
+    /// blocks which have been replaced due to a reorg.
+    pub fn get(&self, height: u64, rewinds: u64) -> Option<PolygonZkevmBlock> {
+        let block = self.inner.as_ref().and_then(|inner| {
+            let mut inner = inner.lock().unwrap();
+            inner.sync_rewinds(rewinds);
+            inner.blocks.get(&height).cloned()
+        });
+        if block.is_some() {
+            self.metrics.cache_hits.inc();
+        } else {
+            self.metrics.cache_misses.inc();
+        }
+        block
+    }
+
+    /// Add a block to the cache, evicting the least recently used block if the cache is full.
+    pub fn insert(&self, block: PolygonZkevmBlock, rewinds: u64) {
+        if let Some(inner) = &self.inner {
+            let mut inner = inner.lock().unwrap();
+            // Don't cache a block which may already have been replaced.
+            if inner.sync_rewinds(rewinds) {
+                inner.blocks.put(block.height, block);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(height: u64) -> PolygonZkevmBlock {
+        PolygonZkevmBlock {
+            timestamp: height,
+            height,
+            l1_block: 0,
+            transactions: "0x".into(),
+        }
+    }
+
+    #[test]
+    fn test_cache() {
+        let metrics = Arc::new(QueryMetrics::default());
+        let cache = BlockCache::new(2, metrics.clone());
+
+        assert_eq!(cache.get(0, 0), None);
+        cache.insert(block(0), 0);
+        cache.insert(block(1), 0);
+        assert_eq!(cache.get(0, 0), Some(block(0)));
+
+        // Block 1 is now the least recently used, so it is evicted to make room.
+        cache.insert(block(2), 0);
+        assert_eq!(cache.get(1, 0), None);
+        assert_eq!(cache.get(0, 0), Some(block(0)));
+        assert_eq!(cache.get(2, 0), Some(block(2)));
+
+        // Rewinding the store invalidates the cache.
+        assert_eq!(cache.get(0, 1), None);
+        // Blocks read before the rewind are not cached.
+        cache.insert(block(0), 0);
+        assert_eq!(cache.get(0, 1), None);
+
+        assert_eq!(metrics.cache_hits.get(), 3);
+        assert_eq!(metrics.cache_misses.get(), 4);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let metrics = Arc::new(QueryMetrics::default());
+        let cache = BlockCache::new(0, metrics.clone());
+        cache.insert(block(0), 0);
+        assert_eq!(cache.get(0, 0), None);
+        assert_eq!(metrics.cache_misses.get(), 1);
+    }
+}
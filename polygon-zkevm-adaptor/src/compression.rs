@@ -0,0 +1,273 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Compression of HTTP responses served by the adaptor.
+//!
+//! Responses are compressed with brotli or gzip, depending on the `Accept-Encoding` header of the
+//! request. This matters most for block range responses from the query service, which can be large
+//! and are fetched by the zkEVM node over the network.
+
+use clap::Args;
+use flate2::write::GzEncoder;
+use http_types::{
+    headers::{ACCEPT_ENCODING, CONTENT_ENCODING, UPGRADE, VARY},
+    Body, StatusCode,
+};
+use std::io::{self, Write};
+
+/// Brotli quality for compressed responses.
+///
+/// The default quality of 11 is meant for static content, and is too slow to compress responses on
+/// the fly.
+const BROTLI_QUALITY: u32 = 5;
+
+/// Base 2 logarithm of the brotli window size, the default used by the reference encoder.
+const BROTLI_WINDOW: u32 = 22;
+
+/// Size of the buffer used by the brotli encoder.
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+#[derive(Args, Clone, Debug)]
+pub struct CompressionOptions {
+    /// Do not compress HTTP responses, even if the client accepts compressed responses.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_DISABLE_COMPRESSION")]
+    pub disable_compression: bool,
+
+    /// Minimum size in bytes of an HTTP response body to compress.
+    ///
+    /// Smaller responses are sent uncompressed, since compressing them saves little bandwidth.
+    /// Responses whose size is not known in advance are streamed, and are never compressed.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_COMPRESSION_MIN_SIZE",
+        default_value = "1024"
+    )]
+    pub compression_min_size: usize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            disable_compression: false,
+            compression_min_size: 1024,
+        }
+    }
+}
+
+impl CompressionOptions {
+    pub fn is_enabled(&self) -> bool {
+        !self.disable_compression
+    }
+}
+
+/// A content encoding supported by the adaptor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Supported encodings, in order of preference.
+    pub const ALL: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// Choose an encoding for a response, given the `Accept-Encoding` header of the request.
+    ///
+    /// Returns the supported encoding with the highest quality value, breaking ties in order of
+    /// preference, or `None` if the client does not accept any supported encoding.
+    pub fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let mut wildcard = None;
+        let mut qualities = [None; Self::ALL.len()];
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let coding = params.next().unwrap_or_default();
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map(|q| q.parse::<f32>().unwrap_or(0.))
+                .unwrap_or(1.);
+            if coding == "*" {
+                wildcard = Some(quality);
+            } else if let Some(i) = Self::ALL
+                .iter()
+                .position(|encoding| encoding.as_str().eq_ignore_ascii_case(coding))
+            {
+                qualities[i] = Some(quality);
+            }
+        }
+
+        let mut best: Option<(Self, f32)> = None;
+        for (encoding, quality) in Self::ALL.into_iter().zip(qualities) {
+            let Some(quality) = quality.or(wildcard) else {
+                continue;
+            };
+            if quality > 0. && best.map_or(true, |(_, best)| quality > best) {
+                best = Some((encoding, quality));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    /// Compress `data` with this encoding.
+    pub fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(
+                    vec![],
+                    BROTLI_BUFFER_SIZE,
+                    BROTLI_QUALITY,
+                    BROTLI_WINDOW,
+                );
+                encoder.write_all(data)?;
+                // Taking the output finishes the stream.
+                Ok(encoder.into_inner())
+            }
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Middleware compressing responses for clients which accept compressed responses.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    min_size: usize,
+}
+
+impl Compression {
+    pub fn new(opt: &CompressionOptions) -> Self {
+        Self {
+            min_size: opt.compression_min_size,
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for Compression {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        // Leave WebSocket upgrades alone, since the connection is taken over by the socket.
+        let encoding = match (req.header(ACCEPT_ENCODING), req.header(UPGRADE)) {
+            (Some(accept), None) => Encoding::negotiate(
+                &accept
+                    .iter()
+                    .map(|value| value.as_str())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            _ => None,
+        };
+        let mut res = next.run(req).await;
+        let Some(encoding) = encoding else {
+            return Ok(res);
+        };
+        if res.header(CONTENT_ENCODING).is_some()
+            || res.status().is_informational()
+            || matches!(
+                res.status(),
+                StatusCode::NoContent | StatusCode::NotModified
+            )
+        {
+            return Ok(res);
+        }
+
+        // Only compress bodies of known size, which are already in memory. Others may be streamed,
+        // and buffering them to compress them would hold up the response.
+        let body = res.take_body();
+        if body.len().map_or(true, |len| len < self.min_size) {
+            res.set_body(body);
+            return Ok(res);
+        }
+        let mime = body.mime().clone();
+        let data = body.into_bytes().await?;
+        let mut encoded = Body::from_bytes(encoding.encode(&data)?);
+        encoded.set_mime(mime);
+        res.set_body(encoded);
+        res.insert_header(CONTENT_ENCODING, encoding.as_str());
+        res.append_header(VARY, "Accept-Encoding");
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use http_types::{Method, Request, Response, Url};
+    use std::io::Read;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(
+            Encoding::negotiate("gzip, deflate, br"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(Encoding::negotiate("br;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("GZIP;q=0.8"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(Encoding::negotiate("*, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("identity"), None);
+        assert_eq!(Encoding::negotiate("gzip;q=0"), None);
+        assert_eq!(Encoding::negotiate(""), None);
+    }
+
+    #[async_std::test]
+    async fn test_compression() {
+        let mut app = tide::new();
+        app.with(Compression::new(&CompressionOptions::default()));
+        app.at("/large")
+            .get(|_| async { Ok("block ".repeat(1000)) });
+        app.at("/small").get(|_| async { Ok("block") });
+
+        let get = |path: &str, accept: Option<&str>| {
+            let mut req = Request::new(
+                Method::Get,
+                Url::parse("http://localhost").unwrap().join(path).unwrap(),
+            );
+            if let Some(accept) = accept {
+                req.insert_header(ACCEPT_ENCODING, accept);
+            }
+            req
+        };
+
+        // Large responses are compressed with the negotiated encoding.
+        for (accept, encoding) in [("gzip", Encoding::Gzip), ("br, gzip", Encoding::Brotli)] {
+            let mut res: Response = app.respond(get("/large", Some(accept))).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(res[CONTENT_ENCODING], encoding.as_str());
+            let compressed = res.body_bytes().await.unwrap();
+            assert!(compressed.len() < 6000);
+
+            let mut body = String::new();
+            match encoding {
+                Encoding::Gzip => GzDecoder::new(&compressed[..])
+                    .read_to_string(&mut body)
+                    .unwrap(),
+                Encoding::Brotli => brotli::Decompressor::new(&compressed[..], 4096)
+                    .read_to_string(&mut body)
+                    .unwrap(),
+            };
+            assert_eq!(body, "block ".repeat(1000));
+        }
+
+        // Small responses, and responses to clients which don't accept compression, are not.
+        for (path, accept) in [("/small", Some("gzip")), ("/large", None)] {
+            let res: Response = app.respond(get(path, accept)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert!(res.header(CONTENT_ENCODING).is_none());
+        }
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Configuration files for the adaptor and load test binaries.
+//!
+//! Every binary accepts a TOML configuration file via `--config` (or `ESPRESSO_ZKEVM_CONFIG`). Keys
+//! in the file are the names of command line options, without the leading dashes. Keys at the top
+//! level of the file apply to every binary which has an option with that name, while keys in a
+//! table named after a binary (such as `[adaptor]` or `[load-test]`) apply only to that binary,
+//! and take precedence over top-level keys. For example:
+//!
+//! ```toml
+//! sequencer-url = "http://localhost:50000"
+//! l1-provider = "http://localhost:8545"
+//!
+//! [adaptor]
+//! storage-path = "/store/adaptor"
+//! rate-limit-per-ip = 10
+//!
+//! [[adaptor.rollup]]
+//! chain-id = 1002
+//! rpc-port = 8546
+//! query-port = 50101
+//! ```
+//!
+//! Options given in the environment override the configuration file, and options given on the
+//! command line override both. Running a binary as `<binary> config check --config <file>` checks
+//! that the configuration is valid for that binary, without running it.
+
+use clap::{Arg, Command, CommandFactory, FromArgMatches, Parser};
+use snafu::Snafu;
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process::exit,
+};
+use toml::{Table, Value};
+
+/// Environment variable which can be used instead of `--config`.
+pub const CONFIG_ENV: &str = "ESPRESSO_ZKEVM_CONFIG";
+
+#[derive(Debug, Snafu)]
+pub enum ConfigError {
+    #[snafu(display("unable to read {}: {source}", path.display()))]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("unable to parse {}: {source}", path.display()))]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[snafu(display("[{section}]: unknown option {key:?}"))]
+    UnknownOption { section: String, key: String },
+
+    #[snafu(display("{key:?} cannot be set in a configuration file"))]
+    Unsupported { key: String },
+
+    #[snafu(display("invalid value for {key:?}: {reason}"))]
+    InvalidValue { key: String, reason: String },
+}
+
+/// Load a configuration file.
+pub fn load(path: &Path) -> Result<Table, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.into(),
+        source,
+    })?;
+    contents.parse().map_err(|source| ConfigError::Parse {
+        path: path.into(),
+        source,
+    })
+}
+
+/// Resolve the options for the binary `section` from `config`.
+///
+/// Returns a list of environment variables to set for `cmd` to pick up the configured options. The
+/// list includes every configured option, regardless of the current environment; use
+/// [`unset_vars`] to let the environment take precedence over the configuration file.
+pub fn env_vars(
+    cmd: &Command,
+    config: &Table,
+    section: &str,
+) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut options: Vec<(&String, &Value, bool)> = config
+        .iter()
+        .filter(|(_, value)| !value.is_table() && !is_array_of_tables_section(value))
+        .map(|(key, value)| (key, value, false))
+        .collect();
+    match config.get(section) {
+        Some(Value::Table(table)) => options.extend(table.iter().map(|(k, v)| (k, v, true))),
+        Some(_) => {
+            return Err(ConfigError::InvalidValue {
+                key: section.into(),
+                reason: "expected a table".into(),
+            })
+        }
+        None => {}
+    }
+
+    let mut vars: Vec<(String, String)> = vec![];
+    for (key, value, in_section) in options {
+        let name = key.replace('_', "-");
+        let Some(arg) = cmd
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(name.as_str()))
+        else {
+            // Top-level options may be meant for a different binary.
+            if in_section {
+                return Err(ConfigError::UnknownOption {
+                    section: section.into(),
+                    key: key.clone(),
+                });
+            }
+            continue;
+        };
+        let Some(var) = arg.get_env() else {
+            return Err(ConfigError::Unsupported { key: key.clone() });
+        };
+        let var = var.to_string_lossy().into_owned();
+        let value = arg_value(arg, key, value)?;
+        // Options in the binary's own section replace top-level options.
+        vars.retain(|(v, _)| *v != var);
+        vars.push((var, value));
+    }
+
+    Ok(vars)
+}
+
+/// Keep only the variables in `vars` which are not already set in the environment.
+pub fn unset_vars(vars: Vec<(String, String)>) -> Vec<(String, String)> {
+    vars.into_iter()
+        .filter(|(var, _)| env::var_os(var).is_none())
+        .collect()
+}
+
+/// Whether `value` is an array of tables, as in a `[[section.option]]` entry.
+fn is_array_of_tables_section(value: &Value) -> bool {
+    matches!(value, Value::Array(values) if values.iter().all(Value::is_table) && !values.is_empty())
+}
+
+/// Format `value` the way `arg` expects to find it in its environment variable.
+fn arg_value(arg: &Arg, key: &str, value: &Value) -> Result<String, ConfigError> {
+    match value {
+        Value::Array(values) => {
+            let Some(delimiter) = arg.get_value_delimiter() else {
+                return Err(ConfigError::InvalidValue {
+                    key: key.into(),
+                    reason: "expected a single value".into(),
+                });
+            };
+            let values = values
+                .iter()
+                .map(|value| scalar_value(key, value))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(values.join(&delimiter.to_string()))
+        }
+        value => scalar_value(key, value),
+    }
+}
+
+fn scalar_value(key: &str, value: &Value) -> Result<String, ConfigError> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        Value::Datetime(d) => Ok(d.to_string()),
+        // Structured options, such as rollups, are written as comma-separated `key=value` pairs.
+        Value::Table(table) => table
+            .iter()
+            .map(|(k, v)| Ok(format!("{k}={}", scalar_value(key, v)?)))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|pairs| pairs.join(",")),
+        Value::Array(_) => Err(ConfigError::InvalidValue {
+            key: key.into(),
+            reason: "nested arrays are not supported".into(),
+        }),
+    }
+}
+
+/// Parse options for the binary `section`, taking a configuration file into account.
+///
+/// If the binary was invoked as `<binary> config check ...`, `check` is called on the parsed
+/// options, and the process exits, with a successful status if the configuration is valid.
+pub fn parse<T: Parser>(section: &str, check: impl FnOnce(&T) -> Result<(), String>) -> T {
+    let mut args: Vec<OsString> = env::args_os().collect();
+    let check_only = args.len() >= 3 && args[1] == "config" && args[2] == "check";
+    if check_only {
+        args.drain(1..3);
+    }
+
+    let cmd = <T as CommandFactory>::command().arg(
+        Arg::new("config")
+            .long("config")
+            .env(CONFIG_ENV)
+            .value_name("FILE")
+            .help("TOML configuration file"),
+    );
+    let path = config_path(&args);
+    if let Some(path) = &path {
+        let vars = load(path).and_then(|config| env_vars(&cmd, &config, section));
+        match vars {
+            Ok(vars) => {
+                for (var, value) in unset_vars(vars) {
+                    env::set_var(var, value);
+                }
+            }
+            Err(err) => {
+                eprintln!("error: {err}");
+                exit(2);
+            }
+        }
+    } else if check_only {
+        eprintln!("error: no configuration file given (use --config or {CONFIG_ENV})");
+        exit(2);
+    }
+
+    let matches = cmd.get_matches_from(args);
+    let opt = <T as FromArgMatches>::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if check_only {
+        match check(&opt) {
+            Ok(()) => {
+                println!("{}: OK", path.unwrap().display());
+                exit(0);
+            }
+            Err(err) => {
+                eprintln!("error: {err}");
+                exit(2);
+            }
+        }
+    }
+    opt
+}
+
+/// Find the configuration file given on the command line or in the environment.
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(path.into());
+        }
+    }
+    env::var_os(CONFIG_ENV).map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Options;
+
+    #[test]
+    fn test_env_vars() {
+        let config: Table = r#"
+            sequencer-url = "http://sequencer:50000"
+            l1-provider = "http://l1:8545"
+            mnemonic = "for another binary"
+
+            [adaptor]
+            l1-provider = "http://other-l1:8545"
+            rate_limit_per_ip = 2.5
+            api-key = ["key1", "key2"]
+            verify = true
+
+            [[adaptor.rollup]]
+            chain-id = 1002
+            rpc-port = 8546
+            query-port = 50101
+
+            [[adaptor.rollup]]
+            chain-id = 1003
+            rpc-port = 8547
+            query-port = 50102
+        "#
+        .parse()
+        .unwrap();
+        let mut vars = env_vars(&Options::command(), &config, "adaptor").unwrap();
+        vars.sort();
+        assert_eq!(
+            vars,
+            [
+                ("ESPRESSO_SEQUENCER_URL", "http://sequencer:50000"),
+                ("ESPRESSO_ZKEVM_ADAPTOR_API_KEYS", "key1,key2"),
+                ("ESPRESSO_ZKEVM_ADAPTOR_RATE_LIMIT_PER_IP", "2.5"),
+                (
+                    "ESPRESSO_ZKEVM_ADAPTOR_ROLLUPS",
+                    "chain-id=1002,query-port=50101,rpc-port=8546;\
+                     chain-id=1003,query-port=50102,rpc-port=8547"
+                ),
+                ("ESPRESSO_ZKEVM_ADAPTOR_VERIFY", "true"),
+                ("ESPRESSO_ZKEVM_L1_PROVIDER", "http://other-l1:8545"),
+            ]
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_unset_vars() {
+        env::set_var("ESPRESSO_ZKEVM_TEST_UNSET_VARS_SET", "from-env");
+        env::remove_var("ESPRESSO_ZKEVM_TEST_UNSET_VARS_UNSET");
+        let vars = [
+            ("ESPRESSO_ZKEVM_TEST_UNSET_VARS_SET", "from-config"),
+            ("ESPRESSO_ZKEVM_TEST_UNSET_VARS_UNSET", "from-config"),
+        ]
+        .map(|(var, value)| (var.to_string(), value.to_string()))
+        .to_vec();
+        assert_eq!(
+            unset_vars(vars),
+            [(
+                "ESPRESSO_ZKEVM_TEST_UNSET_VARS_UNSET".to_string(),
+                "from-config".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_unknown_option() {
+        let config: Table = "[adaptor]\nsequencer = \"http://sequencer:50000\""
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            env_vars(&Options::command(), &config, "adaptor"),
+            Err(ConfigError::UnknownOption { .. })
+        ));
+    }
+}
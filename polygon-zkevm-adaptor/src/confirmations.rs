@@ -0,0 +1,142 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Soft confirmations for transactions submitted through the adaptor.
+//!
+//! A transaction is final as soon as the sequencer includes it in a block, since decided HotShot
+//! blocks are never reverted. The zkEVM node only executes it later, after fetching the block from
+//! the adaptor, so wallets can show users a confirmation much sooner by asking the adaptor whether
+//! a transaction has been sequenced yet.
+//!
+//! The adaptor remembers the hashes of recently submitted transactions, and looks for them in each
+//! block it fetches from the sequencer. If the adaptor verifies blocks against the HotShot contract,
+//! transactions are only confirmed once their block has been verified.
+
+use ethers::types::H256;
+use hotshot_query_service::availability::BlockQueryData;
+use lru::LruCache;
+use sequencer::SeqTypes;
+use serde::{Deserialize, Serialize};
+use std::{num::NonZeroUsize, sync::Mutex, time::Instant};
+use zkevm::ZkEvm;
+
+/// The status of a transaction submitted through the adaptor.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Confirmation {
+    pub hash: H256,
+    /// Whether the transaction has been included in a finalized Espresso block.
+    pub confirmed: bool,
+    /// Height of the Espresso block which includes the transaction.
+    pub block_height: Option<u64>,
+    /// Time from submission until the adaptor saw the transaction in a block, in milliseconds.
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    submitted: Instant,
+    /// The block height and latency, once the transaction has been sequenced.
+    sequenced: Option<(u64, u64)>,
+}
+
+#[derive(Debug)]
+pub struct Confirmations {
+    /// `None` if tracking is disabled.
+    transactions: Option<Mutex<LruCache<H256, Entry>>>,
+}
+
+impl Confirmations {
+    /// Track up to `size` recently submitted transactions.
+    ///
+    /// If `size` is 0, tracking is disabled and every lookup returns `None`.
+    pub fn new(size: usize) -> Self {
+        Self {
+            transactions: NonZeroUsize::new(size).map(|size| Mutex::new(LruCache::new(size))),
+        }
+    }
+
+    /// Start tracking a transaction which is about to be submitted to the sequencer.
+    pub fn submitted(&self, hash: H256) {
+        if let Some(transactions) = &self.transactions {
+            // If the same transaction is submitted twice, keep the original submission time.
+            transactions.lock().unwrap().get_or_insert(hash, || Entry {
+                submitted: Instant::now(),
+                sequenced: None,
+            });
+        }
+    }
+
+    /// Confirm any tracked transactions included in `block`.
+    pub fn record_block(&self, zkevm: ZkEvm, block: &BlockQueryData<SeqTypes>) {
+        let Some(transactions) = &self.transactions else {
+            return;
+        };
+        let txns = zkevm.vm_transactions(block.payload());
+        if txns.is_empty() {
+            return;
+        }
+        let mut transactions = transactions.lock().unwrap();
+        for txn in txns {
+            let hash = txn.hash();
+            if let Some(entry) = transactions.peek_mut(&hash) {
+                if entry.sequenced.is_none() {
+                    let latency = entry.submitted.elapsed().as_millis() as u64;
+                    tracing::debug!(
+                        "transaction {hash:?} sequenced in block {} after {latency} ms",
+                        block.height()
+                    );
+                    entry.sequenced = Some((block.height(), latency));
+                }
+            }
+        }
+    }
+
+    /// The status of transaction `hash`, if it was submitted recently.
+    pub fn get(&self, hash: H256) -> Option<Confirmation> {
+        let entry = *self.transactions.as_ref()?.lock().unwrap().get(&hash)?;
+        Some(Confirmation {
+            hash,
+            confirmed: entry.sequenced.is_some(),
+            block_height: entry.sequenced.map(|(height, _)| height),
+            latency_ms: entry.sequenced.map(|(_, latency)| latency),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_confirmations() {
+        let confirmations = Confirmations::new(2);
+        let hashes = [H256::random(), H256::random(), H256::random()];
+        assert_eq!(confirmations.get(hashes[0]), None);
+
+        confirmations.submitted(hashes[0]);
+        assert_eq!(
+            confirmations.get(hashes[0]),
+            Some(Confirmation {
+                hash: hashes[0],
+                confirmed: false,
+                block_height: None,
+                latency_ms: None,
+            })
+        );
+
+        // Only the most recent transactions are tracked.
+        confirmations.submitted(hashes[1]);
+        confirmations.submitted(hashes[2]);
+        assert_eq!(confirmations.get(hashes[0]), None);
+        assert!(confirmations.get(hashes[2]).is_some());
+
+        let disabled = Confirmations::new(0);
+        disabled.submitted(hashes[0]);
+        assert_eq!(disabled.get(hashes[0]), None);
+    }
+}
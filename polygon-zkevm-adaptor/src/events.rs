@@ -0,0 +1,254 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Structured event stream for observability tooling.
+//!
+//! The adaptor and the load test clients can stream machine-readable events, such as transaction
+//! submissions, blocks forwarded to the L2 node, sequencer responses and errors, to any number of
+//! subscribers connected to a Unix socket. Each event is written as a single line of JSON, with a
+//! `type` field identifying the event and a `version` field identifying the schema. Unlike the log
+//! output, the schema of an event only changes when [`SCHEMA_VERSION`] is bumped.
+//!
+//! Events are dropped if there are no subscribers, and a subscriber which falls too far behind
+//! misses events rather than slowing down the service.
+
+use async_std::{
+    channel::{bounded, Receiver, Sender, TrySendError},
+    io::WriteExt,
+    os::unix::net::UnixListener,
+    sync::Arc,
+    task::spawn,
+};
+use ethers::types::{H256, U256};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Version of the event schema, included in every event.
+///
+/// This is bumped whenever an existing event changes incompatibly. Adding new events does not
+/// change the version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Number of events buffered for each subscriber before it starts missing events.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// The adaptor accepted a transaction submitted to its JSON-RPC API.
+    TransactionReceived { chain_id: u64, hash: H256 },
+    /// The adaptor rejected an invalid transaction without forwarding it to the sequencer.
+    TransactionRejected { chain_id: u64, error: String },
+    /// The sequencer accepted a transaction forwarded by the adaptor.
+    TransactionForwarded { chain_id: u64, hash: H256 },
+    /// The sequencer did not accept a transaction forwarded by the adaptor.
+    SubmissionFailed {
+        chain_id: u64,
+        hash: H256,
+        error: String,
+    },
+    /// The adaptor synced a block from the sequencer and made it available to the L2 node.
+    BlockForwarded {
+        chain_id: u64,
+        height: u64,
+        /// Number of transactions in the block for this rollup.
+        transactions: usize,
+    },
+    /// A request from the adaptor to the sequencer failed.
+    SequencerError { chain_id: u64, error: String },
+    /// A load test run submitted a transfer.
+    TransferSubmitted { run: String, hash: H256 },
+    /// A load test run failed to submit a transfer.
+    TransferFailed { run: String, error: String },
+    /// A transfer submitted by a load test run completed, successfully or not.
+    TransferCompleted {
+        run: String,
+        hash: H256,
+        success: bool,
+        gas_used: Option<U256>,
+        latency_ms: u64,
+    },
+    /// A load test run gave up waiting for the receipt of a transfer.
+    TransferTimedOut { run: String, hash: H256 },
+}
+
+/// An [`Event`], as written to the stream.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EventRecord {
+    pub version: u32,
+    /// Time at which the event was emitted, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl EventRecord {
+    pub fn new(event: Event) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            event,
+        }
+    }
+}
+
+/// Fans events out to all subscribers.
+#[derive(Debug, Default)]
+pub struct EventStream {
+    subscribers: Mutex<Vec<Sender<Arc<str>>>>,
+}
+
+impl EventStream {
+    /// Send `event` to all subscribers.
+    pub fn emit(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let mut line = serde_json::to_string(&EventRecord::new(event)).unwrap();
+        line.push('\n');
+        let line: Arc<str> = line.into();
+        subscribers.retain(|subscriber| match subscriber.try_send(line.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("event subscriber is lagging, dropping event");
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+    }
+
+    /// Subscribe to all future events, each as a newline-terminated line of JSON.
+    pub fn subscribe(&self) -> Receiver<Arc<str>> {
+        let (sender, receiver) = bounded(SUBSCRIBER_BUFFER);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Stream events to every client which connects to a Unix socket at `path`.
+    ///
+    /// A socket left over at `path` from a previous run is replaced.
+    pub async fn serve(self: Arc<Self>, path: PathBuf) {
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() {
+                if let Err(err) = std::fs::remove_file(&path) {
+                    tracing::warn!("unable to remove stale socket {}: {err}", path.display());
+                }
+            }
+        }
+        let listener = match UnixListener::bind(&path).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                tracing::error!("unable to stream events to {}: {err}", path.display());
+                return;
+            }
+        };
+        tracing::info!("streaming events to {}", path.display());
+
+        let mut incoming = listener.incoming();
+        while let Some(conn) = incoming.next().await {
+            let mut conn = match conn {
+                Ok(conn) => conn,
+                Err(err) => {
+                    tracing::warn!("error accepting event subscriber: {err}");
+                    continue;
+                }
+            };
+            let mut events = self.subscribe();
+            spawn(async move {
+                while let Some(line) = events.next().await {
+                    if let Err(err) = conn.write_all(line.as_bytes()).await {
+                        tracing::debug!("event subscriber disconnected: {err}");
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::{
+        io::{prelude::BufReadExt, BufReader},
+        os::unix::net::UnixStream,
+        task::sleep,
+    };
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[test]
+    fn test_event_schema() {
+        let hash = H256::repeat_byte(1);
+        let record = EventRecord {
+            version: SCHEMA_VERSION,
+            timestamp: 1000,
+            event: Event::TransactionForwarded {
+                chain_id: 1001,
+                hash,
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            json!({
+                "version": 1,
+                "timestamp": 1000,
+                "type": "transaction_forwarded",
+                "chain_id": 1001,
+                "hash": format!("{hash:?}"),
+            })
+        );
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(serde_json::from_str::<EventRecord>(&line).unwrap(), record);
+    }
+
+    #[async_std::test]
+    async fn test_event_socket() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("events.sock");
+        let events = Arc::new(EventStream::default());
+        // Nothing happens if there are no subscribers.
+        events.emit(Event::SequencerError {
+            chain_id: 1001,
+            error: "dropped".into(),
+        });
+
+        spawn(events.clone().serve(path.clone()));
+        let conn = loop {
+            match UnixStream::connect(&path).await {
+                Ok(conn) => break conn,
+                Err(_) => sleep(Duration::from_millis(100)).await,
+            }
+        };
+        // Wait for the server to register the subscription.
+        while events.subscribers.lock().unwrap().is_empty() {
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        let event = Event::BlockForwarded {
+            chain_id: 1001,
+            height: 5,
+            transactions: 2,
+        };
+        events.emit(event.clone());
+        let mut line = String::new();
+        BufReader::new(conn).read_line(&mut line).await.unwrap();
+        let record: EventRecord = serde_json::from_str(&line).unwrap();
+        assert_eq!(record.version, SCHEMA_VERSION);
+        assert_eq!(record.event, event);
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A JSON-RPC transport which fails over between several providers.
+//!
+//! [`FailoverClient`] sends each request to the current provider. Transient errors (the provider
+//! could not be reached, or did not respond with valid JSON-RPC) are retried with exponential
+//! backoff, and after a number of consecutive failures the client rotates to the next provider.
+//! Errors returned by the provider itself, such as a rejected transaction, are not retried.
+
+#![cfg(any(test, feature = "testing"))]
+
+use async_std::task::sleep;
+use ethers::{
+    prelude::{MnemonicBuilder, Signer as _},
+    providers::{Http, HttpClientError, JsonRpcClient, Middleware, Provider},
+    signers::{coins_bip39::English, LocalWallet},
+};
+use http_types::Url;
+use sequencer_utils::Signer;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt::Debug,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tide::utils::async_trait;
+
+pub type FailoverSigner =
+    ethers::middleware::SignerMiddleware<Provider<FailoverClient>, LocalWallet>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct FailoverOptions {
+    /// How many times to retry a request which failed with a transient error.
+    pub max_retries: u32,
+    /// How long to wait before the first retry. The wait doubles after each retry.
+    pub initial_backoff: Duration,
+    /// The longest to wait between retries.
+    pub max_backoff: Duration,
+    /// How many consecutive transient errors from a provider trigger rotation to the next one.
+    pub failures_before_rotate: u32,
+}
+
+impl Default for FailoverOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            failures_before_rotate: 3,
+        }
+    }
+}
+
+/// Request statistics for a single provider.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ProviderHealth {
+    pub url: String,
+    pub requests: u64,
+    /// Requests which failed with a transient error.
+    pub failures: u64,
+    /// Number of times the client rotated away from this provider.
+    pub rotations: u64,
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    consecutive_failures: u32,
+}
+
+#[derive(Debug)]
+struct FailoverState {
+    current: usize,
+    health: Vec<ProviderHealth>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FailoverClient {
+    providers: Arc<Vec<Http>>,
+    state: Arc<Mutex<FailoverState>>,
+    opt: FailoverOptions,
+}
+
+impl FailoverClient {
+    /// Create a client for `urls`, in order of preference.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `urls` is empty.
+    pub fn new(urls: &[Url], opt: FailoverOptions) -> Self {
+        assert!(!urls.is_empty(), "at least one provider is required");
+        let providers = urls
+            .iter()
+            .map(|url| Http::from_str(url.as_str()).unwrap())
+            .collect();
+        let health = urls
+            .iter()
+            .map(|url| ProviderHealth {
+                url: url.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        Self {
+            providers: Arc::new(providers),
+            state: Arc::new(Mutex::new(FailoverState { current: 0, health })),
+            opt,
+        }
+    }
+
+    /// Request statistics for each provider.
+    pub fn health(&self) -> Vec<ProviderHealth> {
+        self.state.lock().unwrap().health.clone()
+    }
+
+    fn current(&self) -> usize {
+        self.state.lock().unwrap().current
+    }
+
+    /// Record the result of a request to provider `index`.
+    fn record(&self, index: usize, error: Option<&HttpClientError>) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let health = &mut state.health[index];
+        health.requests += 1;
+        let Some(error) = error else {
+            health.consecutive_failures = 0;
+            return;
+        };
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        health.last_error = Some(error.to_string());
+        tracing::warn!("request to {} failed: {error}", health.url);
+
+        // Another request may already have rotated away from this provider.
+        if health.consecutive_failures >= self.opt.failures_before_rotate
+            && self.providers.len() > 1
+            && state.current == index
+        {
+            health.rotations += 1;
+            health.consecutive_failures = 0;
+            state.current = (index + 1) % self.providers.len();
+            tracing::warn!(
+                "rotating from {} to {}",
+                state.health[index].url,
+                state.health[state.current].url
+            );
+        }
+    }
+}
+
+/// Whether `err` may succeed if the request is retried.
+fn is_transient(err: &HttpClientError) -> bool {
+    // A JSON-RPC error means the provider is up and rejected the request.
+    !matches!(err, HttpClientError::JsonRpcError(_))
+}
+
+#[async_trait]
+impl JsonRpcClient for FailoverClient {
+    type Error = HttpClientError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, HttpClientError>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        // Serialize the parameters up front, so we can reuse them for retries.
+        let params = serde_json::to_value(params).map_err(|err| HttpClientError::SerdeJson {
+            err,
+            text: format!("parameters for {method}"),
+        })?;
+        let mut backoff = self.opt.initial_backoff;
+        let mut retries = 0;
+        loop {
+            let index = self.current();
+            let err = match self.providers[index].request(method, &params).await {
+                Ok(res) => {
+                    self.record(index, None);
+                    return Ok(res);
+                }
+                Err(err) if !is_transient(&err) => {
+                    self.record(index, None);
+                    return Err(err);
+                }
+                Err(err) => err,
+            };
+            self.record(index, Some(&err));
+            if retries >= self.opt.max_retries {
+                return Err(err);
+            }
+            retries += 1;
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(self.opt.max_backoff);
+        }
+    }
+}
+
+/// Use the provider and wallet of `signer` with failover options `opt`.
+pub fn with_failover(signer: &Signer, opt: FailoverOptions) -> FailoverSigner {
+    let client = FailoverClient::new(&[signer.provider().as_ref().url().clone()], opt);
+    FailoverSigner::new(Provider::new(client), signer.signer().clone())
+}
+
+/// Connect a wallet to `providers`, in order of preference.
+///
+/// This is like [`connect_rpc_simple`](crate::connect_rpc_simple), but the resulting signer fails
+/// over between providers.
+pub async fn connect_rpc_failover(
+    providers: &[Url],
+    mnemonic: &str,
+    index: u32,
+    chain_id: Option<u64>,
+    opt: FailoverOptions,
+) -> Option<FailoverSigner> {
+    let provider = Provider::new(FailoverClient::new(providers, opt));
+    let chain_id = match chain_id {
+        Some(id) => id,
+        None => match provider.get_chainid().await {
+            Ok(id) => id.as_u64(),
+            Err(err) => {
+                tracing::error!("error getting chain ID: {}", err);
+                return None;
+            }
+        },
+    };
+    let wallet = match MnemonicBuilder::<English>::default()
+        .phrase(mnemonic)
+        .index(index)
+        .and_then(|mnemonic| mnemonic.build())
+    {
+        Ok(wallet) => wallet,
+        Err(err) => {
+            tracing::error!("error opening wallet: {}", err);
+            return None;
+        }
+    };
+    Some(FailoverSigner::new(
+        provider,
+        wallet.with_chain_id(chain_id),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unreachable() -> Url {
+        let port = portpicker::pick_unused_port().unwrap();
+        format!("http://127.0.0.1:{port}").parse().unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_failover() {
+        let urls = [unreachable(), unreachable()];
+        let client = FailoverClient::new(
+            &urls,
+            FailoverOptions {
+                max_retries: 3,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                failures_before_rotate: 2,
+            },
+        );
+        client
+            .request::<_, serde_json::Value>("eth_chainId", ())
+            .await
+            .unwrap_err();
+
+        // The first two attempts go to the first provider, then we rotate to the second.
+        let health = client.health();
+        assert_eq!(health[0].requests, 2);
+        assert_eq!(health[0].failures, 2);
+        assert_eq!(health[0].rotations, 1);
+        assert_eq!(health[1].requests, 2);
+        assert_eq!(health[1].failures, 2);
+        assert_eq!(health[1].rotations, 1);
+        assert!(health[1].last_error.is_some());
+        assert_eq!(client.current(), 0);
+    }
+}
@@ -0,0 +1,527 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A faucet for the demo L2.
+//!
+//! The faucet holds a funded L2 account and sends a fixed amount of test ETH to anyone who asks for
+//! it with `POST /faucet/request/:address`. Transfers are submitted through the adaptor, like any
+//! other L2 transaction, while the account's balance, the gas price and the chain ID are read from a
+//! zkEVM node.
+//!
+//! Each recipient address can only be funded once per cooldown period, and requests can also be
+//! rate limited per client IP. If a captcha secret is configured, each request must include a
+//! captcha response token in the `X-Captcha-Response` header, which is checked with the captcha
+//! provider before any funds are sent.
+
+use crate::rate_limit::{RateLimitOptions, RateLimiter};
+use async_std::sync::Mutex;
+use clap::Parser;
+use ethers::{
+    prelude::{MnemonicBuilder, Signer as _},
+    providers::{Http, Middleware, Provider, ProviderError},
+    signers::{coins_bip39::English, LocalWallet, WalletError},
+    types::{transaction::eip2718::TypedTransaction, Address, TransactionRequest, H256, U256},
+    utils::{parse_ether, ConversionError},
+};
+use http_types::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use snafu::Snafu;
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    net::SocketAddr,
+    num::ParseIntError,
+    sync::{Arc, Mutex as SyncMutex},
+    time::{Duration, Instant},
+};
+
+/// Header carrying the client's captcha response token.
+pub const CAPTCHA_HEADER: &str = "X-Captcha-Response";
+
+/// Gas limit for a plain ETH transfer.
+const TRANSFER_GAS: u64 = 21000;
+
+/// Number of recipients to remember before pruning expired cooldowns.
+const MAX_TRACKED_RECIPIENTS: usize = 10000;
+
+#[derive(Clone, Debug, Parser)]
+pub struct FaucetOptions {
+    /// Port on which to serve the faucet API.
+    #[clap(long, env = "ESPRESSO_ZKEVM_FAUCET_PORT", default_value = "8111")]
+    pub port: u16,
+
+    /// URL of the adaptor JSON-RPC service, used to submit transfers.
+    #[clap(long, env = "ESPRESSO_ZKEVM_FAUCET_ADAPTOR_RPC")]
+    pub adaptor_rpc: Url,
+
+    /// URL of an L2 zkEVM node JSON-RPC service, used to read the chain state.
+    #[clap(long, env = "ESPRESSO_ZKEVM_FAUCET_L2_PROVIDER")]
+    pub l2_provider: Url,
+
+    /// Mnemonic for the funded L2 account which the faucet sends from.
+    #[clap(long, env = "ESPRESSO_ZKEVM_FAUCET_MNEMONIC")]
+    pub mnemonic: String,
+
+    /// Index of the faucet account derived from the mnemonic.
+    #[clap(long, env = "ESPRESSO_ZKEVM_FAUCET_ACCOUNT_INDEX", default_value = "0")]
+    pub account_index: u32,
+
+    /// Amount of ETH to send for each request.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_FAUCET_GRANT_AMOUNT",
+        default_value = "1",
+        value_parser = |arg: &str| -> Result<U256, ConversionError> { parse_ether(arg) }
+    )]
+    pub grant_amount: U256,
+
+    /// Minimum time in milliseconds between grants to the same address.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_FAUCET_COOLDOWN",
+        default_value = "3600000",
+        value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(Duration::from_millis(arg.parse()?)) }
+    )]
+    pub cooldown: Duration,
+
+    /// Time in milliseconds to wait for a transfer to be included in the L2 before giving up on it.
+    ///
+    /// If the oldest outstanding transfer has no receipt after this long, it was probably dropped,
+    /// and the faucet reads its next nonce from the L2 again instead of building on top of it.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_FAUCET_RECEIPT_TIMEOUT",
+        default_value = "60000",
+        value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(Duration::from_millis(arg.parse()?)) }
+    )]
+    pub receipt_timeout: Duration,
+
+    /// Maximum rate of requests, in requests per second, from a single IP.
+    #[clap(long, env = "ESPRESSO_ZKEVM_FAUCET_RATE_LIMIT_PER_IP")]
+    pub rate_limit_per_ip: Option<f64>,
+
+    /// Maximum number of requests which can be made at once from a single IP.
+    ///
+    /// Defaults to one second's worth of requests.
+    #[clap(long, env = "ESPRESSO_ZKEVM_FAUCET_RATE_LIMIT_PER_IP_BURST")]
+    pub rate_limit_per_ip_burst: Option<u32>,
+
+    /// Secret key for the captcha provider.
+    ///
+    /// If given, every request must include a captcha response token in the `X-Captcha-Response`
+    /// header.
+    #[clap(long, env = "ESPRESSO_ZKEVM_FAUCET_CAPTCHA_SECRET")]
+    pub captcha_secret: Option<String>,
+
+    /// Endpoint of the captcha provider used to verify captcha responses.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_FAUCET_CAPTCHA_VERIFY_URL",
+        default_value = "https://hcaptcha.com/siteverify"
+    )]
+    pub captcha_verify_url: Url,
+}
+
+#[derive(Debug, Snafu)]
+pub enum FaucetError {
+    #[snafu(display("L2 provider error: {source}"))]
+    Provider { source: ProviderError },
+
+    #[snafu(display("unable to sign transfer: {source}"))]
+    Signing { source: WalletError },
+}
+
+impl From<ProviderError> for FaucetError {
+    fn from(source: ProviderError) -> Self {
+        Self::Provider { source }
+    }
+}
+
+impl From<WalletError> for FaucetError {
+    fn from(source: WalletError) -> Self {
+        Self::Signing { source }
+    }
+}
+
+/// Tracks when each key was last granted funds.
+#[derive(Debug)]
+pub struct Cooldowns<K> {
+    period: Duration,
+    last_grant: SyncMutex<HashMap<K, Instant>>,
+}
+
+impl<K: Clone + Eq + Hash> Cooldowns<K> {
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            last_grant: Default::default(),
+        }
+    }
+
+    /// Start a grant to `key` at time `now`, unless it is still cooling down.
+    ///
+    /// On failure, returns how long until `key` can be granted funds again.
+    pub fn try_start(&self, key: &K, now: Instant) -> Result<(), Duration> {
+        let mut last_grant = self.last_grant.lock().unwrap();
+        if let Some(last) = last_grant.get(key) {
+            let elapsed = now.saturating_duration_since(*last);
+            if elapsed < self.period {
+                return Err(self.period - elapsed);
+            }
+        }
+        if last_grant.len() >= MAX_TRACKED_RECIPIENTS {
+            // Keys which are no longer cooling down are indistinguishable from new ones.
+            last_grant.retain(|_, last| now.saturating_duration_since(*last) < self.period);
+        }
+        last_grant.insert(key.clone(), now);
+        Ok(())
+    }
+
+    /// Forget about a grant which failed, so that `key` can try again right away.
+    pub fn cancel(&self, key: &K) {
+        self.last_grant.lock().unwrap().remove(key);
+    }
+}
+
+/// Tracks the nonce of the faucet account and the transfers which have not been confirmed yet.
+#[derive(Debug)]
+pub struct Nonces {
+    timeout: Duration,
+    next: Option<U256>,
+    outstanding: VecDeque<(H256, Instant)>,
+}
+
+impl Nonces {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next: None,
+            outstanding: Default::default(),
+        }
+    }
+
+    /// The nonce of the next transfer, if known.
+    pub fn next(&self) -> Option<U256> {
+        self.next
+    }
+
+    /// Record a transfer with nonce `nonce` and hash `hash`, submitted at time `now`.
+    pub fn sent(&mut self, nonce: U256, hash: H256, now: Instant) {
+        self.next = Some(nonce + 1);
+        self.outstanding.push_back((hash, now));
+    }
+
+    /// The hash of the oldest outstanding transfer, if it has been outstanding for longer than the
+    /// receipt timeout at time `now`.
+    pub fn expired(&self, now: Instant) -> Option<H256> {
+        let (hash, sent) = self.outstanding.front()?;
+        (now.saturating_duration_since(*sent) >= self.timeout).then_some(*hash)
+    }
+
+    /// Stop tracking the oldest outstanding transfer, which has been included in the L2.
+    pub fn confirmed(&mut self) {
+        self.outstanding.pop_front();
+    }
+
+    /// Forget the nonce and all outstanding transfers, so that the nonce is read from the L2 again.
+    pub fn reset(&mut self) {
+        self.next = None;
+        self.outstanding.clear();
+    }
+}
+
+#[derive(Debug)]
+struct Faucet {
+    opt: FaucetOptions,
+    wallet: LocalWallet,
+    l2: Provider<Http>,
+    adaptor: Provider<Http>,
+    /// The nonce of the next transfer, and the transfers which are still outstanding.
+    ///
+    /// Transfers are submitted through the sequencer rather than the zkEVM node's mempool, so the
+    /// node does not know about pending transfers and we have to keep track of the nonce
+    /// ourselves. The lock also serializes transfers, so that each one gets its own nonce.
+    nonces: Mutex<Nonces>,
+    cooldowns: Cooldowns<Address>,
+    limiter: RateLimiter,
+}
+
+type FaucetRequest = tide::Request<Arc<Faucet>>;
+
+impl Faucet {
+    async fn new(opt: FaucetOptions) -> Self {
+        let l2 = Provider::try_from(opt.l2_provider.as_str()).unwrap();
+        let adaptor = Provider::try_from(opt.adaptor_rpc.as_str()).unwrap();
+        let chain_id = l2
+            .get_chainid()
+            .await
+            .expect("unable to get L2 chain ID")
+            .as_u64();
+        let wallet = MnemonicBuilder::<English>::default()
+            .phrase(opt.mnemonic.as_str())
+            .index(opt.account_index)
+            .expect("invalid faucet account index")
+            .build()
+            .expect("invalid faucet mnemonic")
+            .with_chain_id(chain_id);
+        let limiter = RateLimiter::new(&RateLimitOptions {
+            rate_limit_per_ip: opt.rate_limit_per_ip,
+            rate_limit_per_ip_burst: opt.rate_limit_per_ip_burst,
+            ..Default::default()
+        });
+        Self {
+            cooldowns: Cooldowns::new(opt.cooldown),
+            nonces: Mutex::new(Nonces::new(opt.receipt_timeout)),
+            opt,
+            wallet,
+            l2,
+            adaptor,
+            limiter,
+        }
+    }
+
+    /// Check a captcha response token with the captcha provider.
+    async fn verify_captcha(&self, secret: &str, token: &str) -> Result<bool, surf::Error> {
+        #[derive(Serialize)]
+        struct VerifyRequest<'a> {
+            secret: &'a str,
+            response: &'a str,
+        }
+        #[derive(Deserialize)]
+        struct VerifyResponse {
+            success: bool,
+        }
+
+        let res: VerifyResponse = surf::post(&self.opt.captcha_verify_url)
+            .body_form(&VerifyRequest {
+                secret,
+                response: token,
+            })?
+            .recv_json()
+            .await?;
+        Ok(res.success)
+    }
+
+    /// Send the grant amount to `to`, returning the transaction hash.
+    async fn transfer(&self, to: Address) -> Result<H256, FaucetError> {
+        let mut nonces = self.nonces.lock().await;
+        if let Err(err) = self.check_outstanding(&mut nonces).await {
+            nonces.reset();
+            return Err(err);
+        }
+        let next_nonce = match nonces.next() {
+            Some(nonce) => nonce,
+            None => {
+                self.l2
+                    .get_transaction_count(self.wallet.address(), None)
+                    .await?
+            }
+        };
+        let gas_price = self.l2.get_gas_price().await?;
+        let tx: TypedTransaction = TransactionRequest::new()
+            .from(self.wallet.address())
+            .to(to)
+            .value(self.opt.grant_amount)
+            .nonce(next_nonce)
+            .gas(TRANSFER_GAS)
+            .gas_price(gas_price)
+            .chain_id(self.wallet.chain_id())
+            .into();
+        let signature = self.wallet.sign_transaction(&tx).await?;
+        match self
+            .adaptor
+            .send_raw_transaction(tx.rlp_signed(&signature))
+            .await
+        {
+            Ok(pending) => {
+                nonces.sent(next_nonce, pending.tx_hash(), Instant::now());
+                Ok(pending.tx_hash())
+            }
+            Err(err) => {
+                // We don't know whether the nonce was used, so read it from the L2 next time.
+                nonces.reset();
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Check on outstanding transfers which have exceeded the receipt timeout.
+    ///
+    /// Transfers which have been included are no longer tracked. If the oldest one has not been
+    /// included, it was most likely dropped by the sequencer, in which case every later transfer is
+    /// stuck behind the gap in nonces. We forget our nonce, so that the next transfer reuses the
+    /// first nonce which the L2 has not seen.
+    async fn check_outstanding(&self, nonces: &mut Nonces) -> Result<(), FaucetError> {
+        while let Some(hash) = nonces.expired(Instant::now()) {
+            if self.l2.get_transaction_receipt(hash).await?.is_some() {
+                nonces.confirmed();
+            } else {
+                tracing::warn!("faucet transfer {hash:?} timed out, resynchronizing nonce");
+                nonces.reset();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn error_response(status: StatusCode, message: impl Into<String>) -> tide::Response {
+    tide::Response::builder(status)
+        .body(json!({ "error": message.into() }))
+        .build()
+}
+
+async fn request_funds(req: FaucetRequest) -> tide::Result {
+    let faucet = req.state();
+    let Ok(to) = req.param("address")?.parse::<Address>() else {
+        return Ok(error_response(StatusCode::BadRequest, "invalid address"));
+    };
+
+    let client = req
+        .peer_addr()
+        .and_then(|addr| addr.parse::<SocketAddr>().ok())
+        .map(|addr| addr.ip());
+    if faucet.limiter.check(client, Instant::now()).is_err() {
+        tracing::info!("rate limiting faucet request from {client:?}");
+        return Ok(error_response(
+            StatusCode::TooManyRequests,
+            "rate limit exceeded",
+        ));
+    }
+
+    if let Some(secret) = &faucet.opt.captcha_secret {
+        let Some(token) = req.header(CAPTCHA_HEADER) else {
+            return Ok(error_response(StatusCode::Forbidden, "missing captcha"));
+        };
+        match faucet.verify_captcha(secret, token.as_str()).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(error_response(StatusCode::Forbidden, "invalid captcha")),
+            Err(err) => {
+                tracing::error!("unable to verify captcha: {err}");
+                return Ok(error_response(
+                    StatusCode::ServiceUnavailable,
+                    "unable to verify captcha",
+                ));
+            }
+        }
+    }
+
+    if let Err(retry_after) = faucet.cooldowns.try_start(&to, Instant::now()) {
+        let mut res = error_response(
+            StatusCode::TooManyRequests,
+            format!("{to:?} was funded recently"),
+        );
+        res.insert_header("Retry-After", retry_after.as_secs().max(1).to_string());
+        return Ok(res);
+    }
+
+    match faucet.transfer(to).await {
+        Ok(hash) => {
+            tracing::info!(
+                "sent {} wei to {to:?} in {hash:?} (client {client:?})",
+                faucet.opt.grant_amount
+            );
+            Ok(tide::Response::builder(StatusCode::Ok)
+                .body(json!({ "hash": hash }))
+                .build())
+        }
+        Err(err) => {
+            tracing::error!("unable to fund {to:?}: {err}");
+            faucet.cooldowns.cancel(&to);
+            Ok(error_response(
+                StatusCode::ServiceUnavailable,
+                "unable to send funds",
+            ))
+        }
+    }
+}
+
+/// Serve the faucet API.
+///
+/// Never returns.
+pub async fn serve(opt: FaucetOptions) {
+    let port = opt.port;
+    let faucet = Faucet::new(opt).await;
+    tracing::info!(
+        "faucet account {:?} sending from {:?}",
+        faucet.wallet.address(),
+        faucet.opt.adaptor_rpc.as_str()
+    );
+
+    let mut server = tide::with_state(Arc::new(faucet));
+    server.at("/faucet/request/:address").post(request_funds);
+    server
+        .at("/healthz")
+        .get(|_: FaucetRequest| async { Ok(StatusCode::Ok) });
+
+    tracing::info!("serving faucet on port {port}");
+    server.listen(format!("0.0.0.0:{port}")).await.unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cooldowns() {
+        let cooldowns = Cooldowns::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let alice = Address::random();
+        let bob = Address::random();
+
+        cooldowns.try_start(&alice, start).unwrap();
+        assert_eq!(
+            cooldowns.try_start(&alice, start + Duration::from_secs(20)),
+            Err(Duration::from_secs(40))
+        );
+        // Other addresses are not affected.
+        cooldowns.try_start(&bob, start).unwrap();
+        // Alice can try again once the cooldown has expired.
+        cooldowns
+            .try_start(&alice, start + Duration::from_secs(60))
+            .unwrap();
+
+        // A failed grant can be retried right away.
+        cooldowns.cancel(&bob);
+        cooldowns.try_start(&bob, start).unwrap();
+    }
+
+    #[test]
+    fn test_nonces() {
+        let timeout = Duration::from_secs(60);
+        let mut nonces = Nonces::new(timeout);
+        let start = Instant::now();
+        assert_eq!(nonces.next(), None);
+
+        let hashes = [H256::random(), H256::random(), H256::random()];
+        for (i, hash) in hashes.iter().enumerate() {
+            nonces.sent((5 + i).into(), *hash, start + Duration::from_secs(i as u64));
+        }
+        assert_eq!(nonces.next(), Some(8.into()));
+
+        // Nothing needs checking until the oldest transfer times out.
+        assert_eq!(nonces.expired(start + Duration::from_secs(59)), None);
+        let now = start + timeout;
+        assert_eq!(nonces.expired(now), Some(hashes[0]));
+
+        // Once the oldest transfer is confirmed, the next one is only checked when it times out.
+        nonces.confirmed();
+        assert_eq!(nonces.expired(now), None);
+        assert_eq!(nonces.next(), Some(8.into()));
+
+        // If it never gets a receipt, we give up on the nonce and all the transfers after it.
+        let now = now + Duration::from_secs(1);
+        assert_eq!(nonces.expired(now), Some(hashes[1]));
+        nonces.reset();
+        assert_eq!(nonces.expired(now), None);
+        assert_eq!(nonces.next(), None);
+
+        // Transfers resume from the nonce read from the L2.
+        nonces.sent(6.into(), hashes[2], now);
+        assert_eq!(nonces.next(), Some(7.into()));
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Gas price oracles for the adaptor's JSON-RPC API.
+//!
+//! The zkEVM node's own answers to `eth_gasPrice` and `eth_maxPriorityFeePerGas` don't account for
+//! the cost of sequencing transactions with Espresso. When a [`GasPriceOracle`] is configured, the
+//! adaptor answers these methods itself, based on the usage of the rollup in recent Espresso blocks,
+//! which the oracle is told about as blocks are synced from the sequencer.
+//!
+//! `eth_feeHistory` is indexed by L2 block numbers, which the adaptor does not know, so it is still
+//! answered by the zkEVM node. The adaptor adds the oracle's current sequencing fee to the base fees
+//! and rewards in the node's answer, so that wallets which estimate fees from the history also pay
+//! for sequencing.
+
+use clap::{Args, ValueEnum};
+use ethers::types::U256;
+use hotshot_query_service::availability::BlockQueryData;
+use sequencer::{SeqTypes, VmTransaction};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+use zkevm::ZkEvm;
+
+/// Gas used by a plain transfer, over which the sequencing fee for a transaction is spread.
+const TRANSFER_GAS: u64 = 21_000;
+
+/// Size in bytes of a typical signed transfer, used when there are no recent transactions.
+const TYPICAL_TRANSACTION_SIZE: u64 = 110;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum GasOracleKind {
+    /// Do not answer gas price requests, leaving them to the zkEVM node.
+    #[default]
+    None,
+    /// Derive gas prices from the sequencer fee and recent usage of the rollup.
+    Sequencer,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct GasOracleOptions {
+    /// Gas price oracle answering `eth_gasPrice` and `eth_maxPriorityFeePerGas`, and adjusting
+    /// `eth_feeHistory`.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_GAS_ORACLE",
+        value_enum,
+        default_value = "none"
+    )]
+    pub gas_oracle: GasOracleKind,
+
+    /// Gas price in wei when the rollup is idle, not including the sequencing fee.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_GAS_PRICE_BASE",
+        default_value = "1000000000"
+    )]
+    pub gas_price_base: u64,
+
+    /// Fee charged by the sequencer, in wei per byte of transaction data.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_SEQUENCER_FEE_PER_BYTE",
+        default_value = "0"
+    )]
+    pub sequencer_fee_per_byte: u64,
+
+    /// Number of rollup transactions per Espresso block at which the rollup is fully used.
+    ///
+    /// The base gas price rises linearly with usage, up to double the base price at full usage.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_GAS_ORACLE_TARGET_TRANSACTIONS",
+        default_value = "100"
+    )]
+    pub gas_oracle_target_transactions: u64,
+
+    /// Number of recent Espresso blocks over which usage of the rollup is averaged.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_GAS_ORACLE_WINDOW",
+        default_value = "20"
+    )]
+    pub gas_oracle_window: usize,
+}
+
+impl Default for GasOracleOptions {
+    fn default() -> Self {
+        Self {
+            gas_oracle: GasOracleKind::None,
+            gas_price_base: 1_000_000_000,
+            sequencer_fee_per_byte: 0,
+            gas_oracle_target_transactions: 100,
+            gas_oracle_window: 20,
+        }
+    }
+}
+
+impl GasOracleOptions {
+    /// The configured oracle, if any.
+    pub fn oracle(&self) -> Option<Arc<dyn GasPriceOracle>> {
+        match self.gas_oracle {
+            GasOracleKind::None => None,
+            GasOracleKind::Sequencer => Some(Arc::new(SequencerFeeOracle::new(self))),
+        }
+    }
+}
+
+/// Usage of a rollup in a single Espresso block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockUsage {
+    pub height: u64,
+    /// Number of transactions for the rollup.
+    pub transactions: u64,
+    /// Total size in bytes of the transactions for the rollup.
+    pub bytes: u64,
+}
+
+impl BlockUsage {
+    pub fn new(zkevm: ZkEvm, block: &BlockQueryData<SeqTypes>) -> Self {
+        let txns = zkevm.vm_transactions(block.payload());
+        Self {
+            height: block.height(),
+            transactions: txns.len() as u64,
+            bytes: txns.iter().map(|txn| txn.encode().len() as u64).sum(),
+        }
+    }
+}
+
+/// A source of gas price suggestions.
+pub trait GasPriceOracle: Debug + Send + Sync {
+    /// Record the usage of the rollup in a newly sequenced block.
+    fn record_block(&self, usage: BlockUsage);
+
+    /// The gas price to suggest for new transactions, in wei.
+    fn gas_price(&self) -> U256;
+
+    /// The priority fee to suggest for new EIP-1559 transactions, in wei.
+    fn max_priority_fee_per_gas(&self) -> U256;
+
+    /// The part of the suggested gas price which pays for sequencing, in wei.
+    fn sequencing_fee(&self) -> U256;
+}
+
+/// An oracle pricing gas from the sequencer fee and recent usage of the rollup.
+///
+/// The gas price is the sum of a base price, which rises with the average number of rollup
+/// transactions in recent Espresso blocks, and the sequencer fee for a transaction of average size,
+/// spread over the gas used by a transfer. Espresso orders transactions without regard to fees, so
+/// there is no point in paying a priority fee.
+#[derive(Debug)]
+pub struct SequencerFeeOracle {
+    base: u64,
+    fee_per_byte: u64,
+    target_transactions: u64,
+    window: usize,
+    blocks: Mutex<VecDeque<BlockUsage>>,
+}
+
+impl SequencerFeeOracle {
+    pub fn new(opt: &GasOracleOptions) -> Self {
+        Self {
+            base: opt.gas_price_base,
+            fee_per_byte: opt.sequencer_fee_per_byte,
+            target_transactions: opt.gas_oracle_target_transactions.max(1),
+            window: opt.gas_oracle_window.max(1),
+            blocks: Default::default(),
+        }
+    }
+
+    /// The gas price for the given usage.
+    fn price(&self, usage: &[BlockUsage]) -> U256 {
+        let transactions: u64 = usage.iter().map(|block| block.transactions).sum();
+        let bytes: u64 = usage.iter().map(|block| block.bytes).sum();
+        let target = self.target_transactions * usage.len() as u64;
+
+        let base = U256::from(self.base);
+        let congestion = if target == 0 {
+            U256::zero()
+        } else {
+            base * transactions.min(target) / target
+        };
+        base + congestion + self.sequencing(transactions, bytes)
+    }
+
+    /// The sequencer fee for a transaction of average size, per unit of gas.
+    fn sequencing(&self, transactions: u64, bytes: u64) -> U256 {
+        let average_size = if transactions == 0 {
+            TYPICAL_TRANSACTION_SIZE
+        } else {
+            bytes / transactions
+        };
+        U256::from(self.fee_per_byte) * average_size / TRANSFER_GAS
+    }
+}
+
+impl GasPriceOracle for SequencerFeeOracle {
+    fn record_block(&self, usage: BlockUsage) {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.push_back(usage);
+        while blocks.len() > self.window {
+            blocks.pop_front();
+        }
+    }
+
+    fn gas_price(&self) -> U256 {
+        let mut blocks = self.blocks.lock().unwrap();
+        self.price(blocks.make_contiguous())
+    }
+
+    fn max_priority_fee_per_gas(&self) -> U256 {
+        U256::zero()
+    }
+
+    fn sequencing_fee(&self) -> U256 {
+        let blocks = self.blocks.lock().unwrap();
+        self.sequencing(
+            blocks.iter().map(|block| block.transactions).sum(),
+            blocks.iter().map(|block| block.bytes).sum(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sequencer_fee_oracle() {
+        let oracle = SequencerFeeOracle::new(&GasOracleOptions {
+            gas_price_base: 1000,
+            sequencer_fee_per_byte: 210,
+            gas_oracle_target_transactions: 10,
+            gas_oracle_window: 2,
+            ..Default::default()
+        });
+
+        // With no usage, the price is the base price plus the fee for a typical transaction.
+        assert_eq!(oracle.gas_price(), U256::from(1000 + 1));
+        assert_eq!(oracle.max_priority_fee_per_gas(), U256::zero());
+        assert_eq!(oracle.sequencing_fee(), U256::from(1));
+
+        // Half the target usage increases the base price by half, and the fee follows the average
+        // transaction size.
+        let block = |height, transactions| BlockUsage {
+            height,
+            transactions,
+            bytes: 200 * transactions,
+        };
+        oracle.record_block(block(1, 5));
+        assert_eq!(oracle.gas_price(), U256::from(1000 + 500 + 2));
+        assert_eq!(oracle.sequencing_fee(), U256::from(2));
+
+        // Usage above the target is capped, and only the most recent blocks count.
+        oracle.record_block(block(2, 30));
+        oracle.record_block(block(3, 10));
+        assert_eq!(oracle.gas_price(), U256::from(1000 + 1000 + 2));
+    }
+}
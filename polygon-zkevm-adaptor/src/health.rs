@@ -0,0 +1,209 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Health and readiness reporting for the adaptor services.
+//!
+//! The adaptor is considered live as long as it is serving requests, and ready once it can reach
+//! the sequencer and the L1, and the block store is within a configurable number of blocks of the
+//! sequencer. In verification mode, blocks can only be synced once they are committed to the L1, so
+//! the store is instead compared with the blocks committed in the HotShot contract. Connectivity to
+//! the zkEVM node is reported, if a node is configured, but does not affect readiness, since the
+//! node itself depends on the adaptor being up.
+
+use crate::{storage::BlockStore, verify::Verifier, Options};
+use async_std::{
+    future::timeout,
+    sync::{Arc, RwLock},
+};
+use ethers::providers::{Http, Middleware, Provider, ProviderError};
+use futures::join;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    future::Future,
+    time::Duration,
+};
+use tide_disco::{error::ServerError, StatusCode};
+
+/// How long to wait for a dependency to respond before considering it unreachable.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Connectivity to a service the adaptor depends on.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ServiceStatus {
+    pub connected: bool,
+    pub block_height: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl<E: Display> From<Result<u64, E>> for ServiceStatus {
+    fn from(res: Result<u64, E>) -> Self {
+        match res {
+            Ok(height) => Self {
+                connected: true,
+                block_height: Some(height),
+                error: None,
+            },
+            Err(err) => Self {
+                connected: false,
+                block_height: None,
+                error: Some(err.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HealthReport {
+    pub sequencer: ServiceStatus,
+    pub l1: ServiceStatus,
+    /// Connectivity to the zkEVM node, if one is configured.
+    pub l2: Option<ServiceStatus>,
+    /// Number of blocks the adaptor has synced from the sequencer.
+    pub synced_height: u64,
+    /// Number of blocks which have not been synced yet, out of the blocks available to sync: all
+    /// sequencer blocks, or in verification mode, blocks committed in the HotShot contract.
+    pub lag: Option<u64>,
+    pub ready: bool,
+}
+
+pub struct HealthMonitor {
+    sequencer: surf_disco::Client<ServerError>,
+    l1: Provider<Http>,
+    l2: Option<Provider<Http>>,
+    store: Arc<RwLock<BlockStore>>,
+    verifier: Option<Verifier>,
+    max_lag: u64,
+}
+
+impl HealthMonitor {
+    pub fn new(opt: &Options, store: Arc<RwLock<BlockStore>>) -> Self {
+        Self {
+            sequencer: surf_disco::Client::new(opt.sequencer_url.clone()),
+            l1: Provider::try_from(opt.l1_provider.as_str()).unwrap(),
+            l2: opt
+                .l2_provider
+                .as_ref()
+                .map(|url| Provider::try_from(url.as_str()).unwrap()),
+            store,
+            verifier: opt.verifier(),
+            max_lag: opt.readiness_max_lag,
+        }
+    }
+
+    pub async fn check(&self) -> HealthReport {
+        let (sequencer, l1, l2) = join!(
+            with_timeout(self.sequencer.get::<u64>("status/block-height").send()),
+            with_timeout(block_number(&self.l1)),
+            async {
+                match &self.l2 {
+                    Some(l2) => Some(with_timeout(block_number(l2)).await),
+                    None => None,
+                }
+            }
+        );
+        let sequencer = ServiceStatus::from(sequencer);
+        let l1 = ServiceStatus::from(l1);
+        let l2 = l2.map(ServiceStatus::from);
+
+        let synced_height = self.store.read().await.height();
+        let available = match (&self.verifier, sequencer.block_height) {
+            (Some(verifier), Some(height)) => {
+                let committed =
+                    with_timeout(verifier.committed_height(synced_height.min(height), height))
+                        .await;
+                committed
+                    .map_err(|err| tracing::warn!("unable to get committed height: {err}"))
+                    .ok()
+            }
+            (None, height) => height,
+            (Some(_), None) => None,
+        };
+        let lag = available.map(|height| height.saturating_sub(synced_height));
+        let ready =
+            sequencer.connected && l1.connected && matches!(lag, Some(lag) if lag <= self.max_lag);
+
+        HealthReport {
+            sequencer,
+            l1,
+            l2,
+            synced_height,
+            lag,
+            ready,
+        }
+    }
+}
+
+impl HealthReport {
+    pub fn status(&self) -> StatusCode {
+        if self.ready {
+            StatusCode::Ok
+        } else {
+            StatusCode::ServiceUnavailable
+        }
+    }
+}
+
+/// Middleware answering liveness and readiness probes at `/healthz` and `/readyz`.
+///
+/// This is for the query service, whose own routes all live under API modules. Liveness only
+/// depends on the server running, so `/healthz` doesn't check the dependencies, which would make
+/// every probe wait on the sequencer and the L1. `/readyz` returns the health report, with status
+/// 503 if the adaptor is not ready.
+#[derive(Clone)]
+pub struct Probes {
+    health: Arc<HealthMonitor>,
+}
+
+impl Probes {
+    pub fn new(health: Arc<HealthMonitor>) -> Self {
+        Self { health }
+    }
+}
+
+impl Debug for Probes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Probes").finish_non_exhaustive()
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for Probes {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        if req.method() != tide::http::Method::Get {
+            return Ok(next.run(req).await);
+        }
+        match req.url().path() {
+            "/healthz" => Ok(tide::Response::new(tide::StatusCode::Ok)),
+            "/readyz" => {
+                let report = self.health.check().await;
+                let status = if report.ready {
+                    tide::StatusCode::Ok
+                } else {
+                    tide::StatusCode::ServiceUnavailable
+                };
+                Ok(tide::Response::builder(status)
+                    .body(tide::Body::from_json(&report)?)
+                    .build())
+            }
+            _ => Ok(next.run(req).await),
+        }
+    }
+}
+
+async fn block_number(provider: &Provider<Http>) -> Result<u64, ProviderError> {
+    Ok(provider.get_block_number().await?.as_u64())
+}
+
+async fn with_timeout<E: Display>(
+    fut: impl Future<Output = Result<u64, E>>,
+) -> Result<u64, String> {
+    match timeout(CHECK_TIMEOUT, fut).await {
+        Ok(res) => res.map_err(|err| err.to_string()),
+        Err(_) => Err(format!("no response after {CHECK_TIMEOUT:?}")),
+    }
+}
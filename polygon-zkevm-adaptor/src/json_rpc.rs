@@ -5,24 +5,50 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
 // You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use std::{sync::Arc, time::Duration};
+use std::sync::Arc;
 
-use crate::Options;
-use ethers::{
-    types::{Bytes, H256},
-    utils::keccak256,
+use crate::{
+    auth::{Auth, Authenticator},
+    compression::Compression,
+    confirmations::{Confirmation, Confirmations},
+    events::{Event, EventStream},
+    gas_oracle::GasPriceOracle,
+    health::HealthMonitor,
+    keep_alive::{KeepAliveListener, PooledClient},
+    metrics::RpcMetrics,
+    rate_limit::{RateLimit, RateLimiter},
+    shutdown::{DrainRequests, Shutdown},
+    validation::TransactionValidator,
+    Options,
 };
-use http_types::{headers::HeaderValue, Url};
+use ethers::types::{Bytes, H256, U256};
+use futures::future::{select, Either};
+use http_types::{headers::HeaderValue, mime, Method, StatusCode, Url};
 use jsonrpc_v2::{Data, Error as RpcError, MapRouter, Params, RequestObject, Server};
-use sequencer::{Transaction, VmId};
-use surf_disco::error::ClientError;
+use sequencer::{Transaction, Vm};
+use serde_json::{json, Value};
 use tide::security::{CorsMiddleware, Origin};
+use zkevm::ZkEvm;
 
 pub type RpcApiService = Arc<Server<MapRouter>>;
 pub type RpcServer = tide::Server<RpcApiService>;
 pub type RpcServerRequest = tide::Request<RpcApiService>;
 
-pub type RpcData = (Url, VmId);
+/// JSON-RPC methods which submit transactions to the sequencer.
+pub const SUBMISSION_METHODS: [&str; 1] = ["eth_sendRawTransaction"];
+
+#[derive(Clone, Debug)]
+pub struct RpcData {
+    /// Client for submitting transactions, shared by all requests so that connections to the
+    /// sequencer are kept alive and reused.
+    pub sequencer: PooledClient,
+    /// Sequencer endpoint to which transactions are submitted.
+    pub submit_url: Url,
+    pub zkevm: ZkEvm,
+    pub validator: TransactionValidator,
+    pub confirmations: Arc<Confirmations>,
+    pub events: Arc<EventStream>,
+}
 
 /// Handle incoming HTTP JSON RPC requests.
 pub async fn handle_http_request(mut request: RpcServerRequest) -> tide::Result {
@@ -54,6 +80,161 @@ pub async fn handle_http_request(mut request: RpcServerRequest) -> tide::Result
     Ok(response)
 }
 
+/// The methods called by an HTTP JSON-RPC request, which may be a batch.
+///
+/// The body of the request is buffered and restored, so that it can still be read by the next
+/// handler.
+pub async fn request_methods<State>(req: &mut tide::Request<State>) -> tide::Result<Vec<String>> {
+    let body = req.body_bytes().await?;
+    req.set_body(body.clone());
+
+    let method = |req: &Value| Some(req.get("method")?.as_str()?.to_string());
+    Ok(match serde_json::from_slice(&body) {
+        Ok(Value::Array(reqs)) => reqs.iter().filter_map(method).collect(),
+        Ok(req) => method(&req).into_iter().collect(),
+        // Leave it to the JSON-RPC handler to report malformed requests.
+        Err(_) => vec![],
+    })
+}
+
+/// Whether an HTTP JSON-RPC request submits any transactions.
+pub async fn is_submission<State>(req: &mut tide::Request<State>) -> tide::Result<bool> {
+    Ok(request_methods(req)
+        .await?
+        .iter()
+        .any(|method| SUBMISSION_METHODS.contains(&method.as_str())))
+}
+
+/// An HTTP response containing a JSON-RPC error.
+pub fn rpc_error_response(status: StatusCode, code: i64, message: &str) -> tide::Response {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "error": {
+            "code": code,
+            "message": message,
+        },
+        "id": null,
+    });
+    tide::Response::builder(status)
+        .body(body)
+        .content_type("application/json-rpc;charset=utf-8")
+        .build()
+}
+
+/// A zkEVM node, to which JSON-RPC requests the adaptor does not answer itself are forwarded.
+#[derive(Clone, Debug)]
+pub struct L2Node {
+    pub url: Url,
+    pub client: PooledClient,
+}
+
+impl L2Node {
+    /// Call a JSON-RPC method on the node, returning its result or passing on its error.
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let req = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+        let res: Value = async {
+            self.client
+                .client()
+                .post(&self.url)
+                .body_json(&req)?
+                .recv_json()
+                .await
+        }
+        .await
+        .map_err(|err| {
+            tracing::warn!("unable to call {method} on L2 node: {err}");
+            RpcError::INTERNAL_ERROR
+        })?;
+        if let Some(err) = res.get("error") {
+            return Err(RpcError::Full {
+                code: err["code"].as_i64().unwrap_or(-32603),
+                message: err["message"].as_str().unwrap_or_default().to_string(),
+                data: None,
+            });
+        }
+        Ok(res["result"].clone())
+    }
+}
+
+/// Middleware forwarding JSON-RPC requests for methods the adaptor does not implement to a zkEVM
+/// node.
+///
+/// This lets wallets use the adaptor as their only endpoint, getting the adaptor's answers for the
+/// methods it implements and the node's answers for everything else. A batch is forwarded only if
+/// none of its methods are implemented by the adaptor.
+#[derive(Clone, Debug)]
+pub struct NodeProxy {
+    node: L2Node,
+    methods: Arc<Vec<&'static str>>,
+}
+
+impl NodeProxy {
+    /// Forward requests for anything but `methods` to `node`.
+    pub fn new(node: L2Node, methods: Vec<&'static str>) -> Self {
+        Self {
+            node,
+            methods: Arc::new(methods),
+        }
+    }
+
+    /// Whether a request calling `methods` should be forwarded to the node.
+    pub fn forwards(&self, methods: &[String]) -> bool {
+        !methods.is_empty()
+            && !methods
+                .iter()
+                .any(|method| self.methods.contains(&method.as_str()))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for NodeProxy {
+    async fn handle(
+        &self,
+        mut req: tide::Request<State>,
+        next: tide::Next<'_, State>,
+    ) -> tide::Result {
+        if req.method() != Method::Post || req.url().path() != "/" {
+            return Ok(next.run(req).await);
+        }
+        if !self.forwards(&request_methods(&mut req).await?) {
+            return Ok(next.run(req).await);
+        }
+
+        let body = req.body_bytes().await?;
+        let mut res = match self
+            .node
+            .client
+            .client()
+            .post(&self.node.url)
+            .body_bytes(body)
+            .content_type(mime::JSON)
+            .await
+        {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::warn!("unable to forward request to L2 node: {err}");
+                return Ok(rpc_error_response(
+                    StatusCode::BadGateway,
+                    -32603,
+                    "unable to reach L2 node",
+                ));
+            }
+        };
+        let body = res.body_bytes().await?;
+        let mut forwarded = tide::Response::new(res.status());
+        if let Some(content_type) = res.content_type() {
+            forwarded.set_content_type(content_type);
+        }
+        forwarded.set_body(body);
+        Ok(forwarded)
+    }
+}
+
 /// Build HTTP and WebSocket server both exposing a JSON RPC API.
 pub fn build_rpc_server(api: RpcApiService) -> RpcServer {
     // Configure CORS middleware
@@ -75,43 +256,285 @@ pub async fn eth_send_raw_transaction(
 ) -> Result<H256, RpcError> {
     tracing::debug!("Received transaction: {raw_tx:?}");
 
-    let url = (*data).0.clone();
-    let vmid = data.1;
+    // Reject transactions that the zkEVM node would drop before they take up space in a block.
+    let chain_id = data.zkevm.chain_id;
+    let validated = data.validator.validate(&raw_tx).map_err(|err| {
+        tracing::info!("rejecting invalid transaction: {err}");
+        data.events.emit(Event::TransactionRejected {
+            chain_id,
+            error: err.to_string(),
+        });
+        err
+    })?;
+    let hash = validated.hash;
+    data.events
+        .emit(Event::TransactionReceived { chain_id, hash });
 
-    let client = surf_disco::Client::<ClientError>::new(url.join("submit").unwrap());
+    // The client is shared by all requests, so we don't wait for it to connect here. If the
+    // sequencer is unreachable, the submission below fails and is reported like any other error.
+    let txn = Transaction::new(data.zkevm.id(), raw_tx.to_vec());
 
-    if !client.connect(Some(Duration::from_secs(5))).await {
-        tracing::error!("unable to connect to sequencer API at {url}");
+    // Start tracking the transaction before submitting it, so we can't miss it if it is sequenced
+    // right away.
+    data.confirmations.submitted(hash);
+
+    if let Err(err) = submit(&data, &txn).await {
+        tracing::error!("sequencer rejected transaction {hash:?}: {err}");
+        data.events.emit(Event::SubmissionFailed {
+            chain_id,
+            hash,
+            error: err.to_string(),
+        });
         return Err(RpcError::INTERNAL_ERROR);
     }
 
-    let txn = Transaction::new(vmid, raw_tx.to_vec());
+    tracing::debug!("Submitted transaction: {txn:?}");
+    data.events
+        .emit(Event::TransactionForwarded { chain_id, hash });
 
-    client
-        .post::<()>("submit")
-        .body_json(&txn)
-        .unwrap()
-        .send()
-        .await
-        .unwrap();
+    Ok(hash)
+}
 
-    tracing::debug!("Submitted transaction: {txn:?}");
+/// Submit a transaction to the sequencer.
+async fn submit(data: &RpcData, txn: &Transaction) -> surf::Result<()> {
+    let mut res = data
+        .sequencer
+        .client()
+        .post(&data.submit_url)
+        .body_json(txn)?
+        .await?;
+    if !res.status().is_success() {
+        let msg = res.body_string().await.unwrap_or_default();
+        return Err(surf::Error::from_str(res.status(), msg));
+    }
+    Ok(())
+}
+
+/// Report whether a transaction submitted through this adaptor has been included in an Espresso
+/// block, or `null` if the adaptor does not know about the transaction.
+pub async fn espresso_get_transaction_confirmation(
+    data: Data<RpcData>,
+    Params((hash,)): Params<(H256,)>,
+) -> Result<Option<Confirmation>, RpcError> {
+    Ok(data.confirmations.get(hash))
+}
+
+pub async fn eth_gas_price(oracle: Data<Arc<dyn GasPriceOracle>>) -> Result<U256, RpcError> {
+    Ok(oracle.gas_price())
+}
+
+pub async fn eth_max_priority_fee_per_gas(
+    oracle: Data<Arc<dyn GasPriceOracle>>,
+) -> Result<U256, RpcError> {
+    Ok(oracle.max_priority_fee_per_gas())
+}
+
+/// Get the fee history from the L2 node, including the cost of sequencing in the fees.
+pub async fn eth_fee_history(
+    oracle: Data<Arc<dyn GasPriceOracle>>,
+    node: Data<L2Node>,
+    Params(params): Params<Value>,
+) -> Result<Value, RpcError> {
+    let mut history = node.call("eth_feeHistory", params).await?;
+    add_sequencing_fee(&mut history, oracle.sequencing_fee())?;
+    Ok(history)
+}
 
-    Ok(keccak256(raw_tx).into())
+/// Add `fee` to each base fee and reward in the result of `eth_feeHistory`.
+pub fn add_sequencing_fee(history: &mut Value, fee: U256) -> Result<(), RpcError> {
+    let add = |value: &mut Value| -> Result<(), RpcError> {
+        let quantity: U256 = serde_json::from_value(value.take()).map_err(|err| {
+            tracing::warn!("invalid quantity in fee history from L2 node: {err}");
+            RpcError::INTERNAL_ERROR
+        })?;
+        *value = json!(quantity.saturating_add(fee));
+        Ok(())
+    };
+
+    if let Some(fees) = history
+        .get_mut("baseFeePerGas")
+        .and_then(Value::as_array_mut)
+    {
+        fees.iter_mut().try_for_each(&add)?;
+    }
+    if let Some(rewards) = history.get_mut("reward").and_then(Value::as_array_mut) {
+        for block in rewards.iter_mut().filter_map(Value::as_array_mut) {
+            block.iter_mut().try_for_each(&add)?;
+        }
+    }
+    Ok(())
 }
 
-pub async fn serve(opt: &Options) {
-    let rpc_data: RpcData = (opt.sequencer_url.clone(), opt.l2_chain_id.into());
+pub async fn serve(
+    opt: &Options,
+    health: Arc<HealthMonitor>,
+    confirmations: Arc<Confirmations>,
+    events: Arc<EventStream>,
+    gas_oracle: Option<Arc<dyn GasPriceOracle>>,
+    shutdown: Shutdown,
+) {
+    let rpc_data = RpcData {
+        sequencer: PooledClient::new(&opt.keep_alive),
+        submit_url: opt.sequencer_url.join("submit/submit").unwrap(),
+        zkevm: opt.zkevm(),
+        validator: TransactionValidator::new(opt.l2_chain_id, opt.max_transaction_size),
+        confirmations,
+        events,
+    };
 
-    let rpc = Server::new()
+    let mut methods = vec![
+        "eth_sendRawTransaction",
+        "espresso_getTransactionConfirmation",
+    ];
+    let mut rpc = Server::new()
         .with_data(Data::new(rpc_data))
         .with_method("eth_sendRawTransaction", eth_send_raw_transaction)
-        .finish();
+        .with_method(
+            "espresso_getTransactionConfirmation",
+            espresso_get_transaction_confirmation,
+        );
+    let node = opt.l2_provider.clone().map(|url| L2Node {
+        url,
+        client: PooledClient::new(&opt.keep_alive),
+    });
+    // Answer gas price requests ourselves, so that the suggested prices include the cost of
+    // sequencing.
+    if let Some(oracle) = gas_oracle {
+        methods.extend(["eth_gasPrice", "eth_maxPriorityFeePerGas"]);
+        rpc = rpc
+            .with_data(Data::new(oracle))
+            .with_method("eth_gasPrice", eth_gas_price)
+            .with_method("eth_maxPriorityFeePerGas", eth_max_priority_fee_per_gas);
+        // Only the node knows the fee history, so without one, there is nothing to adjust.
+        if let Some(node) = &node {
+            methods.push("eth_feeHistory");
+            rpc = rpc
+                .with_data(Data::new(node.clone()))
+                .with_method("eth_feeHistory", eth_fee_history);
+        }
+    }
+    let rpc = rpc.finish();
 
-    let server = build_rpc_server(rpc);
-    tracing::info!("serving RPC on port {}", opt.rpc_port);
+    let metrics = Arc::new(RpcMetrics::default());
+    let mut server = build_rpc_server(rpc);
+    server.with(DrainRequests::new(shutdown.clone()));
+    if opt.compression.is_enabled() {
+        server.with(Compression::new(&opt.compression));
+    }
+    // Authenticate before rate limiting, so that unauthorized requests don't use up the quota.
+    if opt.auth.is_enabled() {
+        server.with(Auth::new(Authenticator::new(&opt.auth)));
+    }
+    if opt.rate_limit.is_enabled() {
+        server.with(RateLimit::new(
+            RateLimiter::new(&opt.rate_limit),
+            metrics.clone(),
+        ));
+    }
+    // Forward everything else to the node, once the request has been authenticated and counted
+    // against the rate limit.
+    if let Some(node) = node {
+        server.with(NodeProxy::new(node, methods));
+    }
+    server.at("/metrics").get(move |_: RpcServerRequest| {
+        let metrics = metrics.clone();
+        async move { Ok(metrics.export()) }
+    });
+
+    // Liveness only depends on the server running, so it doesn't check the dependencies, which
+    // would make every probe wait on the sequencer and the L1. Readiness depends on the health
+    // report.
     server
-        .listen(&format!("0.0.0.0:{}", opt.rpc_port))
-        .await
-        .unwrap();
+        .at("/healthz")
+        .get(|_: RpcServerRequest| async { Ok(tide::Response::new(StatusCode::Ok)) });
+    server.at("/readyz").get(move |_: RpcServerRequest| {
+        let health = health.clone();
+        async move {
+            let report = health.check().await;
+            let status = if report.ready {
+                StatusCode::Ok
+            } else {
+                StatusCode::ServiceUnavailable
+            };
+            Ok(tide::Response::builder(status)
+                .body(tide::Body::from_json(&report)?)
+                .build())
+        }
+    });
+
+    tracing::info!("serving RPC on port {}", opt.rpc_port);
+    let listener = KeepAliveListener::new(format!("0.0.0.0:{}", opt.rpc_port), &opt.keep_alive);
+    // Dropping the listener stops accepting new connections, but requests on existing connections
+    // continue to be handled in the background, so we can wait for them to finish.
+    match select(Box::pin(server.listen(listener)), Box::pin(shutdown.wait())).await {
+        Either::Left((res, _)) => res.unwrap(),
+        Either::Right(_) => {
+            tracing::info!(
+                "RPC server on port {} draining {} requests",
+                opt.rpc_port,
+                shutdown.in_flight()
+            );
+            if !shutdown.drain(opt.shutdown_timeout).await {
+                tracing::warn!(
+                    "{} requests still in flight after {:?}",
+                    shutdown.in_flight(),
+                    opt.shutdown_timeout
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_node_proxy_forwards() {
+        let proxy = NodeProxy::new(
+            L2Node {
+                url: "http://node:8123".parse().unwrap(),
+                client: PooledClient::new(&Default::default()),
+            },
+            vec!["eth_sendRawTransaction", "eth_gasPrice"],
+        );
+        let methods = |methods: &[&str]| methods.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+
+        assert!(proxy.forwards(&methods(&["eth_getBalance"])));
+        assert!(proxy.forwards(&methods(&["eth_blockNumber", "eth_feeHistory"])));
+        assert!(!proxy.forwards(&methods(&["eth_gasPrice"])));
+        // Batches which call any of our methods are handled locally.
+        assert!(!proxy.forwards(&methods(&["eth_getBalance", "eth_sendRawTransaction"])));
+        // Malformed requests are left to the local handler to report.
+        assert!(!proxy.forwards(&[]));
+    }
+
+    #[test]
+    fn test_fee_history_sequencing_fee() {
+        let mut history = json!({
+            "oldestBlock": "0x10",
+            "baseFeePerGas": ["0x3e8", "0x7d0", "0x0"],
+            "gasUsedRatio": [0.5, 0.0],
+            "reward": [["0x1", "0x2"], ["0x0", "0x0"]],
+        });
+        assert!(add_sequencing_fee(&mut history, U256::from(10)).is_ok());
+        assert_eq!(
+            history,
+            json!({
+                "oldestBlock": "0x10",
+                "baseFeePerGas": ["0x3f2", "0x7da", "0xa"],
+                "gasUsedRatio": [0.5, 0.0],
+                "reward": [["0xb", "0xc"], ["0xa", "0xa"]],
+            })
+        );
+
+        // Rewards are only present if percentiles were requested.
+        let mut history = json!({ "baseFeePerGas": ["0x1"] });
+        assert!(add_sequencing_fee(&mut history, U256::from(1)).is_ok());
+        assert_eq!(history, json!({ "baseFeePerGas": ["0x2"] }));
+
+        // The node's answer is not passed on if we can't adjust it.
+        let mut history = json!({ "baseFeePerGas": ["not a number"] });
+        assert!(add_sequencing_fee(&mut history, U256::from(1)).is_err());
+    }
 }
@@ -0,0 +1,487 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Persistent HTTP connections to and from the adaptor.
+//!
+//! By default, connections to the adaptor's servers are kept open between requests, so that a
+//! client making many requests, like a zkEVM node syncing blocks, doesn't pay for a new connection
+//! each time. Likewise, the adaptor reuses its connections to the sequencer when submitting
+//! transactions, and to the zkEVM node when forwarding JSON-RPC requests. Connections which have
+//! been idle for longer than a configurable timeout are closed, so that they don't pile up.
+//!
+//! The servers are bound to a [`KeepAliveListener`], which closes idle connections itself, since
+//! the HTTP implementation used by tide and tide-disco always waits 60 seconds for the next request
+//! on a connection. The clients are [`PooledClient`]s, which replace their connection pool once it
+//! has been idle for longer than the timeout, since the pool has no idle timeout of its own.
+
+use async_std::{
+    net::{TcpListener, TcpStream},
+    task::{sleep, spawn},
+};
+use clap::Args;
+use futures::{
+    future::{select, Either},
+    io::{AsyncRead, AsyncWrite},
+    StreamExt,
+};
+use http_types::{headers::CONNECTION, Request, Response};
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    io,
+    num::ParseIntError,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tide::listener::{ListenInfo, Listener, ToListener};
+
+/// How long to wait before accepting connections again after failing to accept one.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Args, Clone, Debug)]
+pub struct KeepAliveOptions {
+    /// Close connections to the adaptor's HTTP servers after each response, instead of keeping
+    /// them open for further requests.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_DISABLE_SERVER_KEEP_ALIVE")]
+    pub disable_server_keep_alive: bool,
+
+    /// Time in milliseconds after which the adaptor's HTTP servers close a connection on which no
+    /// request is in progress.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_SERVER_IDLE_TIMEOUT",
+        default_value = "60000",
+        value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(Duration::from_millis(arg.parse()?)) }
+    )]
+    pub server_idle_timeout: Duration,
+
+    /// Open a new connection for each request to the sequencer and the zkEVM node, instead of
+    /// reusing connections.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_DISABLE_CLIENT_KEEP_ALIVE")]
+    pub disable_client_keep_alive: bool,
+
+    /// Time in milliseconds after which idle connections to the sequencer and the zkEVM node are
+    /// closed instead of reused.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_CLIENT_IDLE_TIMEOUT",
+        default_value = "60000",
+        value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(Duration::from_millis(arg.parse()?)) }
+    )]
+    pub client_idle_timeout: Duration,
+
+    /// Maximum number of concurrent connections to each of the sequencer and the zkEVM node.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_CLIENT_MAX_CONNECTIONS",
+        default_value = "50"
+    )]
+    pub client_max_connections: usize,
+}
+
+impl Default for KeepAliveOptions {
+    fn default() -> Self {
+        Self {
+            disable_server_keep_alive: false,
+            server_idle_timeout: Duration::from_secs(60),
+            disable_client_keep_alive: false,
+            client_idle_timeout: Duration::from_secs(60),
+            client_max_connections: 50,
+        }
+    }
+}
+
+/// A TCP listener for tide and tide-disco servers, which closes idle connections.
+pub struct KeepAliveListener<State> {
+    addr: String,
+    keep_alive: bool,
+    idle_timeout: Duration,
+    listener: Option<TcpListener>,
+    server: Option<tide::Server<State>>,
+    info: Option<ListenInfo>,
+}
+
+impl<State> KeepAliveListener<State> {
+    pub fn new(addr: impl Into<String>, opt: &KeepAliveOptions) -> Self {
+        Self {
+            addr: addr.into(),
+            keep_alive: !opt.disable_server_keep_alive,
+            idle_timeout: opt.server_idle_timeout,
+            listener: None,
+            server: None,
+            info: None,
+        }
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> ToListener<State> for KeepAliveListener<State> {
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self> {
+        Ok(self)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Listener<State> for KeepAliveListener<State> {
+    async fn bind(&mut self, server: tide::Server<State>) -> io::Result<()> {
+        assert!(self.server.is_none(), "`bind` can only be called once");
+        let listener = TcpListener::bind(&self.addr).await?;
+        let addr = listener.local_addr()?;
+        self.info = Some(ListenInfo::new(
+            format!("http://{addr}"),
+            "tcp".into(),
+            false,
+        ));
+        self.listener = Some(listener);
+        self.server = Some(server);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let server = self
+            .server
+            .take()
+            .expect("`bind` must be called before `accept`");
+        let listener = self
+            .listener
+            .take()
+            .expect("`bind` must be called before `accept`");
+
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    spawn(serve_connection(
+                        server.clone(),
+                        stream,
+                        self.keep_alive,
+                        self.idle_timeout,
+                    ));
+                }
+                Err(err) => {
+                    // Errors like running out of file descriptors are usually transient, so keep
+                    // serving, but give existing connections a chance to finish first.
+                    tracing::warn!("error accepting connection on {}: {err}", self.addr);
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        self.info.iter().cloned().collect()
+    }
+}
+
+impl<State> Debug for KeepAliveListener<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeepAliveListener")
+            .field("addr", &self.addr)
+            .field("keep_alive", &self.keep_alive)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<State> Display for KeepAliveListener<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.info {
+            Some(info) => write!(f, "{info}"),
+            None => write!(f, "http://{}", self.addr),
+        }
+    }
+}
+
+/// Handle HTTP requests on `stream` until the client closes it or it is idle for `idle_timeout`.
+async fn serve_connection<State: Clone + Send + Sync + 'static>(
+    server: tide::Server<State>,
+    stream: TcpStream,
+    keep_alive: bool,
+    idle_timeout: Duration,
+) {
+    let local_addr = stream.local_addr().ok();
+    let peer_addr = stream.peer_addr().ok();
+    let activity = Arc::new(Activity::new());
+    let io = TrackedStream {
+        stream,
+        activity: activity.clone(),
+    };
+
+    let requests = activity.clone();
+    let conn = async_h1::accept(io, move |mut req: Request| {
+        let server = server.clone();
+        let activity = requests.clone();
+        async move {
+            // The peer address is used for per-IP rate limiting.
+            req.set_local_addr(local_addr);
+            req.set_peer_addr(peer_addr);
+            activity.start_request();
+            let res: http_types::Result<Response> = server.respond(req).await;
+            activity.end_request();
+            let mut res = res?;
+            if !keep_alive {
+                res.insert_header(CONNECTION, "close");
+            }
+            Ok(res)
+        }
+    });
+
+    // Dropping the connection future closes the connection, unless it has been upgraded (for
+    // example, to a WebSocket), in which case the future has already finished.
+    match select(Box::pin(conn), Box::pin(activity.idle(idle_timeout))).await {
+        Either::Left((Err(err), _)) => {
+            tracing::debug!("error on HTTP connection from {peer_addr:?}: {err}");
+        }
+        Either::Left((Ok(()), _)) => {}
+        Either::Right(_) => {
+            tracing::debug!("closing idle HTTP connection from {peer_addr:?}");
+        }
+    }
+}
+
+/// Activity on a connection, used to decide when it is idle.
+#[derive(Debug)]
+struct Activity {
+    /// The last time data was sent or received, or a request finished.
+    last: Mutex<Instant>,
+    /// Number of requests being handled.
+    requests: AtomicUsize,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    fn start_request(&self) {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn end_request(&self) {
+        self.requests.fetch_sub(1, Ordering::SeqCst);
+        self.touch();
+    }
+
+    /// Whether the connection has been idle for at least `timeout`, and if not, how much longer it
+    /// would have to stay idle.
+    fn check_idle(&self, timeout: Duration, now: Instant) -> Result<(), Duration> {
+        if self.requests.load(Ordering::SeqCst) > 0 {
+            // Requests can take arbitrarily long, and the connection is not idle while they do.
+            return Err(timeout);
+        }
+        let idle = now.saturating_duration_since(*self.last.lock().unwrap());
+        if idle >= timeout {
+            Ok(())
+        } else {
+            Err(timeout - idle)
+        }
+    }
+
+    /// Wait until the connection has been idle for `timeout`.
+    async fn idle(&self, timeout: Duration) {
+        while let Err(remaining) = self.check_idle(timeout, Instant::now()) {
+            sleep(remaining).await;
+        }
+    }
+}
+
+/// A TCP stream which records its [`Activity`].
+#[derive(Clone, Debug)]
+struct TrackedStream {
+    stream: TcpStream,
+    activity: Arc<Activity>,
+}
+
+impl AsyncRead for TrackedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if matches!(res, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.touch();
+        }
+        res
+    }
+}
+
+impl AsyncWrite for TrackedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if matches!(res, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.touch();
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+/// An HTTP client which reuses connections according to [`KeepAliveOptions`].
+///
+/// The connection pool of the underlying client has no idle timeout, so once the client has not
+/// been used for longer than the timeout, it is replaced with a new one, closing its connections.
+#[derive(Clone)]
+pub struct PooledClient {
+    keep_alive: bool,
+    max_connections: usize,
+    idle_timeout: Duration,
+    inner: Arc<Mutex<(surf::Client, Instant)>>,
+}
+
+impl PooledClient {
+    pub fn new(opt: &KeepAliveOptions) -> Self {
+        let keep_alive = !opt.disable_client_keep_alive;
+        let max_connections = opt.client_max_connections;
+        Self {
+            keep_alive,
+            max_connections,
+            idle_timeout: opt.client_idle_timeout,
+            inner: Arc::new(Mutex::new((
+                build_client(keep_alive, max_connections),
+                Instant::now(),
+            ))),
+        }
+    }
+
+    /// The client to use for the next request.
+    pub fn client(&self) -> surf::Client {
+        self.client_at(Instant::now())
+    }
+
+    fn client_at(&self, now: Instant) -> surf::Client {
+        let mut inner = self.inner.lock().unwrap();
+        let (client, last_used) = &mut *inner;
+        if now.saturating_duration_since(*last_used) > self.idle_timeout {
+            *client = build_client(self.keep_alive, self.max_connections);
+        }
+        *last_used = now;
+        client.clone()
+    }
+}
+
+impl Debug for PooledClient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledClient")
+            .field("keep_alive", &self.keep_alive)
+            .field("max_connections", &self.max_connections)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+fn build_client(keep_alive: bool, max_connections: usize) -> surf::Client {
+    surf::Client::try_from(
+        surf::Config::new()
+            .set_http_keep_alive(keep_alive)
+            .set_max_connections_per_host(max_connections),
+    )
+    .expect("failed to create HTTP client")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_idle() {
+        let activity = Activity::new();
+        let timeout = Duration::from_secs(10);
+        let start = *activity.last.lock().unwrap();
+
+        assert_eq!(
+            activity.check_idle(timeout, start + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+        assert_eq!(activity.check_idle(timeout, start + timeout), Ok(()));
+
+        // A connection with a request in progress is never idle.
+        activity.start_request();
+        assert_eq!(
+            activity.check_idle(timeout, start + Duration::from_secs(60)),
+            Err(timeout)
+        );
+        // The idle time starts over when the request finishes.
+        activity.end_request();
+        let end = *activity.last.lock().unwrap();
+        assert!(activity.check_idle(timeout, end).is_err());
+        assert_eq!(activity.check_idle(timeout, end + timeout), Ok(()));
+    }
+
+    #[async_std::test]
+    async fn test_idle_connections_closed() {
+        let port = portpicker::pick_unused_port().unwrap();
+        let mut app = tide::new();
+        app.at("/").get(|_| async { Ok("hello") });
+        let opt = KeepAliveOptions {
+            server_idle_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        spawn(app.listen(KeepAliveListener::new(format!("127.0.0.1:{port}"), &opt)));
+
+        // Wait for the server to start.
+        let stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(_) => sleep(Duration::from_millis(50)).await,
+            }
+        };
+
+        // The connection stays open for several requests...
+        let mut stream = stream;
+        for _ in 0..2 {
+            futures::AsyncWriteExt::write_all(
+                &mut stream,
+                b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            )
+            .await
+            .unwrap();
+            let mut buf = [0; 1024];
+            let n = futures::AsyncReadExt::read(&mut stream, &mut buf)
+                .await
+                .unwrap();
+            assert!(std::str::from_utf8(&buf[..n])
+                .unwrap()
+                .starts_with("HTTP/1.1 200"));
+        }
+
+        // ...but is closed once it is idle.
+        let mut buf = [0; 1024];
+        let n = async_std::future::timeout(
+            Duration::from_secs(5),
+            futures::AsyncReadExt::read(&mut stream, &mut buf),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(n, 0);
+    }
+}
@@ -5,14 +5,48 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
 // You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use auth::AuthOptions;
 use clap::Parser;
+use compression::CompressionOptions;
+use ethers::types::Address;
+use gas_oracle::GasOracleOptions;
+use keep_alive::KeepAliveOptions;
+use rate_limit::RateLimitOptions;
+use snafu::Snafu;
+use std::{
+    collections::HashSet, iter, num::ParseIntError, path::PathBuf, str::FromStr, time::Duration,
+};
+use storage::{migrate_legacy_store, BlockStore};
 use surf_disco::Url;
+use sync::SyncOptions;
+use verify::Verifier;
 use zkevm::ZkEvm;
 
+pub mod auth;
+pub mod cache;
+pub mod compression;
+pub mod config;
+pub mod confirmations;
+pub mod events;
+#[cfg(feature = "faucet")]
+pub mod faucet;
+pub mod gas_oracle;
+pub mod health;
 pub mod json_rpc;
+pub mod keep_alive;
+pub mod listener;
+pub mod metrics;
 pub mod query_service;
+pub mod rate_limit;
+pub mod reorg;
+pub mod shutdown;
+pub mod snapshot;
+pub mod storage;
+pub mod sync;
+pub mod validation;
+pub mod verify;
 
-#[derive(Parser)]
+#[derive(Clone, Debug, Parser)]
 pub struct Options {
     /// URL of a HotShot sequencer node.
     #[clap(long, env = "ESPRESSO_SEQUENCER_URL")]
@@ -22,6 +56,13 @@ pub struct Options {
     #[clap(long, env = "ESPRESSO_ZKEVM_L1_PROVIDER")]
     pub l1_provider: Url,
 
+    /// URL of the layer 2 zkEVM node JSON-RPC provider.
+    ///
+    /// Used to report connectivity to the node in health checks, and to answer JSON-RPC requests for
+    /// methods the adaptor does not implement itself.
+    #[clap(long, env = "ESPRESSO_ZKEVM_L2_PROVIDER")]
+    pub l2_provider: Option<Url>,
+
     /// Chain ID for layer 2 EVM.
     ///
     /// This will be used as the VM ID for layer 2 EVM transactions within the HotShot sequencer.
@@ -45,6 +86,203 @@ pub struct Options {
         default_value = "50100"
     )]
     pub query_port: u16,
+
+    /// Maximum size in bytes of a raw transaction accepted by the JSON-RPC API.
+    ///
+    /// Larger transactions are rejected without being forwarded to the sequencer.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_MAX_TRANSACTION_SIZE",
+        default_value = "100132"
+    )]
+    pub max_transaction_size: usize,
+
+    /// Directory in which to persist translated blocks.
+    ///
+    /// Blocks for each rollup are stored in a subdirectory named after the rollup's chain ID. A store
+    /// kept directly in this directory by an older adaptor is moved to the subdirectory for
+    /// `--l2-chain-id`. If not provided, blocks are kept in memory, and a restarted adaptor has to
+    /// refetch all blocks from the sequencer.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_STORAGE_PATH")]
+    pub storage_path: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub rate_limit: RateLimitOptions,
+
+    #[clap(flatten)]
+    pub auth: AuthOptions,
+
+    #[clap(flatten)]
+    pub sync: SyncOptions,
+
+    #[clap(flatten)]
+    pub compression: CompressionOptions,
+
+    #[clap(flatten)]
+    pub keep_alive: KeepAliveOptions,
+
+    #[clap(flatten)]
+    pub gas_oracle: GasOracleOptions,
+
+    /// Address of the HotShot contract on layer 1.
+    #[clap(long, env = "ESPRESSO_SEQUENCER_HOTSHOT_ADDRESS")]
+    pub hotshot_address: Option<Address>,
+
+    /// Only serve blocks which match a commitment in the HotShot contract on layer 1.
+    ///
+    /// Requires `--hotshot-address`. In this mode, blocks are not served until they have been
+    /// committed to the L1, so the adaptor will lag behind the sequencer.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_VERIFY",
+        requires = "hotshot_address"
+    )]
+    pub verify: bool,
+
+    /// Maximum number of blocks the adaptor may lag behind the sequencer while reporting ready.
+    ///
+    /// With `--verify`, this is the lag behind the blocks committed in the HotShot contract
+    /// instead, since blocks cannot be synced before they are committed.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_READINESS_MAX_LAG",
+        default_value = "5"
+    )]
+    pub readiness_max_lag: u64,
+
+    /// Number of recently requested blocks to cache in memory, or 0 to disable caching.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_CACHE_SIZE",
+        default_value = "1000"
+    )]
+    pub cache_size: usize,
+
+    /// Number of recently submitted transactions to track for soft confirmations, or 0 to disable.
+    ///
+    /// The `espresso_getTransactionConfirmation` JSON-RPC method reports whether a tracked
+    /// transaction has been included in an Espresso block.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_CONFIRMATION_CACHE_SIZE",
+        default_value = "10000"
+    )]
+    pub confirmation_cache_size: usize,
+
+    /// Path of a Unix socket on which to stream structured events as JSON lines.
+    ///
+    /// Every client which connects to the socket receives events for transaction submissions,
+    /// sequencer responses, blocks forwarded to the L2 node and errors, for all rollups.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_EVENTS_SOCKET")]
+    pub events_socket: Option<PathBuf>,
+
+    /// Maximum number of blocks returned by a single range request to the query service.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_MAX_PAGE_SIZE",
+        default_value = "100"
+    )]
+    pub max_page_size: usize,
+
+    /// Maximum time in milliseconds to wait for in-flight requests to finish when shutting down.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_SHUTDOWN_TIMEOUT",
+        default_value = "10000",
+        value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(Duration::from_millis(arg.parse()?)) }
+    )]
+    pub shutdown_timeout: Duration,
+
+    /// Additional rollups to serve from this adaptor.
+    ///
+    /// Each rollup is given as a comma-separated list of `key=value` pairs, with the keys
+    /// `chain-id`, `rpc-port`, `query-port` and, optionally, `l2-provider`, for example
+    /// `chain-id=1002,rpc-port=8546,query-port=50101`. Each rollup gets its own JSON-RPC and query
+    /// API servers, which behave exactly like the servers for the rollup configured by
+    /// `--l2-chain-id`, `--rpc-port` and `--query-port`. Blocks for all rollups are synced from a
+    /// single subscription to the sequencer.
+    #[clap(
+        long = "rollup",
+        env = "ESPRESSO_ZKEVM_ADAPTOR_ROLLUPS",
+        value_delimiter = ';'
+    )]
+    pub rollups: Vec<RollupOptions>,
+
+    /// Instead of running the adaptor, snapshot or restore its block stores.
+    #[clap(subcommand)]
+    pub command: Option<snapshot::Command>,
+}
+
+/// Configuration for an additional rollup served by the adaptor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RollupOptions {
+    pub chain_id: u64,
+    pub rpc_port: u16,
+    pub query_port: u16,
+    pub l2_provider: Option<Url>,
+}
+
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum ParseRollupError {
+    #[snafu(display("expected key=value, got {pair:?}"))]
+    MalformedPair { pair: String },
+
+    #[snafu(display("unknown key {key:?}"))]
+    UnknownKey { key: String },
+
+    #[snafu(display("invalid value for {key}: {msg}"))]
+    InvalidValue { key: String, msg: String },
+
+    #[snafu(display("missing {key}"))]
+    MissingKey { key: &'static str },
+}
+
+impl FromStr for RollupOptions {
+    type Err = ParseRollupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, ParseRollupError>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value
+                .parse()
+                .map_err(|err: T::Err| ParseRollupError::InvalidValue {
+                    key: key.to_string(),
+                    msg: err.to_string(),
+                })
+        }
+
+        let mut chain_id = None;
+        let mut rpc_port = None;
+        let mut query_port = None;
+        let mut l2_provider = None;
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) =
+                pair.split_once('=')
+                    .ok_or_else(|| ParseRollupError::MalformedPair {
+                        pair: pair.to_string(),
+                    })?;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "chain-id" => chain_id = Some(parse(key, value)?),
+                "rpc-port" => rpc_port = Some(parse(key, value)?),
+                "query-port" => query_port = Some(parse(key, value)?),
+                "l2-provider" => l2_provider = Some(parse(key, value)?),
+                _ => {
+                    return Err(ParseRollupError::UnknownKey {
+                        key: key.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(Self {
+            chain_id: chain_id.ok_or(ParseRollupError::MissingKey { key: "chain-id" })?,
+            rpc_port: rpc_port.ok_or(ParseRollupError::MissingKey { key: "rpc-port" })?,
+            query_port: query_port.ok_or(ParseRollupError::MissingKey { key: "query-port" })?,
+            l2_provider,
+        })
+    }
 }
 
 impl Options {
@@ -53,16 +291,215 @@ impl Options {
             chain_id: self.l2_chain_id,
         }
     }
+
+    /// Move a block store written by a version of the adaptor which only served one rollup to
+    /// where the rollup configured by the top-level options now keeps its blocks.
+    pub fn migrate_storage(&self) {
+        if let Some(path) = &self.storage_path {
+            let dir = path.join(self.l2_chain_id.to_string());
+            if let Err(err) = migrate_legacy_store(path, &dir) {
+                panic!("failed to migrate block store in {}: {err}", path.display());
+            }
+        }
+    }
+
+    /// Open the block store configured by these options.
+    pub fn block_store(&self) -> BlockStore {
+        match &self.storage_path {
+            Some(path) => {
+                let path = path.join(self.l2_chain_id.to_string());
+                BlockStore::open(&path).unwrap_or_else(|err| {
+                    panic!("failed to open block store at {}: {err}", path.display())
+                })
+            }
+            None => BlockStore::memory(),
+        }
+    }
+
+    /// The verifier to check synced blocks with, if verification is enabled.
+    pub fn verifier(&self) -> Option<Verifier> {
+        if !self.verify {
+            return None;
+        }
+        let address = self
+            .hotshot_address
+            .expect("verification requires the HotShot contract address");
+        Some(Verifier::new(&self.l1_provider, address))
+    }
+
+    /// Options for each rollup served by the adaptor, each configured as a single rollup.
+    ///
+    /// The rollup configured by the top-level options comes first. Panics if two rollups share a
+    /// chain ID or a port.
+    pub fn rollups(&self) -> Vec<Options> {
+        let rollups = self.expand_rollups();
+        if let Err(err) = check_rollups(&rollups) {
+            panic!("{err}");
+        }
+        rollups
+    }
+
+    /// Check that the options are consistent.
+    pub fn check(&self) -> Result<(), String> {
+        if self.verify && self.hotshot_address.is_none() {
+            return Err("verification requires the HotShot contract address".into());
+        }
+        if self.max_page_size == 0 {
+            return Err("maximum page size must be positive".into());
+        }
+        self.rate_limit.check()?;
+        check_rollups(&self.expand_rollups())
+    }
+
+    fn expand_rollups(&self) -> Vec<Options> {
+        let primary = Options {
+            rollups: vec![],
+            ..self.clone()
+        };
+        iter::once(primary.clone())
+            .chain(self.rollups.iter().map(|rollup| Options {
+                l2_chain_id: rollup.chain_id,
+                rpc_port: rollup.rpc_port,
+                query_port: rollup.query_port,
+                l2_provider: rollup.l2_provider.clone(),
+                ..primary.clone()
+            }))
+            .collect()
+    }
+}
+
+/// Check that no two rollups share a chain ID or a port.
+fn check_rollups(rollups: &[Options]) -> Result<(), String> {
+    let mut chain_ids = HashSet::new();
+    let mut ports = HashSet::new();
+    for rollup in rollups {
+        if !chain_ids.insert(rollup.l2_chain_id) {
+            return Err(format!(
+                "chain ID {} is configured for more than one rollup",
+                rollup.l2_chain_id
+            ));
+        }
+        for port in [rollup.rpc_port, rollup.query_port] {
+            if !ports.insert(port) {
+                return Err(format!(
+                    "port {port} is configured for more than one server"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_rollup() {
+        assert_eq!(
+            "chain-id=1002,rpc-port=8546,query-port=50101"
+                .parse::<RollupOptions>()
+                .unwrap(),
+            RollupOptions {
+                chain_id: 1002,
+                rpc_port: 8546,
+                query_port: 50101,
+                l2_provider: None,
+            }
+        );
+        assert_eq!(
+            "chain-id=1002, rpc-port=8546, query-port=50101, l2-provider=http://node:8123"
+                .parse::<RollupOptions>()
+                .unwrap()
+                .l2_provider,
+            Some("http://node:8123".parse().unwrap())
+        );
+
+        assert_eq!(
+            "chain-id=1002,rpc-port=8546".parse::<RollupOptions>(),
+            Err(ParseRollupError::MissingKey { key: "query-port" })
+        );
+        assert!(matches!(
+            "chain-id=1002,rpc-port=8546,query-port=50101,foo=bar".parse::<RollupOptions>(),
+            Err(ParseRollupError::UnknownKey { .. })
+        ));
+        assert!(matches!(
+            "chain-id=abc,rpc-port=8546,query-port=50101".parse::<RollupOptions>(),
+            Err(ParseRollupError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            "chain-id".parse::<RollupOptions>(),
+            Err(ParseRollupError::MalformedPair { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rollups() {
+        let opt = Options::parse_from([
+            "adaptor",
+            "--sequencer-url",
+            "http://sequencer:50000",
+            "--l1-provider",
+            "http://l1:8545",
+            "--rollup",
+            "chain-id=1002,rpc-port=8546,query-port=50101",
+        ]);
+        let rollups = opt.rollups();
+        assert_eq!(rollups.len(), 2);
+        assert_eq!(rollups[0].l2_chain_id, 1001);
+        assert_eq!(rollups[0].rpc_port, 8545);
+        assert_eq!(rollups[1].l2_chain_id, 1002);
+        assert_eq!(rollups[1].rpc_port, 8546);
+        assert_eq!(rollups[1].query_port, 50101);
+        assert_eq!(rollups[1].sequencer_url, opt.sequencer_url);
+    }
+
+    #[test]
+    fn test_check() {
+        let args = [
+            "adaptor",
+            "--sequencer-url",
+            "http://sequencer:50000",
+            "--l1-provider",
+            "http://l1:8545",
+        ];
+        Options::parse_from(args).check().unwrap();
+        Options::parse_from(args.into_iter().chain(["--max-page-size", "0"]))
+            .check()
+            .unwrap_err();
+        Options::parse_from(
+            args.into_iter()
+                .chain(["--rollup", "chain-id=1001,rpc-port=8546,query-port=50101"]),
+        )
+        .check()
+        .unwrap_err();
+    }
 }
 
 mod polygon_zkevm;
 #[cfg(any(test, feature = "testing"))]
 pub use polygon_zkevm::*;
 
+mod failover;
+#[cfg(any(test, feature = "testing"))]
+pub use failover::*;
+
 mod random_client;
 #[cfg(any(test, feature = "testing"))]
 pub use random_client::*;
 
+mod accounts;
+#[cfg(any(test, feature = "testing"))]
+pub use accounts::*;
+
+mod scenario;
+#[cfg(any(test, feature = "testing"))]
+pub use scenario::*;
+
 mod demo_with_sequencer;
 #[cfg(any(test, feature = "testing"))]
 pub use demo_with_sequencer::*;
+
+mod test_harness;
+#[cfg(any(test, feature = "testing"))]
+pub use test_harness::*;
@@ -0,0 +1,83 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Middleware for servers we don't build ourselves.
+//!
+//! The query service is a tide-disco app, which does not let us add middleware. Instead, we bind it
+//! to a [`MiddlewareListener`], which adds middleware to the server just before it starts
+//! listening.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+};
+use tide::{
+    listener::{ListenInfo, Listener, ToListener},
+    Middleware,
+};
+
+/// A listener which adds a middleware to the server bound to it.
+///
+/// Listeners can be nested to add several middlewares. The middleware of the outermost listener
+/// runs first.
+#[derive(Debug)]
+pub struct MiddlewareListener<L, M> {
+    listener: L,
+    middleware: M,
+}
+
+impl<L, M> MiddlewareListener<L, M> {
+    pub fn new(listener: L, middleware: M) -> Self {
+        Self {
+            listener,
+            middleware,
+        }
+    }
+}
+
+impl<State, L, M> ToListener<State> for MiddlewareListener<L, M>
+where
+    State: Clone + Send + Sync + 'static,
+    L: ToListener<State>,
+    M: Middleware<State> + Clone,
+{
+    type Listener = MiddlewareListener<L::Listener, M>;
+
+    fn to_listener(self) -> io::Result<Self::Listener> {
+        Ok(MiddlewareListener::new(
+            self.listener.to_listener()?,
+            self.middleware,
+        ))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State, L, M> Listener<State> for MiddlewareListener<L, M>
+where
+    State: Clone + Send + Sync + 'static,
+    L: Listener<State>,
+    M: Middleware<State> + Clone,
+{
+    async fn bind(&mut self, mut app: tide::Server<State>) -> io::Result<()> {
+        app.with(self.middleware.clone());
+        self.listener.bind(app).await
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        self.listener.accept().await
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        self.listener.info()
+    }
+}
+
+impl<L: Display, M> Display for MiddlewareListener<L, M> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.listener.fmt(f)
+    }
+}
@@ -6,15 +6,115 @@
 // You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use async_compatibility_layer::logging::{setup_backtrace, setup_logging};
-use clap::Parser;
-use futures::join;
-use polygon_zkevm_adaptor::{json_rpc, query_service, Options};
+use async_std::{
+    sync::{Arc, RwLock},
+    task::spawn,
+};
+use futures::{future::join_all, join};
+use polygon_zkevm_adaptor::{
+    config,
+    confirmations::Confirmations,
+    events::EventStream,
+    health::HealthMonitor,
+    json_rpc, query_service,
+    shutdown::{handle_signals, Shutdown},
+    sync::{sync_blocks, Rollup},
+    Options,
+};
+use std::process::exit;
 
 #[async_std::main]
 async fn main() {
     setup_logging();
     setup_backtrace();
 
-    let opt = Options::parse();
-    join!(json_rpc::serve(&opt), query_service::serve(&opt),);
+    let opt: Options = config::parse("adaptor", Options::check);
+    opt.migrate_storage();
+    if let Some(command) = &opt.command {
+        if let Err(err) = command.run(&opt).await {
+            eprintln!("error: {err}");
+            exit(1);
+        }
+        return;
+    }
+
+    let shutdown = Shutdown::default();
+    spawn(handle_signals(shutdown.clone()));
+
+    // Events from all rollups go to the same stream.
+    let events = Arc::new(EventStream::default());
+    if let Some(path) = &opt.events_socket {
+        spawn(events.clone().serve(path.clone()));
+    }
+
+    // All rollups are synced from a single subscription to the sequencer.
+    let rollups: Vec<_> = opt
+        .rollups()
+        .into_iter()
+        .map(|opt| {
+            let rollup = Rollup {
+                zkevm: opt.zkevm(),
+                store: Arc::new(RwLock::new(opt.block_store())),
+                new_block: Default::default(),
+                // Shared between the JSON-RPC server, which tracks submitted transactions, and the
+                // syncer, which syncs the blocks that confirm them.
+                confirmations: Arc::new(Confirmations::new(opt.confirmation_cache_size)),
+                // Likewise, the syncer reports the usage of the rollup to the gas price oracle.
+                gas_oracle: opt.gas_oracle.oracle(),
+            };
+            (opt, rollup)
+        })
+        .collect();
+    spawn(sync_blocks(
+        opt.sync.clone(),
+        surf_disco::Client::new(opt.sequencer_url.clone()),
+        opt.verifier(),
+        rollups.iter().map(|(_, rollup)| rollup.clone()).collect(),
+        events.clone(),
+    ));
+
+    let stores = join_all(rollups.into_iter().map(|(opt, rollup)| {
+        let shutdown = shutdown.clone();
+        let events = events.clone();
+        async move {
+            tracing::info!("serving rollup {}", opt.l2_chain_id);
+            let health = Arc::new(HealthMonitor::new(&opt, rollup.store.clone()));
+            join!(
+                json_rpc::serve(
+                    &opt,
+                    health.clone(),
+                    rollup.confirmations,
+                    events,
+                    rollup.gas_oracle,
+                    shutdown.clone()
+                ),
+                query_service::serve(
+                    &opt,
+                    rollup.store.clone(),
+                    rollup.new_block,
+                    health,
+                    shutdown
+                ),
+            );
+            rollup.store
+        }
+    }))
+    .await;
+
+    // Flush the block stores. We hold on to the locks until we exit, so that the background sync
+    // tasks cannot start writing another block.
+    let mut locked = vec![];
+    let mut clean = true;
+    for store in &stores {
+        let mut store = store.write().await;
+        if let Err(err) = store.flush() {
+            tracing::error!("failed to flush block store: {err}");
+            clean = false;
+        }
+        locked.push(store);
+    }
+    if !clean {
+        exit(1);
+    }
+    tracing::info!("shutdown complete");
 }
@@ -0,0 +1,107 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Prometheus metrics exported by the adaptor services.
+
+use prometheus::{IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+
+/// Metrics for the JSON-RPC adaptor.
+#[derive(Clone, Debug)]
+pub struct RpcMetrics {
+    registry: Registry,
+    pub submissions_allowed: IntCounter,
+    pub submissions_rate_limited: IntCounterVec,
+}
+
+impl Default for RpcMetrics {
+    fn default() -> Self {
+        let registry = Registry::new();
+        let submissions_allowed = IntCounter::new(
+            "rpc_submissions_allowed_total",
+            "Transaction submissions which passed rate limiting",
+        )
+        .unwrap();
+        let submissions_rate_limited = IntCounterVec::new(
+            Opts::new(
+                "rpc_submissions_rate_limited_total",
+                "Transaction submissions rejected by rate limiting, by limit",
+            ),
+            &["limit"],
+        )
+        .unwrap();
+        registry
+            .register(Box::new(submissions_allowed.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(submissions_rate_limited.clone()))
+            .unwrap();
+        Self {
+            registry,
+            submissions_allowed,
+            submissions_rate_limited,
+        }
+    }
+}
+
+impl RpcMetrics {
+    /// Export all metrics in the Prometheus text format.
+    pub fn export(&self) -> String {
+        export(&self.registry)
+    }
+}
+
+/// Metrics for the query service adaptor.
+#[derive(Clone, Debug)]
+pub struct QueryMetrics {
+    registry: Registry,
+    pub cache_hits: IntCounter,
+    pub cache_misses: IntCounter,
+}
+
+impl Default for QueryMetrics {
+    fn default() -> Self {
+        let registry = Registry::new();
+        let cache_hits = IntCounter::new(
+            "query_block_cache_hits_total",
+            "Blocks served from the cache",
+        )
+        .unwrap();
+        let cache_misses = IntCounter::new(
+            "query_block_cache_misses_total",
+            "Block requests which missed the cache",
+        )
+        .unwrap();
+        registry.register(Box::new(cache_hits.clone())).unwrap();
+        registry.register(Box::new(cache_misses.clone())).unwrap();
+        Self {
+            registry,
+            cache_hits,
+            cache_misses,
+        }
+    }
+}
+
+impl QueryMetrics {
+    /// The registry containing all query service metrics.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Export all metrics in the Prometheus text format.
+    pub fn export(&self) -> String {
+        export(&self.registry)
+    }
+}
+
+fn export(registry: &Registry) -> String {
+    TextEncoder::new()
+        .encode_to_string(&registry.gather())
+        .unwrap_or_else(|err| {
+            tracing::error!("failed to encode metrics: {err}");
+            String::new()
+        })
+}
@@ -18,13 +18,33 @@
 //! Polygon L2 node. However, in a production system, the node itself would be responsible for
 //! extracting the relevant transactions and decoding them directly from HotShot.
 
-use crate::Options;
-use async_std::sync::RwLock;
-use futures::{FutureExt, StreamExt, TryFutureExt};
+use crate::{
+    cache::BlockCache,
+    compression::Compression,
+    health::{HealthMonitor, Probes},
+    keep_alive::KeepAliveListener,
+    listener::MiddlewareListener,
+    metrics::QueryMetrics,
+    reorg::watch_reorgs,
+    shutdown::{DrainRequests, Shutdown},
+    storage::BlockStore,
+    Options,
+};
+use async_std::{
+    sync::{Arc, RwLock},
+    task::spawn,
+};
+use ethers::providers::{Http, Provider};
+use event_listener::Event;
+use futures::{
+    future::{select, Either},
+    stream, FutureExt, Stream, StreamExt, TryFutureExt,
+};
 use hotshot_query_service::availability::BlockQueryData;
 use sequencer::SeqTypes;
 use serde::{Deserialize, Serialize};
-use tide_disco::{error::ServerError, App};
+use std::{borrow::Cow, io};
+use tide_disco::{error::ServerError, App, Error as _, StatusCode};
 use zkevm::{polygon_zkevm::encode_transactions, ZkEvm};
 
 type HotShotClient = surf_disco::Client<ServerError>;
@@ -32,16 +52,66 @@ type HotShotClient = surf_disco::Client<ServerError>;
 struct State {
     hotshot: HotShotClient,
     zkevm: ZkEvm,
+    store: Arc<RwLock<BlockStore>>,
+    /// Notified whenever a new block is added to `store`.
+    new_block: Arc<Event>,
+    health: Arc<HealthMonitor>,
+    /// Only serve blocks which have been verified against the HotShot contract.
+    verify: bool,
+    cache: BlockCache,
+    metrics: Arc<QueryMetrics>,
+    /// Maximum number of blocks to return from a single range request.
+    max_page_size: usize,
 }
 
-pub async fn serve(opt: &Options) {
+impl State {
+    /// Get a block which has already been synced, from the cache if possible.
+    async fn stored_block(&self, height: u64) -> Result<Option<PolygonZkevmBlock>, ServerError> {
+        let store = self.store.read().await;
+        let rewinds = store.rewinds();
+        if let Some(block) = self.cache.get(height, rewinds) {
+            return Ok(Some(block));
+        }
+        let block = store.get(height).map_err(storage_error)?;
+        if let Some(block) = &block {
+            self.cache.insert(block.clone(), rewinds);
+        }
+        Ok(block)
+    }
+}
+
+/// Serve blocks from `store`, which is kept up to date by [`sync_blocks`](crate::sync::sync_blocks).
+///
+/// `new_block` must be notified whenever a block is added to `store`.
+pub async fn serve(
+    opt: &Options,
+    store: Arc<RwLock<BlockStore>>,
+    new_block: Arc<Event>,
+    health: Arc<HealthMonitor>,
+    shutdown: Shutdown,
+) {
     let hotshot = HotShotClient::new(opt.sequencer_url.clone());
+    let metrics = Arc::new(QueryMetrics::default());
     let state = State {
         hotshot,
         zkevm: opt.zkevm(),
+        store,
+        new_block,
+        health: health.clone(),
+        verify: opt.verify,
+        cache: BlockCache::new(opt.cache_size, metrics.clone()),
+        metrics,
+        max_page_size: opt.max_page_size,
     };
     state.hotshot.connect(None).await;
 
+    spawn(watch_reorgs(
+        Provider::try_from(opt.l1_provider.as_str()).unwrap(),
+        opt.sync.l1_reorg_check_interval,
+        state.store.clone(),
+        state.new_block.clone(),
+    ));
+
     let api: toml::Value = toml::from_str(include_str!("query_api.toml")).unwrap();
     let mut app = App::<_, ServerError>::with_state(RwLock::new(state));
     app.module::<ServerError>("availability", api)
@@ -49,12 +119,64 @@ pub async fn serve(opt: &Options) {
         .get("getblock", |req, state| {
             async move {
                 let height: u64 = req.integer_param("height")?;
+                let rewinds = state.store.read().await.rewinds();
+                if let Some(block) = state.stored_block(height).await? {
+                    return Ok(block);
+                }
+
+                // If we haven't synced this block yet, fall back to fetching it directly from the
+                // sequencer, unless we are only supposed to serve verified blocks.
+                if state.verify {
+                    return Err(ServerError::catch_all(
+                        StatusCode::NotFound,
+                        format!("block {height} has not been verified yet"),
+                    ));
+                }
                 let block = state
                     .hotshot
                     .get(&format!("availability/block/{height}"))
                     .send()
                     .await?;
-                Ok(PolygonZkevmBlock::new(state.zkevm, &block))
+                let block = PolygonZkevmBlock::new(state.zkevm, &block);
+                state.cache.insert(block.clone(), rewinds);
+                Ok(block)
+            }
+            .boxed()
+        })
+        .unwrap()
+        .get("getblockrange", |req, state| {
+            async move {
+                let from: u64 = req.integer_param("from")?;
+                let to = req.opt_integer_param("to")?.unwrap_or(u64::MAX);
+                // An empty page would point the client back at `from`, and it would never finish.
+                let limit: Option<usize> = req.opt_integer_param("limit")?;
+                if limit == Some(0) {
+                    return Err(ServerError::catch_all(
+                        StatusCode::BadRequest,
+                        "limit must be positive".into(),
+                    ));
+                }
+                let limit = limit
+                    .unwrap_or(state.max_page_size)
+                    .min(state.max_page_size);
+
+                // Only return blocks we have synced, so that a range request never turns into a
+                // burst of requests to the sequencer.
+                let end = to.min(state.store.read().await.height());
+                let page_end = end.min(from.saturating_add(limit as u64));
+                let mut blocks = vec![];
+                for height in from..page_end {
+                    match state.stored_block(height).await? {
+                        Some(block) => blocks.push(block),
+                        // The store was rewound while we were reading it.
+                        None => break,
+                    }
+                }
+                let next = from + blocks.len() as u64;
+                Ok(BlockRange {
+                    blocks,
+                    next: (next < end).then_some(next),
+                })
             }
             .boxed()
         })
@@ -63,13 +185,25 @@ pub async fn serve(opt: &Options) {
             async move {
                 let state = state.read().await;
                 let height: u64 = req.integer_param("height")?;
-                let blocks = state
-                    .hotshot
-                    .socket(&format!("availability/stream/blocks/{height}"))
-                    .subscribe::<BlockQueryData<SeqTypes>>()
-                    .await?;
-                let zkevm = state.zkevm;
-                Ok(blocks.map(move |block| Ok(PolygonZkevmBlock::new(zkevm, &block?))))
+                Ok(block_stream(
+                    state.store.clone(),
+                    state.new_block.clone(),
+                    height,
+                ))
+            }
+            .try_flatten_stream()
+            .boxed()
+        })
+        .unwrap()
+        .stream("streamblockrange", |req, state| {
+            async move {
+                let state = state.read().await;
+                let from: u64 = req.integer_param("from")?;
+                let to: u64 = req.integer_param("to")?;
+                Ok(
+                    block_stream(state.store.clone(), state.new_block.clone(), from)
+                        .take(to.saturating_sub(from) as usize),
+                )
             }
             .try_flatten_stream()
             .boxed()
@@ -84,26 +218,132 @@ pub async fn serve(opt: &Options) {
         })
         .unwrap();
 
-    if let Err(err) = app.serve(format!("0.0.0.0:{}", opt.query_port)).await {
-        tracing::error!("query service adaptor exited with error: {}", err);
+    let api: toml::Value = toml::from_str(include_str!("status_api.toml")).unwrap();
+    app.module::<ServerError>("status", api)
+        .unwrap()
+        .get("health", |_, state| {
+            async move { Ok(state.health.check().await) }.boxed()
+        })
+        .unwrap()
+        .metrics("metrics", |_, state| {
+            async move { Ok(Cow::Borrowed(state.metrics.registry())) }.boxed()
+        })
+        .unwrap();
+
+    // Track requests, so that range requests from the zkEVM node's synchronizer can finish before
+    // we exit. Block streams never finish, so they are cut off when the process exits. Probes are
+    // answered before any of this, and are not tracked.
+    let listener = KeepAliveListener::new(format!("0.0.0.0:{}", opt.query_port), &opt.keep_alive);
+    let drain = DrainRequests::new(shutdown.clone());
+    let probes = Probes::new(health);
+    let server = if opt.compression.is_enabled() {
+        app.serve(MiddlewareListener::new(
+            MiddlewareListener::new(
+                MiddlewareListener::new(listener, Compression::new(&opt.compression)),
+                drain,
+            ),
+            probes,
+        ))
+        .boxed()
+    } else {
+        app.serve(MiddlewareListener::new(
+            MiddlewareListener::new(listener, drain),
+            probes,
+        ))
+        .boxed()
+    };
+    // As with the JSON-RPC server, dropping the listener stops accepting new connections, while
+    // requests on existing connections continue to be handled in the background.
+    match select(Box::pin(server), Box::pin(shutdown.wait())).await {
+        Either::Left((Err(err), _)) => {
+            tracing::error!("query service adaptor exited with error: {}", err);
+        }
+        Either::Left((Ok(()), _)) => {}
+        Either::Right(_) => {
+            tracing::info!(
+                "query service on port {} draining {} requests",
+                opt.query_port,
+                shutdown.in_flight()
+            );
+            if !shutdown.drain(opt.shutdown_timeout).await {
+                tracing::warn!(
+                    "{} requests still in flight after {:?}",
+                    shutdown.in_flight(),
+                    opt.shutdown_timeout
+                );
+            }
+        }
     }
 }
 
+/// A stream of blocks from `store`, starting at `from`, which waits for new blocks to be synced
+/// when it reaches the end of the store.
+///
+/// If blocks are removed from the store due to an L1 reorg, the stream yields an error and ends, so
+/// that the client can resubscribe and receive the replacement blocks.
+fn block_stream(
+    store: Arc<RwLock<BlockStore>>,
+    new_block: Arc<Event>,
+    from: u64,
+) -> impl Stream<Item = Result<PolygonZkevmBlock, ServerError>> {
+    stream::unfold((Some(from), None), move |(height, mut rewinds)| {
+        let store = store.clone();
+        let new_block = new_block.clone();
+        async move {
+            let height = height?;
+            loop {
+                // Start listening before checking the store, so we can't miss a notification
+                // between the check and the wait.
+                let listener = new_block.listen();
+                let guard = store.read().await;
+                let expected_rewinds = *rewinds.get_or_insert(guard.rewinds());
+                if guard.rewinds() != expected_rewinds {
+                    let err = ServerError::catch_all(
+                        StatusCode::Conflict,
+                        "blocks were replaced due to an L1 reorg, resubscribe to receive them"
+                            .into(),
+                    );
+                    return Some((Err(err), (None, None)));
+                }
+                match guard.get(height) {
+                    Ok(Some(block)) => return Some((Ok(block), (Some(height + 1), rewinds))),
+                    Ok(None) => {}
+                    Err(err) => return Some((Err(storage_error(err)), (None, None))),
+                }
+                drop(guard);
+                listener.await;
+            }
+        }
+    })
+}
+
+fn storage_error(err: io::Error) -> ServerError {
+    ServerError::catch_all(StatusCode::InternalServerError, err.to_string())
+}
+
+/// A page of consecutive blocks.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockRange {
+    pub blocks: Vec<PolygonZkevmBlock>,
+    /// The height to start the next request from, if the requested range was not exhausted.
+    pub next: Option<u64>,
+}
+
 /// Block of Polygon zkEVM transactions produced by the HotShot sequencer.
 ///
 /// This type, derived from a sequencer block, contains the Polygon zkEVM transactions extracted
 /// from the sequencer block and hex encoded according to the format expected by the zkEVM node. It
 /// also contains metadata fields used by the node to associate this L2 block with an L1 block.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct PolygonZkevmBlock {
-    timestamp: u64,
-    height: u64,
-    l1_block: u64,
-    transactions: String,
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PolygonZkevmBlock {
+    pub timestamp: u64,
+    pub height: u64,
+    pub l1_block: u64,
+    pub transactions: String,
 }
 
 impl PolygonZkevmBlock {
-    fn new(zkevm: ZkEvm, l2_block: &BlockQueryData<SeqTypes>) -> Self {
+    pub fn new(zkevm: ZkEvm, l2_block: &BlockQueryData<SeqTypes>) -> Self {
         Self {
             timestamp: l2_block.header().timestamp,
             height: l2_block.height(),
@@ -117,8 +357,14 @@ impl PolygonZkevmBlock {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{
+        confirmations::Confirmations,
+        health::HealthReport,
+        sync::{sync_blocks, Rollup},
+        validation::DEFAULT_MAX_TRANSACTION_SIZE,
+        verify::{block_commitment, verify_payload, VerificationError},
+    };
     use async_compatibility_layer::logging::{setup_backtrace, setup_logging};
-    use async_std::task::spawn;
     use ethers::{prelude::*, types::transaction::eip2718::TypedTransaction};
     use futures::future::ready;
     use portpicker::pick_unused_port;
@@ -183,15 +429,58 @@ mod test {
         // Start the query service adaptor.
         let opt = Options {
             l1_provider: l1.url(),
+            l2_provider: None,
             sequencer_url: format!("http://localhost:{sequencer_port}")
                 .parse()
                 .unwrap(),
             l2_chain_id: 1001,
             rpc_port: 0,
             query_port: adaptor_port,
+            max_transaction_size: DEFAULT_MAX_TRANSACTION_SIZE,
+            storage_path: None,
+            rate_limit: Default::default(),
+            auth: Default::default(),
+            sync: Default::default(),
+            compression: Default::default(),
+            keep_alive: Default::default(),
+            gas_oracle: Default::default(),
+            hotshot_address: None,
+            verify: false,
+            cache_size: 100,
+            confirmation_cache_size: 100,
+            events_socket: None,
+            max_page_size: 100,
+            shutdown_timeout: Duration::from_secs(1),
+            readiness_max_lag: 5,
+            rollups: vec![],
+            command: None,
         };
         let zkevm = opt.zkevm();
-        spawn(async move { serve(&opt).await });
+        let rollup = Rollup {
+            zkevm,
+            store: Arc::new(RwLock::new(opt.block_store())),
+            new_block: Default::default(),
+            confirmations: Arc::new(Confirmations::new(opt.confirmation_cache_size)),
+            gas_oracle: None,
+        };
+        let health = Arc::new(HealthMonitor::new(&opt, rollup.store.clone()));
+        spawn(sync_blocks(
+            opt.sync.clone(),
+            HotShotClient::new(opt.sequencer_url.clone()),
+            None,
+            vec![rollup.clone()],
+            Default::default(),
+        ));
+        spawn(async move {
+            serve(
+                &opt,
+                rollup.store,
+                rollup.new_block,
+                health,
+                Default::default(),
+            )
+            .await
+        });
 
         // Subscribe to future blocks.
         let adaptor = surf_disco::Client::<ServerError>::new(
@@ -231,6 +520,38 @@ mod test {
             break i;
         };
 
+        // The transactions in the sequencer's block match its header, but the transactions from
+        // another block do not.
+        let sequencer = HotShotClient::new(
+            format!("http://localhost:{sequencer_port}")
+                .parse()
+                .unwrap(),
+        );
+        let genuine: BlockQueryData<SeqTypes> = sequencer
+            .get(&format!("availability/block/{block_num}"))
+            .send()
+            .await
+            .unwrap();
+        verify_payload(zkevm, &genuine).unwrap();
+        let other: BlockQueryData<SeqTypes> = sequencer
+            .get(&format!("availability/block/{}", block_num - 1))
+            .send()
+            .await
+            .unwrap();
+        let payload = serde_json::to_value(genuine.payload()).unwrap();
+        let mut tampered = serde_json::to_value(&genuine).unwrap();
+        for field in tampered.as_object_mut().unwrap().values_mut() {
+            if *field == payload {
+                *field = serde_json::to_value(other.payload()).unwrap();
+            }
+        }
+        let tampered: BlockQueryData<SeqTypes> = serde_json::from_value(tampered).unwrap();
+        assert_eq!(block_commitment(&tampered), block_commitment(&genuine));
+        assert!(matches!(
+            verify_payload(zkevm, &tampered),
+            Err(VerificationError::PayloadMismatch { .. })
+        ));
+
         let block = adaptor
             .get::<PolygonZkevmBlock>(&format!("block/{block_num}"))
             .send()
@@ -238,5 +559,33 @@ mod test {
             .unwrap();
         assert_eq!(block.height, block_num as u64);
         assert_eq!(expected, Bytes::from_str(&block.transactions).unwrap());
+
+        // Fetch the same block as part of a range.
+        let range = adaptor
+            .get::<BlockRange>(&format!("blocks?from=0&to={}&limit=100", block_num + 1))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(range.blocks.len(), block_num + 1);
+        assert_eq!(range.next, None);
+        assert_eq!(range.blocks[block_num], block);
+        for (i, block) in range.blocks.iter().enumerate() {
+            assert_eq!(block.height, i as u64);
+        }
+
+        // Check the health probes and report.
+        let root: surf::Url = format!("http://localhost:{adaptor_port}").parse().unwrap();
+        let res = surf::get(root.join("healthz").unwrap()).await.unwrap();
+        assert_eq!(res.status(), surf::StatusCode::Ok);
+        let mut res = surf::get(root.join("readyz").unwrap()).await.unwrap();
+        let ready = res.body_json::<HealthReport>().await.unwrap().ready;
+        assert_eq!(res.status() == surf::StatusCode::Ok, ready);
+        let status = surf_disco::Client::<ServerError>::new(root.join("status").unwrap());
+        let report = status.get::<HealthReport>("health").send().await.unwrap();
+        tracing::info!("health report: {report:?}");
+        assert!(report.sequencer.connected);
+        assert!(report.l1.connected);
+        assert_eq!(report.l2, None);
+        assert!(report.synced_height > block_num as u64);
     }
 }
@@ -6,23 +6,30 @@
 // You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 #![cfg(any(test, feature = "testing"))]
+use crate::{
+    events::{Event, EventStream},
+    with_failover, FailoverOptions, FailoverSigner, ProviderHealth,
+};
 use async_std::sync::RwLock;
 use ethers::{
     abi::Address,
+    middleware::NonceManagerMiddleware,
     prelude::{MnemonicBuilder, Signer as _},
     providers::{Middleware, Provider},
     signers::coins_bip39::English,
-    types::{TransactionRequest, H256, U256},
+    types::{Block, Bytes, Transaction, TransactionReceipt, TransactionRequest, H256, U256, U64},
 };
-use futures::future::join;
+use futures::future::{join, Future};
 use http_types::Url;
 use rand::{distributions::Standard, prelude::Distribution, Rng};
 
 use sequencer_utils::{NonceManager, Signer};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt::Display,
     path::PathBuf,
+    str::FromStr,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -73,6 +80,11 @@ pub async fn connect_rpc_simple(
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Transfer {
+    /// Index of the sending account, derived from the mnemonic of the run.
+    ///
+    /// If not given, the transfer is sent from the account running the operations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<u32>,
     pub to: Address,
     pub amount: U256,
 }
@@ -80,6 +92,7 @@ pub struct Transfer {
 impl Distribution<Transfer> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Transfer {
         Transfer {
+            from: None,
             to: rng.gen(),
             amount: rng.gen_range(0..1000).into(),
         }
@@ -92,6 +105,88 @@ impl Distribution<Transfer> for Standard {
 pub enum Operation {
     Transfer(Transfer),
     Wait(Duration),
+    Assert(BalanceAssertion),
+}
+
+/// A comparison between a balance and an expected amount.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    pub fn holds(self, lhs: U256, rhs: U256) -> bool {
+        match self {
+            Self::Eq => lhs == rhs,
+            Self::Ne => lhs != rhs,
+            Self::Lt => lhs < rhs,
+            Self::Le => lhs <= rhs,
+            Self::Gt => lhs > rhs,
+            Self::Ge => lhs >= rhs,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Eq => "==",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        }
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "==" => Ok(Self::Eq),
+            "!=" => Ok(Self::Ne),
+            "<" => Ok(Self::Lt),
+            "<=" => Ok(Self::Le),
+            ">" => Ok(Self::Gt),
+            ">=" => Ok(Self::Ge),
+            _ => Err(format!("unknown comparison {s:?}")),
+        }
+    }
+}
+
+/// A check on the balance of an account, made once all previous transfers have completed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BalanceAssertion {
+    /// Name of the account, for reporting.
+    pub name: String,
+    pub account: Address,
+    pub comparison: Comparison,
+    pub amount: U256,
+}
+
+impl BalanceAssertion {
+    /// Check the assertion, returning a description of the failure if it does not hold.
+    pub async fn check<M: Middleware>(&self, client: &M) -> Result<(), String> {
+        let balance = client
+            .get_balance(self.account, None)
+            .await
+            .map_err(|err| format!("unable to get balance of {}: {err}", self.name))?;
+        if self.comparison.holds(balance, self.amount) {
+            Ok(())
+        } else {
+            Err(format!(
+                "expected balance of {} {} {}, but it is {balance}",
+                self.name,
+                self.comparison.as_str(),
+                self.amount
+            ))
+        }
+    }
 }
 
 impl Distribution<Operation> for Standard {
@@ -114,29 +209,41 @@ pub enum Effect {
 }
 
 impl Operation {
-    async fn execute(&self, client: Arc<NonceManager>) -> Option<Effect> {
+    /// Execute the operation, returning an error if a transfer could not be submitted.
+    ///
+    /// Transfers are sent from the default sender of `client`, regardless of their `from` account.
+    async fn execute<M: Middleware>(&self, client: Arc<M>) -> Result<Option<Effect>, String> {
         match self {
             Operation::Transfer(transfer) => {
-                let Transfer { to, amount } = transfer;
+                let Transfer { to, amount, .. } = transfer;
                 let tx = TransactionRequest {
-                    from: Some(client.inner().address()),
+                    from: client.default_sender(),
                     to: Some((*to).into()),
                     value: Some(*amount),
                     ..Default::default()
                 };
-                let hash = client.send_transaction(tx, None).await.unwrap().tx_hash();
+                let hash = match client.send_transaction(tx, None).await {
+                    Ok(tx) => tx.tx_hash(),
+                    Err(err) => {
+                        tracing::error!("Failed to submit transaction: {err}");
+                        return Err(err.to_string());
+                    }
+                };
                 tracing::info!("Submitted transaction: {:?}", hash);
-                Some(Effect::PendingReceipt {
+                Ok(Some(Effect::PendingReceipt {
                     transfer: transfer.clone(),
                     hash,
                     start: Instant::now(),
-                })
+                }))
             }
             Operation::Wait(duration) => {
                 async_std::task::sleep(*duration).await;
                 tracing::info!("Finished sleep of {:?}", duration);
-                None
+                Ok(None)
             }
+            // Assertions depend on the effects of previous operations, so they are checked by the
+            // runner once those effects are complete.
+            Operation::Assert(_) => Ok(None),
         }
     }
 }
@@ -172,6 +279,17 @@ impl Operations {
         let operations = serde_json::from_str(&data).unwrap();
         Self(operations)
     }
+
+    /// Indices of the accounts, other than the one running the operations, which send transfers.
+    pub fn senders(&self) -> BTreeSet<u32> {
+        self.0
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Transfer(transfer) => transfer.from,
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -199,11 +317,117 @@ impl CombinedOperations {
     }
 }
 
+/// The client used by [`Run`] to submit transactions.
+type RunClient = NonceManagerMiddleware<FailoverSigner>;
+
+/// The result of a transfer submitted by a [`Run`], taken from its receipt.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransferReceipt {
+    pub hash: H256,
+    /// Whether the transfer succeeded, as opposed to being reverted.
+    pub success: bool,
+    pub gas_used: Option<U256>,
+    pub effective_gas_price: Option<U256>,
+    pub block_number: Option<U64>,
+}
+
+impl From<&TransactionReceipt> for TransferReceipt {
+    fn from(receipt: &TransactionReceipt) -> Self {
+        Self {
+            hash: receipt.transaction_hash,
+            success: receipt.status == Some(1.into()),
+            gas_used: receipt.gas_used,
+            effective_gas_price: receipt.effective_gas_price,
+            block_number: receipt.block_number,
+        }
+    }
+}
+
+/// Summary of a completed [`Run`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RunReport {
+    /// Number of transactions submitted.
+    pub submitted: usize,
+    /// Number of transactions with a successful receipt.
+    pub successful: usize,
+    /// Receipts of all transfers which completed, including reverted ones.
+    pub receipts: Vec<TransferReceipt>,
+    pub providers: Vec<ProviderHealth>,
+}
+
+impl RunReport {
+    /// Number of transfers which completed but were reverted.
+    pub fn reverted(&self) -> usize {
+        self.receipts
+            .iter()
+            .filter(|receipt| !receipt.success)
+            .count()
+    }
+
+    /// Total gas used by all completed transfers.
+    pub fn gas_used(&self) -> U256 {
+        self.receipts
+            .iter()
+            .filter_map(|receipt| receipt.gas_used)
+            .fold(U256::zero(), |total, gas| total + gas)
+    }
+
+    /// Total fees paid for all completed transfers, in wei.
+    pub fn fees(&self) -> U256 {
+        self.receipts
+            .iter()
+            .filter_map(|receipt| Some(receipt.gas_used? * receipt.effective_gas_price?))
+            .fold(U256::zero(), |total, fee| total + fee)
+    }
+
+    pub fn log(&self, name: &str) {
+        tracing::info!(
+            "[{name}] {}/{} transactions successful, {} reverted",
+            self.successful,
+            self.submitted,
+            self.reverted()
+        );
+        tracing::info!(
+            "[{name}] gas used: {}, fees paid: {} wei",
+            self.gas_used(),
+            self.fees()
+        );
+        for receipt in self.receipts.iter().filter(|receipt| !receipt.success) {
+            tracing::error!("[{name}] reverted: {receipt:?}");
+        }
+        for provider in &self.providers {
+            tracing::info!(
+                "[{name}] provider {}: {} requests, {} failures, {} rotations, last error: {}",
+                provider.url,
+                provider.requests,
+                provider.failures,
+                provider.rotations,
+                provider.last_error.as_deref().unwrap_or("none")
+            );
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct State {
     pending: VecDeque<Effect>,
+    /// Number of effects taken out of `pending` which are still being checked.
+    in_flight: usize,
+    /// Number of transfers which were given up on after a receipt timeout.
+    abandoned: usize,
     submit_operations_done: bool,
-    client: Arc<NonceManager>,
+    client: Arc<RunClient>,
+    /// Clients for the other accounts which send transfers, by account index.
+    senders: HashMap<u32, Arc<RunClient>>,
+    failed_assertions: Vec<String>,
+    receipts: Vec<TransferReceipt>,
+}
+
+impl State {
+    /// Whether every submitted transfer has either completed or been given up on.
+    fn settled(&self) -> bool {
+        self.pending.is_empty() && self.in_flight == 0
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -211,31 +435,84 @@ pub struct Run {
     name: String,
     operations: Operations,
     // The signer is used to re-initialize the nonce manager when necessary.
-    signer: Signer,
+    signer: FailoverSigner,
+    /// Signers for the other accounts which send transfers, by account index.
+    accounts: HashMap<u32, FailoverSigner>,
     state: Arc<RwLock<State>>,
+    events: Arc<EventStream>,
 }
 
 impl Run {
+    /// Run `operations` using `signer`, retrying failed requests with the default options.
     pub fn new(name: impl Into<String>, operations: Operations, signer: Signer) -> Self {
+        Self::with_failover(
+            name,
+            operations,
+            with_failover(&signer, FailoverOptions::default()),
+        )
+    }
+
+    /// Run `operations` using a signer which fails over between several providers.
+    pub fn with_failover(
+        name: impl Into<String>,
+        operations: Operations,
+        signer: FailoverSigner,
+    ) -> Self {
         Self {
             name: name.into(),
             operations,
             signer: signer.clone(),
+            accounts: Default::default(),
             state: Arc::new(RwLock::new(State {
                 pending: Default::default(),
+                in_flight: 0,
+                abandoned: 0,
                 submit_operations_done: Default::default(),
-                client: Arc::new(NonceManager::new(signer.clone(), signer.address())),
+                client: Arc::new(NonceManagerMiddleware::new(
+                    signer.clone(),
+                    signer.address(),
+                )),
+                senders: Default::default(),
+                failed_assertions: Default::default(),
+                receipts: Default::default(),
             })),
+            events: Default::default(),
         }
     }
 
+    /// Send transfers from other accounts using `signers`, by account index.
+    pub fn with_accounts(
+        mut self,
+        signers: impl IntoIterator<Item = (u32, FailoverSigner)>,
+    ) -> Self {
+        self.accounts.extend(signers);
+        self
+    }
+
+    /// Report the progress of the run to `events`.
+    pub fn with_events(mut self, events: Arc<EventStream>) -> Self {
+        self.events = events;
+        self
+    }
+
     /// Run the test and wait for completion.
-    ///
-    /// Returns
-    /// * Number of transactions submitted
-    /// * Number of transactions successful
-    pub async fn wait(&self) -> (usize, usize) {
-        join(self.submit_operations(), self.wait_for_effects()).await
+    pub async fn wait(&self) -> RunReport {
+        let (submitted, successful) = join(self.submit_operations(), self.wait_for_effects()).await;
+        RunReport {
+            submitted,
+            successful,
+            receipts: self.state.read().await.receipts.clone(),
+            providers: self.provider_health(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Request statistics for each of the providers used by this run.
+    pub fn provider_health(&self) -> Vec<ProviderHealth> {
+        self.signer.provider().as_ref().health()
     }
 
     pub async fn submit_operations(&self) -> usize {
@@ -246,18 +523,36 @@ impl Run {
                 self.name,
                 self.operations.0.len()
             );
-            if let Operation::Transfer(_) = operation {
-                submitted += 1;
-                let effect = operation
-                    .execute(self.state.read().await.client.clone())
-                    .await;
-                if let Some(effect) = effect {
-                    self.state.write().await.pending.push_back(effect);
+            match operation {
+                Operation::Transfer(transfer) => {
+                    submitted += 1;
+                    let effect = match self.client(transfer.from).await {
+                        Ok(client) => operation.execute(client).await,
+                        Err(err) => Err(err),
+                    };
+                    match effect {
+                        Ok(Some(effect)) => {
+                            let Effect::PendingReceipt { hash, .. } = &effect;
+                            self.events.emit(Event::TransferSubmitted {
+                                run: self.name.clone(),
+                                hash: *hash,
+                            });
+                            self.state.write().await.pending.push_back(effect);
+                        }
+                        Ok(None) => {}
+                        Err(error) => self.events.emit(Event::TransferFailed {
+                            run: self.name.clone(),
+                            error,
+                        }),
+                    }
                 }
-            } else {
-                operation
-                    .execute(self.state.read().await.client.clone())
-                    .await;
+                Operation::Wait(_) => {
+                    // Waiting cannot fail.
+                    let _ = operation
+                        .execute(self.state.read().await.client.clone())
+                        .await;
+                }
+                Operation::Assert(assertion) => self.check_assertion(assertion).await,
             }
         }
         self.state.write().await.submit_operations_done = true;
@@ -269,6 +564,60 @@ impl Run {
         submitted
     }
 
+    /// The client which sends transfers from the account with index `from`.
+    async fn client(&self, from: Option<u32>) -> Result<Arc<RunClient>, String> {
+        let mut state = self.state.write().await;
+        let Some(index) = from else {
+            return Ok(state.client.clone());
+        };
+        if let Some(client) = state.senders.get(&index) {
+            return Ok(client.clone());
+        }
+        let Some(signer) = self.accounts.get(&index) else {
+            return Err(format!("no signer for account {index}"));
+        };
+        let client = Arc::new(NonceManagerMiddleware::new(
+            signer.clone(),
+            signer.address(),
+        ));
+        state.senders.insert(index, client.clone());
+        Ok(client)
+    }
+
+    /// Descriptions of the assertions which failed during the run.
+    pub async fn failed_assertions(&self) -> Vec<String> {
+        self.state.read().await.failed_assertions.clone()
+    }
+
+    async fn check_assertion(&self, assertion: &BalanceAssertion) {
+        // Wait for all previous transfers to complete, so that the assertion sees their effects.
+        while !self.state.read().await.settled() {
+            async_std::task::sleep(Duration::from_secs(1)).await;
+        }
+        let (client, abandoned) = {
+            let state = self.state.read().await;
+            (state.client.clone(), state.abandoned)
+        };
+        if abandoned > 0 {
+            // The balance would not reflect the transfers which never completed.
+            let err = format!(
+                "unable to check balance of {}: {abandoned} earlier transfers timed out",
+                assertion.name
+            );
+            tracing::error!("[{}] assertion failed: {err}", self.name);
+            self.state.write().await.failed_assertions.push(err);
+            return;
+        }
+        match assertion.check(&*client).await {
+            Ok(()) => tracing::info!("[{}] assertion passed: {assertion:?}", self.name),
+            Err(err) => {
+                tracing::error!("[{}] assertion failed: {err}", self.name);
+                self.state.write().await.failed_assertions.push(err);
+            }
+        }
+    }
+
+    /// Wait for the receipts of submitted transfers, returning the number which succeeded.
     pub async fn wait_for_effects(&self) -> usize {
         let mut received = 0;
         loop {
@@ -277,26 +626,57 @@ impl Run {
                 self.name,
                 self.state.read().await.pending.len()
             );
-            let effect = { self.state.write().await.pending.pop_front() };
+            let effect = {
+                let mut state = self.state.write().await;
+                let effect = state.pending.pop_front();
+                if effect.is_some() {
+                    state.in_flight += 1;
+                }
+                effect
+            };
             if let Some(effect) = effect {
                 match effect {
                     Effect::PendingReceipt { hash, start, .. } => {
-                        if self
-                            .state
-                            .read()
-                            .await
-                            .client
-                            .get_transaction_receipt(hash)
-                            .await
-                            .unwrap()
-                            .is_some()
-                        {
-                            tracing::info!(
-                                "[{}] hash={hash:?} receive_receipt={:?}",
-                                self.name,
-                                start.elapsed()
-                            );
-                            received += 1;
+                        let client = self.state.read().await.client.clone();
+                        let receipt = match client.get_transaction_receipt(hash).await {
+                            Ok(receipt) => receipt,
+                            Err(err) => {
+                                // Treat errors like a missing receipt, so the effect is retried
+                                // until it times out.
+                                tracing::warn!("[{}] hash={hash:?} error={err}", self.name);
+                                None
+                            }
+                        };
+                        if let Some(receipt) = receipt {
+                            let receipt = TransferReceipt::from(&receipt);
+                            self.events.emit(Event::TransferCompleted {
+                                run: self.name.clone(),
+                                hash,
+                                success: receipt.success,
+                                gas_used: receipt.gas_used,
+                                latency_ms: start.elapsed().as_millis() as u64,
+                            });
+                            if receipt.success {
+                                tracing::info!(
+                                    "[{}] hash={hash:?} receive_receipt={:?} gas_used={:?} block={:?}",
+                                    self.name,
+                                    start.elapsed(),
+                                    receipt.gas_used,
+                                    receipt.block_number
+                                );
+                                received += 1;
+                            } else {
+                                tracing::error!(
+                                    "[{}] hash={hash:?} reverted={:?} gas_used={:?} block={:?}",
+                                    self.name,
+                                    start.elapsed(),
+                                    receipt.gas_used,
+                                    receipt.block_number
+                                );
+                            }
+                            let mut state = self.state.write().await;
+                            state.receipts.push(receipt);
+                            state.in_flight -= 1;
                         } else {
                             tracing::info!(
                                 "[{}] hash={hash:?} wait_receipt={:?}",
@@ -305,19 +685,30 @@ impl Run {
                             );
                             if start.elapsed() > Duration::from_secs(90) {
                                 tracing::info!("[{}] hash={hash:?} receipt_timeout", self.name);
+                                self.events.emit(Event::TransferTimedOut {
+                                    run: self.name.clone(),
+                                    hash,
+                                });
                                 tracing::info!("[{}] Removing all pending effects", self.name);
                                 // Keep a write lock to avoid adding more pending receipts.
                                 let mut state = self.state.write().await;
+                                state.in_flight -= 1;
+                                state.abandoned += 1;
                                 while let Some(effect) = state.pending.pop_front() {
                                     tracing::info!("[{}] effect_clear: {effect:?}", self.name);
+                                    state.abandoned += 1;
                                 }
-                                tracing::info!("[{}] Reinitializing nonce manager", self.name);
-                                state.client = Arc::new(NonceManager::new(
+                                tracing::info!("[{}] Reinitializing nonce managers", self.name);
+                                state.client = Arc::new(NonceManagerMiddleware::new(
                                     self.signer.clone(),
                                     self.signer.address(),
                                 ));
+                                state.senders.clear();
                             } else {
-                                self.state.write().await.pending.push_back(effect);
+                                let mut state = self.state.write().await;
+                                state.pending.push_back(effect);
+                                state.in_flight -= 1;
+                                drop(state);
                                 // No receipt for this transaction yet, wait a bit.
                                 async_std::task::sleep(Duration::from_millis(1000)).await;
                             }
@@ -329,7 +720,7 @@ impl Run {
                 async_std::task::sleep(Duration::from_secs(5)).await;
             }
             let state = self.state.read().await;
-            if state.submit_operations_done && state.pending.is_empty() {
+            if state.submit_operations_done && state.settled() {
                 tracing::info!(
                     "[{}] All effects completed ({received} successful)!",
                     self.name
@@ -342,6 +733,295 @@ impl Run {
     }
 }
 
+/// How long to wait for the receipt of a transaction during a replay.
+const REPLAY_RECEIPT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// How long to keep retrying a failed request for a final balance during a replay.
+const REPLAY_BALANCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The observable outcome of a transfer.
+///
+/// This excludes anything which legitimately differs between two chains executing the same
+/// operations, such as transaction hashes (which depend on the chain ID), block numbers and
+/// timestamps.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransferOutcome {
+    pub from: Address,
+    pub to: Option<Address>,
+    pub value: U256,
+    pub nonce: U256,
+    pub input: Bytes,
+    pub status: Option<U64>,
+    pub gas_used: Option<U256>,
+    pub num_logs: usize,
+}
+
+/// A transaction included in a block, without any of its chain-specific fields.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransactionContents {
+    pub from: Address,
+    pub to: Option<Address>,
+    pub value: U256,
+    pub nonce: U256,
+    pub input: Bytes,
+}
+
+impl From<&Transaction> for TransactionContents {
+    fn from(tx: &Transaction) -> Self {
+        Self {
+            from: tx.from,
+            to: tx.to,
+            value: tx.value,
+            nonce: tx.nonce,
+            input: tx.input.clone(),
+        }
+    }
+}
+
+/// The contents of the block which included a transfer.
+///
+/// Like [`TransferOutcome`], this excludes hashes, block numbers and timestamps, as well as the
+/// state root, which depends on how each endpoint commits to its state.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockContents {
+    pub transactions: Vec<TransactionContents>,
+    pub gas_used: U256,
+}
+
+impl From<&Block<Transaction>> for BlockContents {
+    fn from(block: &Block<Transaction>) -> Self {
+        Self {
+            transactions: block.transactions.iter().map(Into::into).collect(),
+            gas_used: block.gas_used,
+        }
+    }
+}
+
+/// The result of replaying [`Operations`] against a single L2 endpoint.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReplayResult {
+    /// The outcome of each transfer, in order, or `None` if it never completed.
+    pub outcomes: Vec<Option<TransferOutcome>>,
+    /// The contents of the block which included each transfer, in order, or `None` if it never
+    /// completed.
+    #[serde(default)]
+    pub blocks: Vec<Option<BlockContents>>,
+    /// Final balances of every account involved in the replay, except those whose balance could
+    /// not be fetched.
+    pub balances: BTreeMap<Address, U256>,
+    /// Descriptions of the assertions which failed during the replay.
+    #[serde(default)]
+    pub failed_assertions: Vec<String>,
+}
+
+/// A difference in behavior between two L2 endpoints replaying the same operations.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Divergence {
+    Outcome {
+        /// The index of the transfer among the transfers in the replayed operations.
+        transfer: usize,
+        left: Option<TransferOutcome>,
+        right: Option<TransferOutcome>,
+    },
+    Block {
+        /// The index of the transfer which the block included.
+        transfer: usize,
+        left: Option<BlockContents>,
+        right: Option<BlockContents>,
+    },
+    Balance {
+        address: Address,
+        left: Option<U256>,
+        right: Option<U256>,
+    },
+}
+
+impl Operations {
+    /// Execute these operations against the L2 endpoint `signer` is connected to, one at a time.
+    ///
+    /// Unlike [`Run`], which submits transfers as fast as the plan allows, a replay waits for each
+    /// transfer to complete before moving on, so that the outcome does not depend on how
+    /// transactions happen to be batched by the endpoint.
+    pub async fn replay(&self, name: &str, signer: Signer) -> ReplayResult {
+        let client = Arc::new(NonceManager::new(signer.clone(), signer.address()));
+        let mut result = ReplayResult::default();
+        let mut accounts = vec![signer.address()];
+        for (index, operation) in self.0.iter().enumerate() {
+            tracing::info!(
+                "[{name}] Replaying operation {index: >6} / {}: {operation:?}",
+                self.0.len()
+            );
+            if let Operation::Assert(assertion) = operation {
+                // Each transfer has already completed, so we can check the assertion right away.
+                if let Err(err) = assertion.check(&signer).await {
+                    tracing::error!("[{name}] assertion failed: {err}");
+                    result.failed_assertions.push(err);
+                }
+                continue;
+            }
+            if let Operation::Transfer(Transfer {
+                from: Some(index), ..
+            }) = operation
+            {
+                tracing::warn!(
+                    "[{name}] unable to replay transfer from account {index}, only transfers from \
+                     the replaying account are supported"
+                );
+                result.outcomes.push(None);
+                result.blocks.push(None);
+                continue;
+            }
+            let effect = operation.execute(client.clone()).await.ok().flatten();
+            let Operation::Transfer(transfer) = operation else {
+                continue;
+            };
+            accounts.push(transfer.to);
+            // A transfer which could not be submitted has no outcome.
+            let (outcome, block) = match effect {
+                Some(Effect::PendingReceipt { hash, .. }) => {
+                    match transfer_outcome(name, &signer, hash).await {
+                        Some((outcome, block)) => (Some(outcome), Some(block)),
+                        None => {
+                            tracing::warn!("[{name}] hash={hash:?} receipt_timeout");
+                            (None, None)
+                        }
+                    }
+                }
+                None => (None, None),
+            };
+            result.outcomes.push(outcome);
+            result.blocks.push(block);
+        }
+        let client = &signer;
+        for address in accounts {
+            let deadline = Instant::now() + REPLAY_BALANCE_TIMEOUT;
+            let balance = poll_until(
+                deadline,
+                format!("[{name}] balance of {address:?}"),
+                || async move { client.get_balance(address, None).await.map(Some) },
+            )
+            .await;
+            match balance {
+                Some(balance) => {
+                    result.balances.insert(address, balance);
+                }
+                None => tracing::error!("[{name}] unable to get balance of {address:?}"),
+            }
+        }
+        result
+    }
+}
+
+/// Wait for the transfer `hash` to complete, returning its outcome and the block which included it.
+///
+/// Returns `None` if the transfer did not complete, or its details could not be fetched, within
+/// [`REPLAY_RECEIPT_TIMEOUT`].
+async fn transfer_outcome(
+    name: &str,
+    client: &Signer,
+    hash: H256,
+) -> Option<(TransferOutcome, BlockContents)> {
+    let deadline = Instant::now() + REPLAY_RECEIPT_TIMEOUT;
+    let receipt = poll_until(deadline, format!("[{name}] hash={hash:?} receipt"), || {
+        client.get_transaction_receipt(hash)
+    })
+    .await?;
+    let tx = poll_until(
+        deadline,
+        format!("[{name}] hash={hash:?} transaction"),
+        || client.get_transaction(hash),
+    )
+    .await?;
+    let block_number = receipt.block_number?;
+    let block = poll_until(deadline, format!("[{name}] hash={hash:?} block"), || {
+        client.get_block_with_txs(block_number)
+    })
+    .await?;
+    let outcome = TransferOutcome {
+        from: tx.from,
+        to: tx.to,
+        value: tx.value,
+        nonce: tx.nonce,
+        input: tx.input,
+        status: receipt.status,
+        gas_used: receipt.gas_used,
+        num_logs: receipt.logs.len(),
+    };
+    Some((outcome, (&block).into()))
+}
+
+/// Call `f` until it returns a value, retrying every second until `deadline`.
+///
+/// Errors are logged and retried like a missing value, as missing receipts are in
+/// [`Run::wait_for_effects`], so that a transient error from the endpoint does not end the replay.
+async fn poll_until<T, E, F>(
+    deadline: Instant,
+    what: impl Display,
+    mut f: impl FnMut() -> F,
+) -> Option<T>
+where
+    E: Display,
+    F: Future<Output = Result<Option<T>, E>>,
+{
+    loop {
+        match f().await {
+            Ok(Some(value)) => return Some(value),
+            Ok(None) => {}
+            Err(err) => tracing::warn!("{what}: {err}"),
+        }
+        if Instant::now() > deadline {
+            return None;
+        }
+        async_std::task::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+impl ReplayResult {
+    /// Find all the differences between two replays of the same operations.
+    pub fn diff(&self, other: &Self) -> Vec<Divergence> {
+        let mut divergences = vec![];
+        let num_transfers = self.outcomes.len().max(other.outcomes.len());
+        for transfer in 0..num_transfers {
+            let left = self.outcomes.get(transfer).cloned().flatten();
+            let right = other.outcomes.get(transfer).cloned().flatten();
+            if left != right {
+                divergences.push(Divergence::Outcome {
+                    transfer,
+                    left,
+                    right,
+                });
+            }
+            let left = self.blocks.get(transfer).cloned().flatten();
+            let right = other.blocks.get(transfer).cloned().flatten();
+            if left != right {
+                divergences.push(Divergence::Block {
+                    transfer,
+                    left,
+                    right,
+                });
+            }
+        }
+        let addresses: BTreeSet<_> = self
+            .balances
+            .keys()
+            .chain(other.balances.keys())
+            .copied()
+            .collect();
+        for address in addresses {
+            let left = self.balances.get(&address).copied();
+            let right = other.balances.get(&address).copied();
+            if left != right {
+                divergences.push(Divergence::Balance {
+                    address,
+                    left,
+                    right,
+                });
+            }
+        }
+        divergences
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -355,4 +1035,102 @@ mod tests {
         ops.save(&path);
         assert_eq!(Operations::load(&path), ops);
     }
+
+    #[test]
+    fn test_replay_diff() {
+        let outcome = TransferOutcome {
+            from: Address::random(),
+            to: Some(Address::random()),
+            value: 1.into(),
+            nonce: 0.into(),
+            input: Default::default(),
+            status: Some(1.into()),
+            gas_used: Some(21000.into()),
+            num_logs: 0,
+        };
+        let block = BlockContents {
+            transactions: vec![TransactionContents {
+                from: outcome.from,
+                to: outcome.to,
+                value: outcome.value,
+                nonce: outcome.nonce,
+                input: outcome.input.clone(),
+            }],
+            gas_used: 21000.into(),
+        };
+        let alice = Address::random();
+        let bob = Address::random();
+        let left = ReplayResult {
+            outcomes: vec![Some(outcome.clone()), Some(outcome.clone())],
+            blocks: vec![Some(block.clone()), Some(block.clone())],
+            balances: [(alice, 1.into()), (bob, 2.into())].into(),
+            ..Default::default()
+        };
+        assert_eq!(left.diff(&left), vec![]);
+
+        let failed = TransferOutcome {
+            status: Some(0.into()),
+            ..outcome.clone()
+        };
+        // The same transfers, but the first one was included in a block with an extra transaction.
+        let crowded = BlockContents {
+            transactions: vec![
+                block.transactions[0].clone(),
+                TransactionContents {
+                    from: bob,
+                    to: Some(alice),
+                    value: 1.into(),
+                    nonce: 0.into(),
+                    input: Default::default(),
+                },
+            ],
+            gas_used: 42000.into(),
+        };
+        let right = ReplayResult {
+            outcomes: vec![Some(outcome), Some(failed.clone())],
+            blocks: vec![Some(crowded.clone()), Some(block)],
+            balances: [(alice, 1.into()), (bob, 3.into())].into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            left.diff(&right),
+            vec![
+                Divergence::Block {
+                    transfer: 0,
+                    left: left.blocks[0].clone(),
+                    right: Some(crowded),
+                },
+                Divergence::Outcome {
+                    transfer: 1,
+                    left: left.outcomes[1].clone(),
+                    right: Some(failed),
+                },
+                Divergence::Balance {
+                    address: bob,
+                    left: Some(2.into()),
+                    right: Some(3.into()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_report() {
+        let receipt = |success, gas_used: u64, price: u64| TransferReceipt {
+            hash: H256::random(),
+            success,
+            gas_used: Some(gas_used.into()),
+            effective_gas_price: Some(price.into()),
+            block_number: Some(1.into()),
+        };
+        let report = RunReport {
+            submitted: 3,
+            successful: 1,
+            receipts: vec![receipt(true, 21000, 2), receipt(false, 30000, 3)],
+            providers: vec![],
+        };
+        assert_eq!(report.reverted(), 1);
+        assert_eq!(report.gas_used(), 51000.into());
+        assert_eq!(report.fees(), U256::from(21000u64 * 2 + 30000 * 3));
+    }
 }
@@ -0,0 +1,422 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Rate limiting for transaction submission.
+//!
+//! Submissions are limited by a global token bucket, shared by all clients, and by a token bucket
+//! per client IP address. A request is only allowed if both buckets have a token available. Note
+//! that the client address is the address of the peer connected to the adaptor, so when requests
+//! are forwarded by a zkEVM node or a reverse proxy, all of them count against the same address.
+
+use crate::{
+    json_rpc::{is_submission, rpc_error_response},
+    metrics::RpcMetrics,
+};
+use clap::Args;
+use http_types::{Method, StatusCode};
+use lru::LruCache;
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// JSON-RPC error code for requests rejected due to rate limiting (EIP-1474).
+pub const LIMIT_EXCEEDED: i64 = -32005;
+
+/// Number of per-IP buckets to keep.
+///
+/// Beyond this, the bucket of the least recently seen client is forgotten. Such a client has most
+/// likely refilled its bucket anyway, and a full bucket is indistinguishable from a new one.
+const MAX_TRACKED_CLIENTS: usize = 10000;
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct RateLimitOptions {
+    /// Maximum rate of transaction submissions, in transactions per second, across all clients.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_RATE_LIMIT_GLOBAL")]
+    pub rate_limit_global: Option<f64>,
+
+    /// Maximum number of transactions which can be submitted at once across all clients.
+    ///
+    /// Defaults to one second's worth of transactions.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_RATE_LIMIT_GLOBAL_BURST")]
+    pub rate_limit_global_burst: Option<u32>,
+
+    /// Maximum rate of transaction submissions, in transactions per second, from a single IP.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_RATE_LIMIT_PER_IP")]
+    pub rate_limit_per_ip: Option<f64>,
+
+    /// Maximum number of transactions which can be submitted at once from a single IP.
+    ///
+    /// Defaults to one second's worth of transactions.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_RATE_LIMIT_PER_IP_BURST")]
+    pub rate_limit_per_ip_burst: Option<u32>,
+}
+
+impl RateLimitOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.rate_limit_global.is_some() || self.rate_limit_per_ip.is_some()
+    }
+
+    /// Check that the configured rates and bursts are positive.
+    pub fn check(&self) -> Result<(), String> {
+        for (name, rate) in [
+            ("global", self.rate_limit_global),
+            ("per-IP", self.rate_limit_per_ip),
+        ] {
+            if rate.map_or(false, |rate| !(rate.is_finite() && rate > 0.)) {
+                return Err(format!("{name} rate limit must be a positive number"));
+            }
+        }
+        for (name, burst) in [
+            ("global", self.rate_limit_global_burst),
+            ("per-IP", self.rate_limit_per_ip_burst),
+        ] {
+            if burst == Some(0) {
+                return Err(format!("{name} rate limit burst must be positive"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TokenBucket {
+    /// Tokens added per second.
+    rate: f64,
+    /// Maximum number of tokens.
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket refilling at `rate` tokens per second.
+    ///
+    /// If `burst` is not given, the capacity of the bucket is one second's worth of tokens.
+    pub fn new(rate: f64, burst: Option<u32>, now: Instant) -> Self {
+        let capacity = burst.map(f64::from).unwrap_or(rate.ceil()).max(1.);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    /// Take a token from the bucket, if one is available.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        let available = self.has_token(now);
+        if available {
+            self.take();
+        }
+        available
+    }
+
+    /// Whether a token is available at time `now`, without taking it.
+    pub fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.
+    }
+
+    /// Take a token which [`has_token`](Self::has_token) said was available.
+    fn take(&mut self) {
+        self.tokens -= 1.;
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+/// The limit which caused a request to be rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Global,
+    PerIp,
+}
+
+impl Limit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Global => "global",
+            Self::PerIp => "ip",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    global: Option<Mutex<TokenBucket>>,
+    per_ip: Option<(f64, Option<u32>)>,
+    clients: Mutex<LruCache<IpAddr, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(opt: &RateLimitOptions) -> Self {
+        let now = Instant::now();
+        Self {
+            global: opt
+                .rate_limit_global
+                .map(|rate| Mutex::new(TokenBucket::new(rate, opt.rate_limit_global_burst, now))),
+            per_ip: opt
+                .rate_limit_per_ip
+                .map(|rate| (rate, opt.rate_limit_per_ip_burst)),
+            clients: Mutex::new(LruCache::new(
+                NonZeroUsize::new(MAX_TRACKED_CLIENTS).unwrap(),
+            )),
+        }
+    }
+
+    /// Check whether a request from `client` is allowed at time `now`.
+    ///
+    /// Tokens are only taken if both the per-IP and the global bucket have one, so that a request
+    /// rejected by one limit does not count against the other. If both limits are exceeded, the
+    /// per-IP limit is reported.
+    pub fn check(&self, client: Option<IpAddr>, now: Instant) -> Result<(), Limit> {
+        let mut clients = self.clients.lock().unwrap();
+        let mut per_ip = match (self.per_ip, client) {
+            (Some((rate, burst)), Some(ip)) => {
+                Some(clients.get_or_insert_mut(ip, || TokenBucket::new(rate, burst, now)))
+            }
+            _ => None,
+        };
+        let mut global = self.global.as_ref().map(|global| global.lock().unwrap());
+
+        if let Some(bucket) = &mut per_ip {
+            if !bucket.has_token(now) {
+                return Err(Limit::PerIp);
+            }
+        }
+        if let Some(bucket) = &mut global {
+            if !bucket.has_token(now) {
+                return Err(Limit::Global);
+            }
+        }
+        if let Some(bucket) = per_ip {
+            bucket.take();
+        }
+        if let Some(bucket) = &mut global {
+            bucket.take();
+        }
+        Ok(())
+    }
+}
+
+/// Middleware enforcing a [`RateLimiter`] on transaction submissions.
+///
+/// Requests which do not submit transactions are not rate limited.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+    metrics: Arc<RpcMetrics>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: RateLimiter, metrics: Arc<RpcMetrics>) -> Self {
+        Self {
+            limiter: Arc::new(limiter),
+            metrics,
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for RateLimit {
+    async fn handle(
+        &self,
+        mut req: tide::Request<State>,
+        next: tide::Next<'_, State>,
+    ) -> tide::Result {
+        if req.method() != Method::Post || !is_submission(&mut req).await? {
+            return Ok(next.run(req).await);
+        }
+
+        let client = req
+            .peer_addr()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+            .map(|addr| addr.ip());
+        match self.limiter.check(client, Instant::now()) {
+            Ok(()) => {
+                self.metrics.submissions_allowed.inc();
+                Ok(next.run(req).await)
+            }
+            Err(limit) => {
+                tracing::info!("rate limiting submission from {client:?} ({limit:?} limit)");
+                self.metrics
+                    .submissions_rate_limited
+                    .with_label_values(&[limit.as_str()])
+                    .inc();
+                Ok(rpc_error_response(
+                    StatusCode::TooManyRequests,
+                    LIMIT_EXCEEDED,
+                    "rate limit exceeded",
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2., Some(3), start);
+
+        // We can use the whole burst at once.
+        for _ in 0..3 {
+            assert!(bucket.try_acquire(start));
+        }
+        assert!(!bucket.try_acquire(start));
+
+        // Tokens are refilled at the configured rate.
+        assert!(bucket.try_acquire(start + Duration::from_millis(500)));
+        assert!(!bucket.try_acquire(start + Duration::from_millis(500)));
+
+        // The bucket never holds more than its capacity.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_acquire(later));
+        }
+        assert!(!bucket.try_acquire(later));
+    }
+
+    #[test]
+    fn test_per_ip_limit() {
+        let limiter = RateLimiter::new(&RateLimitOptions {
+            rate_limit_per_ip: Some(1.),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let alice = Some("10.0.0.1".parse().unwrap());
+        let bob = Some("10.0.0.2".parse().unwrap());
+
+        limiter.check(alice, now).unwrap();
+        assert_eq!(limiter.check(alice, now), Err(Limit::PerIp));
+        // Other clients are not affected by Alice using up her quota.
+        limiter.check(bob, now).unwrap();
+        // Clients with unknown addresses are only subject to the global limit.
+        limiter.check(None, now).unwrap();
+        limiter.check(None, now).unwrap();
+    }
+
+    #[test]
+    fn test_tracked_clients() {
+        let limiter = RateLimiter::new(&RateLimitOptions {
+            rate_limit_per_ip: Some(1.),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let client = |i: usize| Some(IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]));
+
+        // Even if all clients are active, we only track a bounded number of them, forgetting the
+        // least recently seen first.
+        for i in 0..=MAX_TRACKED_CLIENTS {
+            limiter.check(client(i), now).unwrap();
+        }
+        assert_eq!(limiter.clients.lock().unwrap().len(), MAX_TRACKED_CLIENTS);
+        limiter.check(client(0), now).unwrap();
+        assert_eq!(
+            limiter.check(client(MAX_TRACKED_CLIENTS), now),
+            Err(Limit::PerIp)
+        );
+    }
+
+    #[test]
+    fn test_check_options() {
+        for opt in [
+            RateLimitOptions {
+                rate_limit_global: Some(0.),
+                ..Default::default()
+            },
+            RateLimitOptions {
+                rate_limit_per_ip: Some(-1.),
+                ..Default::default()
+            },
+            RateLimitOptions {
+                rate_limit_per_ip: Some(f64::NAN),
+                ..Default::default()
+            },
+            RateLimitOptions {
+                rate_limit_global: Some(1.),
+                rate_limit_global_burst: Some(0),
+                ..Default::default()
+            },
+        ] {
+            opt.check().unwrap_err();
+        }
+        RateLimitOptions {
+            rate_limit_global: Some(0.5),
+            rate_limit_per_ip_burst: Some(1),
+            ..Default::default()
+        }
+        .check()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let limiter = RateLimiter::new(&RateLimitOptions {
+            rate_limit_global: Some(1.),
+            rate_limit_global_burst: Some(2),
+            rate_limit_per_ip: Some(10.),
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        limiter
+            .check(Some("10.0.0.1".parse().unwrap()), now)
+            .unwrap();
+        limiter
+            .check(Some("10.0.0.2".parse().unwrap()), now)
+            .unwrap();
+        assert_eq!(
+            limiter.check(Some("10.0.0.3".parse().unwrap()), now),
+            Err(Limit::Global)
+        );
+        limiter
+            .check(
+                Some("10.0.0.3".parse().unwrap()),
+                now + Duration::from_secs(1),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_rejected_requests_take_no_tokens() {
+        let limiter = RateLimiter::new(&RateLimitOptions {
+            rate_limit_global: Some(1.),
+            rate_limit_global_burst: Some(1),
+            rate_limit_per_ip: Some(0.1),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let alice = Some("10.0.0.1".parse().unwrap());
+        let bob = Some("10.0.0.2".parse().unwrap());
+
+        // Alice uses up the global bucket, so Bob is rejected by the global limit...
+        limiter.check(alice, now).unwrap();
+        assert_eq!(limiter.check(bob, now), Err(Limit::Global));
+        // ...without using up his own allowance, which he can use once the global bucket refills.
+        let later = now + Duration::from_secs(1);
+        limiter.check(bob, later).unwrap();
+
+        // Likewise, requests rejected by the per-IP limit leave the global bucket alone.
+        let later = later + Duration::from_secs(1);
+        assert_eq!(limiter.check(alice, later), Err(Limit::PerIp));
+        limiter.check(None, later).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Detection of L1 reorgs affecting synced blocks.
+//!
+//! Each sequencer block refers to an L1 block by number, which the zkEVM node uses to associate the
+//! L2 block with L1 state. We remember the hash of each recent L1 block referenced by a synced
+//! block. If the L1 reorgs and one of those hashes changes, the synced blocks referring to the
+//! reorged L1 blocks are removed from the store, so they are fetched and translated again. Removing
+//! blocks also ends any block streams served to the zkEVM node, which then has to resubscribe from
+//! its own view of the chain.
+
+use crate::storage::BlockStore;
+use async_std::{
+    sync::{Arc, RwLock},
+    task::sleep,
+};
+use ethers::{
+    providers::{Http, Middleware, Provider, ProviderError},
+    types::H256,
+};
+use event_listener::Event;
+use snafu::Snafu;
+use std::{collections::BTreeMap, io, time::Duration};
+
+/// Maximum number of L1 blocks to track.
+///
+/// Reorgs deeper than this are not detected.
+const MAX_TRACKED_L1_BLOCKS: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TrackedL1Block {
+    hash: H256,
+    /// The first synced block which refers to this L1 block.
+    first_height: u64,
+}
+
+#[derive(Debug)]
+pub struct ReorgDetector {
+    l1: Provider<Http>,
+    /// Recent L1 blocks referenced by synced blocks, by L1 block number.
+    tracked: BTreeMap<u64, TrackedL1Block>,
+    /// Height of the next synced block whose L1 block we have not yet looked at.
+    next_height: u64,
+}
+
+impl ReorgDetector {
+    /// Track L1 blocks referenced by blocks synced from `from` onwards.
+    pub fn new(l1: Provider<Http>, from: u64) -> Self {
+        Self {
+            l1,
+            tracked: Default::default(),
+            next_height: from,
+        }
+    }
+
+    /// Check for reorgs of tracked L1 blocks, and start tracking newly synced blocks.
+    ///
+    /// If a reorg is detected, `store` is truncated to remove the affected blocks, and the new
+    /// height of the store is returned.
+    pub async fn update(&mut self, store: &RwLock<BlockStore>) -> Result<Option<u64>, ReorgError> {
+        let rewind = self.check().await?;
+        if let Some(height) = rewind {
+            store.write().await.truncate(height)?;
+            self.next_height = height;
+        }
+
+        let height = store.read().await.height();
+        // The store may have been rewound by someone else, in which case we go back with it.
+        self.next_height = self.next_height.min(height);
+        while self.next_height < height {
+            let Some(block) = store.read().await.get(self.next_height)? else {
+                break;
+            };
+            if !self.tracked.contains_key(&block.l1_block) {
+                let Some(hash) = self.hash(block.l1_block).await? else {
+                    // Our L1 provider is behind the sequencer. Try again later.
+                    break;
+                };
+                self.tracked.insert(
+                    block.l1_block,
+                    TrackedL1Block {
+                        hash,
+                        first_height: self.next_height,
+                    },
+                );
+                while self.tracked.len() > MAX_TRACKED_L1_BLOCKS {
+                    self.tracked.pop_first();
+                }
+            }
+            self.next_height += 1;
+        }
+
+        Ok(rewind)
+    }
+
+    /// Find the first synced block which refers to a reorged L1 block, if any.
+    async fn check(&mut self) -> Result<Option<u64>, ProviderError> {
+        // If an L1 block is still canonical, so are all of its ancestors, so we can work backwards
+        // from the most recent block and stop at the first one which hasn't changed.
+        let mut reorged = None;
+        for (&number, tracked) in self.tracked.iter().rev() {
+            match self.hash(number).await? {
+                Some(hash) if hash == tracked.hash => break,
+                Some(_) => reorged = Some(number),
+                None => {
+                    // The L1 provider is lagging, or in the middle of switching to a shorter chain.
+                    // We can't tell whether this block was reorged yet. Try again later.
+                    tracing::debug!("L1 block {number} is not available, skipping reorg check");
+                    return Ok(None);
+                }
+            }
+        }
+
+        let Some(number) = reorged else {
+            return Ok(None);
+        };
+        let height = self.tracked[&number].first_height;
+        tracing::warn!("L1 reorg at L1 block {number}, rewinding synced blocks to height {height}");
+        self.tracked.split_off(&number);
+        Ok(Some(height))
+    }
+
+    async fn hash(&self, number: u64) -> Result<Option<H256>, ProviderError> {
+        Ok(self
+            .l1
+            .get_block(number)
+            .await?
+            .and_then(|block| block.hash))
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ReorgError {
+    #[snafu(display("L1 provider error: {source}"))]
+    Provider { source: ProviderError },
+
+    #[snafu(display("storage error: {source}"))]
+    Storage { source: io::Error },
+}
+
+impl From<ProviderError> for ReorgError {
+    fn from(source: ProviderError) -> Self {
+        Self::Provider { source }
+    }
+}
+
+impl From<io::Error> for ReorgError {
+    fn from(source: io::Error) -> Self {
+        Self::Storage { source }
+    }
+}
+
+/// Periodically check for L1 reorgs affecting blocks in `store`.
+///
+/// Never returns.
+pub async fn watch_reorgs(
+    l1: Provider<Http>,
+    interval: Duration,
+    store: Arc<RwLock<BlockStore>>,
+    new_block: Arc<Event>,
+) {
+    let from = store.read().await.height();
+    let mut detector = ReorgDetector::new(l1, from);
+    loop {
+        match detector.update(&store).await {
+            // Wake up streams waiting on the end of the store, so they notice the rewind.
+            Ok(Some(_)) => {
+                new_block.notify(usize::MAX);
+            }
+            Ok(None) => {}
+            Err(err) => tracing::warn!("unable to check for L1 reorgs: {err}"),
+        }
+        sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query_service::PolygonZkevmBlock;
+    use async_compatibility_layer::logging::{setup_backtrace, setup_logging};
+    use ethers::types::U256;
+    use sequencer_utils::AnvilOptions;
+    use serde_json::Value;
+
+    fn block(height: u64, l1_block: u64) -> PolygonZkevmBlock {
+        PolygonZkevmBlock {
+            timestamp: height,
+            height,
+            l1_block,
+            transactions: "0x".into(),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_reorg_detection() {
+        setup_logging();
+        setup_backtrace();
+
+        let anvil = AnvilOptions::default().spawn().await;
+        let l1 = Provider::<Http>::try_from(anvil.url().as_str()).unwrap();
+        let mine = || async { l1.request::<_, Value>("evm_mine", ()).await.unwrap() };
+
+        mine().await;
+        let snapshot: U256 = l1.request("evm_snapshot", ()).await.unwrap();
+        mine().await;
+        mine().await;
+
+        let store = RwLock::new(BlockStore::memory());
+        {
+            let mut store = store.write().await;
+            for (height, l1_block) in [(0, 1), (1, 1), (2, 2), (3, 3)] {
+                store.append(&block(height, l1_block)).unwrap();
+            }
+        }
+        let mut detector = ReorgDetector::new(l1.clone(), 0);
+        assert_eq!(detector.update(&store).await.unwrap(), None);
+        assert_eq!(detector.tracked.len(), 3);
+
+        // Replace L1 blocks 2 and 3 with blocks with different timestamps, and thus different
+        // hashes.
+        let timestamp = l1.get_block(3).await.unwrap().unwrap().timestamp;
+        l1.request::<_, Value>("evm_revert", [snapshot])
+            .await
+            .unwrap();
+
+        // Until the L1 has blocks 2 and 3 again, we can't tell whether they were reorged, so
+        // nothing is rewound.
+        assert_eq!(detector.update(&store).await.unwrap(), None);
+        assert_eq!(store.read().await.height(), 4);
+
+        l1.request::<_, Value>("evm_setNextBlockTimestamp", [timestamp + U256::from(100)])
+            .await
+            .unwrap();
+        mine().await;
+        mine().await;
+
+        assert_eq!(detector.update(&store).await.unwrap(), Some(2));
+        assert_eq!(store.read().await.height(), 2);
+        assert_eq!(detector.update(&store).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,430 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Scripted workloads for the random client.
+//!
+//! A scenario is a TOML file describing a fixed sequence of steps, which is compiled into
+//! [`Operations`] and run like a generated load test plan. For example:
+//!
+//! ```toml
+//! [accounts]
+//! alice = "0x0000000000000000000000000000000000000a11"
+//! bob = { index = 1 }
+//!
+//! [[steps]]
+//! fund = { account = "alice", amount = 100 }
+//!
+//! [[steps]]
+//! fund = { account = "bob", amount = 1000000000000000 }
+//!
+//! [[steps]]
+//! transfer = { from = "bob", to = "alice", amount = 10 }
+//!
+//! [[steps]]
+//! repeat = { count = 3, var = "i", steps = [
+//!     { fund = { account = "user${i}", amount = "1${i}" } },
+//!     { wait = 500 },
+//! ] }
+//!
+//! [[steps]]
+//! assert = { account = "alice", balance = ">= 100" }
+//! ```
+//!
+//! `fund` steps send from the funded account running the scenario, while `transfer` steps send from
+//! one named account to another. Accounts are referred to by name: names declared in `[accounts]`
+//! map to the given addresses, or to the account with the given index derived from the mnemonic
+//! running the scenario, and any other name maps to an address derived from the name itself. Only
+//! accounts declared with an index can send transfers, and they must be funded first, including for
+//! gas.
+//!
+//! Amounts are in wei. TOML integers are limited to 64-bit signed values, so amounts of about 9.2
+//! ETH (2^63 wei) or more must be written as strings, like `amount = "20000000000000000000"`;
+//! larger integers are rejected when the scenario is parsed. Inside a `repeat` step, `${var}` is
+//! replaced by the iteration number (starting from 0) in account names and amounts. An `assert`
+//! step is checked once all previous transfers have completed.
+
+#![cfg(any(test, feature = "testing"))]
+
+use crate::{BalanceAssertion, Comparison, Operation, Operations, Transfer};
+use ethers::{
+    prelude::{MnemonicBuilder, Signer as _},
+    signers::coins_bip39::English,
+    types::{Address, U256},
+    utils::keccak256,
+};
+use serde::Deserialize;
+use snafu::Snafu;
+use std::{collections::HashMap, fs, path::Path, time::Duration};
+
+#[derive(Debug, Snafu)]
+pub enum ScenarioError {
+    #[snafu(display("unable to read scenario: {source}"))]
+    Read { source: std::io::Error },
+
+    #[snafu(display("unable to parse scenario: {source}"))]
+    Parse { source: toml::de::Error },
+
+    #[snafu(display("invalid address for account {name}: {address:?}"))]
+    InvalidAddress { name: String, address: String },
+
+    #[snafu(display("unable to derive account {name} with index {index}: {reason}"))]
+    InvalidIndex {
+        name: String,
+        index: u32,
+        reason: String,
+    },
+
+    #[snafu(display("account {name} cannot send transfers, declare it with an index"))]
+    NoSigner { name: String },
+
+    #[snafu(display("invalid amount {amount:?}"))]
+    InvalidAmount { amount: String },
+
+    #[snafu(display("invalid balance check {check:?}, expected e.g. \">= 100\""))]
+    InvalidCheck { check: String },
+
+    #[snafu(display("loop variable {var} shadows an enclosing loop variable"))]
+    ShadowedVariable { var: String },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    /// Named accounts.
+    #[serde(default)]
+    pub accounts: HashMap<String, Account>,
+    pub steps: Vec<Step>,
+}
+
+/// A named account, either an address or the index of an account derived from the mnemonic.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Account {
+    Address(String),
+    Index { index: u32 },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    /// Transfer `amount` wei to `account`.
+    Fund { account: String, amount: Amount },
+    /// Transfer `amount` wei from the account `from` to the account `to`.
+    Transfer {
+        from: String,
+        to: String,
+        amount: Amount,
+    },
+    /// Wait for a number of milliseconds.
+    Wait(u64),
+    /// Check the balance of `account`, e.g. `balance = ">= 100"`.
+    Assert { account: String, balance: String },
+    /// Run `steps` `count` times, with `${var}` replaced by the iteration number.
+    Repeat {
+        count: u64,
+        #[serde(default = "default_loop_var")]
+        var: String,
+        steps: Vec<Step>,
+    },
+}
+
+fn default_loop_var() -> String {
+    "i".into()
+}
+
+/// An amount of wei, either as a number or as a string which may use loop variables.
+///
+/// Numbers are limited to TOML's 64-bit signed integers. Larger amounts can be given as strings,
+/// which are parsed as 256-bit decimal numbers.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Amount {
+    Number(u64),
+    Expr(String),
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, ScenarioError> {
+        let contents = fs::read_to_string(path).map_err(|source| ScenarioError::Read { source })?;
+        toml::from_str(&contents).map_err(|source| ScenarioError::Parse { source })
+    }
+
+    /// Compile the scenario into a plan for the random client.
+    ///
+    /// Accounts declared with an index are derived from `mnemonic`, which should be the mnemonic of
+    /// the account running the scenario.
+    pub fn compile(&self, mnemonic: &str) -> Result<Operations, ScenarioError> {
+        let mut compiler = Compiler {
+            accounts: &self.accounts,
+            mnemonic,
+            vars: vec![],
+            operations: vec![],
+        };
+        compiler.compile(&self.steps)?;
+        Ok(Operations(compiler.operations))
+    }
+}
+
+struct Compiler<'a> {
+    accounts: &'a HashMap<String, Account>,
+    mnemonic: &'a str,
+    /// Loop variables in scope, innermost last.
+    vars: Vec<(String, u64)>,
+    operations: Vec<Operation>,
+}
+
+impl<'a> Compiler<'a> {
+    fn compile(&mut self, steps: &[Step]) -> Result<(), ScenarioError> {
+        for step in steps {
+            match step {
+                Step::Fund { account, amount } => {
+                    let to = self.account(account)?;
+                    let amount = self.amount(amount)?;
+                    self.operations.push(Operation::Transfer(Transfer {
+                        from: None,
+                        to,
+                        amount,
+                    }));
+                }
+                Step::Transfer { from, to, amount } => {
+                    let from = self.sender(from)?;
+                    let to = self.account(to)?;
+                    let amount = self.amount(amount)?;
+                    self.operations.push(Operation::Transfer(Transfer {
+                        from: Some(from),
+                        to,
+                        amount,
+                    }));
+                }
+                Step::Wait(ms) => self
+                    .operations
+                    .push(Operation::Wait(Duration::from_millis(*ms))),
+                Step::Assert { account, balance } => {
+                    let invalid = || ScenarioError::InvalidCheck {
+                        check: balance.clone(),
+                    };
+                    let (comparison, amount) =
+                        balance.trim().split_once(' ').ok_or_else(invalid)?;
+                    let comparison: Comparison = comparison.parse().map_err(|_| invalid())?;
+                    let amount = self.amount(&Amount::Expr(amount.trim().into()))?;
+                    let name = self.substitute(account);
+                    self.operations.push(Operation::Assert(BalanceAssertion {
+                        account: self.account(account)?,
+                        name,
+                        comparison,
+                        amount,
+                    }));
+                }
+                Step::Repeat { count, var, steps } => {
+                    if self.vars.iter().any(|(name, _)| name == var) {
+                        return Err(ScenarioError::ShadowedVariable { var: var.clone() });
+                    }
+                    for i in 0..*count {
+                        self.vars.push((var.clone(), i));
+                        let res = self.compile(steps);
+                        self.vars.pop();
+                        res?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace loop variables in `s` with their current values.
+    fn substitute(&self, s: &str) -> String {
+        self.vars.iter().fold(s.to_string(), |s, (var, value)| {
+            s.replace(&format!("${{{var}}}"), &value.to_string())
+        })
+    }
+
+    fn account(&self, name: &str) -> Result<Address, ScenarioError> {
+        let name = self.substitute(name);
+        match self.accounts.get(&name) {
+            Some(Account::Address(address)) => {
+                address.parse().map_err(|_| ScenarioError::InvalidAddress {
+                    name,
+                    address: address.clone(),
+                })
+            }
+            Some(Account::Index { index }) => MnemonicBuilder::<English>::default()
+                .phrase(self.mnemonic)
+                .index(*index)
+                .and_then(|builder| builder.build())
+                .map(|wallet| wallet.address())
+                .map_err(|err| ScenarioError::InvalidIndex {
+                    name,
+                    index: *index,
+                    reason: err.to_string(),
+                }),
+            // Derive an address from the name, so that scenarios can create as many accounts as
+            // they need without declaring them.
+            None => Ok(Address::from_slice(&keccak256(name.as_bytes())[12..])),
+        }
+    }
+
+    /// The index of the account `name`, which sends a transfer.
+    fn sender(&self, name: &str) -> Result<u32, ScenarioError> {
+        let name = self.substitute(name);
+        match self.accounts.get(&name) {
+            Some(Account::Index { index }) => Ok(*index),
+            _ => Err(ScenarioError::NoSigner { name }),
+        }
+    }
+
+    fn amount(&self, amount: &Amount) -> Result<U256, ScenarioError> {
+        match amount {
+            Amount::Number(amount) => Ok((*amount).into()),
+            Amount::Expr(expr) => {
+                let amount = self.substitute(expr);
+                U256::from_dec_str(&amount).map_err(|_| ScenarioError::InvalidAmount { amount })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MNEMONIC: &str = "test test test test test test test test test test test junk";
+
+    #[test]
+    fn test_compile_scenario() {
+        let scenario: Scenario = toml::from_str(
+            r#"
+            [accounts]
+            alice = "0x0000000000000000000000000000000000000a11"
+            bob = { index = 1 }
+
+            [[steps]]
+            fund = { account = "alice", amount = 100 }
+
+            [[steps]]
+            fund = { account = "bob", amount = 50 }
+
+            [[steps]]
+            transfer = { from = "bob", to = "alice", amount = 20 }
+
+            [[steps]]
+            repeat = { count = 2, steps = [
+                { fund = { account = "user${i}", amount = "1${i}" } },
+                { wait = 500 },
+            ] }
+
+            [[steps]]
+            assert = { account = "alice", balance = ">= 100" }
+            "#,
+        )
+        .unwrap();
+        let alice: Address = "0x0000000000000000000000000000000000000a11"
+            .parse()
+            .unwrap();
+        let bob: Address = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8"
+            .parse()
+            .unwrap();
+        let user = |i: u64| Address::from_slice(&keccak256(format!("user{i}"))[12..]);
+        let operations = scenario.compile(MNEMONIC).unwrap();
+        assert_eq!(operations.senders(), [1].into());
+        assert_eq!(
+            operations.0,
+            vec![
+                Operation::Transfer(Transfer {
+                    from: None,
+                    to: alice,
+                    amount: 100.into()
+                }),
+                Operation::Transfer(Transfer {
+                    from: None,
+                    to: bob,
+                    amount: 50.into()
+                }),
+                Operation::Transfer(Transfer {
+                    from: Some(1),
+                    to: alice,
+                    amount: 20.into()
+                }),
+                Operation::Transfer(Transfer {
+                    from: None,
+                    to: user(0),
+                    amount: 10.into()
+                }),
+                Operation::Wait(Duration::from_millis(500)),
+                Operation::Transfer(Transfer {
+                    from: None,
+                    to: user(1),
+                    amount: 11.into()
+                }),
+                Operation::Wait(Duration::from_millis(500)),
+                Operation::Assert(BalanceAssertion {
+                    name: "alice".into(),
+                    account: alice,
+                    comparison: Comparison::Ge,
+                    amount: 100.into(),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_scenario() {
+        let scenario: Scenario = toml::from_str(
+            r#"
+            [[steps]]
+            assert = { account = "alice", balance = "about 100" }
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            scenario.compile(MNEMONIC),
+            Err(ScenarioError::InvalidCheck { .. })
+        ));
+
+        // Only accounts derived from the mnemonic can send transfers.
+        let scenario: Scenario = toml::from_str(
+            r#"
+            [accounts]
+            alice = "0x0000000000000000000000000000000000000a11"
+
+            [[steps]]
+            transfer = { from = "alice", to = "bob", amount = 1 }
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            scenario.compile(MNEMONIC),
+            Err(ScenarioError::NoSigner { .. })
+        ));
+    }
+
+    #[test]
+    fn test_large_amount() {
+        // Amounts which don't fit in a TOML integer can be given as strings.
+        let scenario: Scenario = toml::from_str(
+            r#"
+            [[steps]]
+            fund = { account = "alice", amount = "20000000000000000000" }
+            "#,
+        )
+        .unwrap();
+        let operations = scenario.compile(MNEMONIC).unwrap();
+        assert!(matches!(
+            &operations.0[..],
+            [Operation::Transfer(Transfer { amount, .. })]
+                if *amount == U256::from_dec_str("20000000000000000000").unwrap()
+        ));
+
+        // As numbers, they are rejected rather than truncated.
+        toml::from_str::<Scenario>(
+            r#"
+            [[steps]]
+            fund = { account = "alice", amount = 20000000000000000000 }
+            "#,
+        )
+        .unwrap_err();
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Graceful shutdown of the adaptor services.
+//!
+//! When the adaptor receives SIGTERM or SIGINT, the servers stop accepting connections, JSON-RPC
+//! and query service requests which are already being handled are allowed to finish (up to a
+//! timeout), and the block stores are flushed before the process exits. A second signal exits
+//! immediately.
+
+use async_std::future::timeout;
+use event_listener::Event;
+use futures::StreamExt;
+use http_types::StatusCode;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_async_std::Signals;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Coordinates shutdown between the signal handler and the servers.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    triggered: AtomicBool,
+    /// Notified when shutdown is triggered.
+    on_trigger: Event,
+    in_flight: AtomicUsize,
+    /// Notified whenever a request finishes.
+    on_request_finished: Event,
+}
+
+impl Shutdown {
+    /// Start shutting down.
+    pub fn trigger(&self) {
+        self.inner.triggered.store(true, Ordering::SeqCst);
+        self.inner.on_trigger.notify(usize::MAX);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.inner.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Wait until shutdown is triggered.
+    pub async fn wait(&self) {
+        loop {
+            // Start listening before checking the flag, so we can't miss the notification.
+            let listener = self.inner.on_trigger.listen();
+            if self.is_triggered() {
+                return;
+            }
+            listener.await;
+        }
+    }
+
+    /// Record the start of a request, which is finished when the returned guard is dropped.
+    pub fn start_request(&self) -> RequestGuard {
+        self.inner.in_flight.fetch_add(1, Ordering::SeqCst);
+        RequestGuard {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// The number of requests which have started but not finished.
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Wait for all in-flight requests to finish, for at most `max_wait`.
+    ///
+    /// Returns `false` if there were still requests in flight after `max_wait`.
+    pub async fn drain(&self, max_wait: Duration) -> bool {
+        let drained = async {
+            loop {
+                let listener = self.inner.on_request_finished.listen();
+                if self.in_flight() == 0 {
+                    return;
+                }
+                listener.await;
+            }
+        };
+        timeout(max_wait, drained).await.is_ok()
+    }
+}
+
+/// Marks a request as in flight until dropped.
+#[derive(Debug)]
+pub struct RequestGuard {
+    inner: Arc<Inner>,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.inner.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.inner.on_request_finished.notify(usize::MAX);
+    }
+}
+
+/// Trigger `shutdown` on the first SIGTERM or SIGINT, and exit immediately on the second.
+pub async fn handle_signals(shutdown: Shutdown) {
+    let mut signals = match Signals::new([SIGTERM, SIGINT]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            tracing::error!("unable to register signal handlers: {err}");
+            return;
+        }
+    };
+    if let Some(signal) = signals.next().await {
+        tracing::info!("received signal {signal}, shutting down");
+        shutdown.trigger();
+    }
+    if let Some(signal) = signals.next().await {
+        tracing::warn!("received signal {signal} during shutdown, exiting immediately");
+        std::process::exit(1);
+    }
+}
+
+/// Middleware tracking in-flight requests, and rejecting new requests once shutdown has started.
+#[derive(Clone, Debug)]
+pub struct DrainRequests {
+    shutdown: Shutdown,
+}
+
+impl DrainRequests {
+    pub fn new(shutdown: Shutdown) -> Self {
+        Self { shutdown }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> tide::Middleware<State> for DrainRequests {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        if self.shutdown.is_triggered() {
+            // Ask the client not to reuse this connection, since the server is going away.
+            return Ok(tide::Response::builder(StatusCode::ServiceUnavailable)
+                .header("Connection", "close")
+                .body("shutting down")
+                .build());
+        }
+        let _guard = self.shutdown.start_request();
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::task::spawn;
+
+    #[async_std::test]
+    async fn test_drain() {
+        let shutdown = Shutdown::default();
+        let guard = shutdown.start_request();
+        assert_eq!(shutdown.in_flight(), 1);
+
+        // Waiting for shutdown returns once it is triggered.
+        let waiter = spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.wait().await }
+        });
+        shutdown.trigger();
+        waiter.await;
+
+        // Draining times out while the request is in flight, and succeeds once it finishes.
+        assert!(!shutdown.drain(Duration::from_millis(100)).await);
+        let drain = spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.drain(Duration::from_secs(10)).await }
+        });
+        drop(guard);
+        assert!(drain.await);
+        assert_eq!(shutdown.in_flight(), 0);
+    }
+}
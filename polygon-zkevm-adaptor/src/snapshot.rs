@@ -0,0 +1,564 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Snapshots of the adaptor's persisted state.
+//!
+//! `adaptor snapshot DIR` copies the block store of each configured rollup to `DIR`, along with a
+//! manifest recording the L1 state the blocks refer to: the L1 chain and the hash of its latest
+//! block, and, if the HotShot contract is configured, the commitment it holds for the last stored
+//! block. `adaptor restore DIR` checks that the configured L1 still matches the manifest, and copies
+//! the block stores into the adaptor's storage path, so that the adaptor resumes syncing where the
+//! snapshot left off instead of replaying the whole history from the sequencer.
+//!
+//! The snapshot does not include the state of the L1 or of the zkEVM nodes. These must be restored
+//! separately, and the manifest lets `restore` check that they were.
+
+use crate::{storage::BlockStore, Options};
+use clap::Subcommand;
+use ethers::{
+    contract::ContractError,
+    providers::{Http, Middleware, Provider, ProviderError},
+    types::{Address, BlockNumber, H256, U256},
+};
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use zkevm_contract_bindings::i_hot_shot::IHotShot;
+
+/// Version of the snapshot format, recorded in the manifest.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Directory in the storage path in which restored stores are staged before being moved into place.
+const RESTORE_DIR: &str = "restore";
+
+#[derive(Debug, Snafu)]
+pub enum SnapshotError {
+    #[snafu(display("snapshots require a storage path for the adaptor"))]
+    NoStoragePath,
+
+    #[snafu(display("{}: {source}", path.display()))]
+    Io { path: PathBuf, source: io::Error },
+
+    #[snafu(display("snapshot directory {} is not empty", path.display()))]
+    DirectoryNotEmpty { path: PathBuf },
+
+    #[snafu(display("invalid snapshot manifest: {source}"))]
+    Manifest { source: serde_json::Error },
+
+    #[snafu(display("unsupported snapshot version {version} (expected {SNAPSHOT_VERSION})"))]
+    UnsupportedVersion { version: u32 },
+
+    #[snafu(display("L1 provider error: {source}"))]
+    Provider { source: ProviderError },
+
+    #[snafu(display("unable to read commitment from HotShot contract: {source}"))]
+    Contract {
+        source: ContractError<Provider<Http>>,
+    },
+
+    #[snafu(display(
+        "snapshot has rollups {snapshot:?}, but rollups {configured:?} are configured"
+    ))]
+    RollupMismatch {
+        snapshot: Vec<u64>,
+        configured: Vec<u64>,
+    },
+
+    #[snafu(display("L1 does not match snapshot: {reason}"))]
+    L1Mismatch { reason: String },
+
+    #[snafu(display("block store for rollup {chain_id} already has {height} blocks"))]
+    StoreNotEmpty { chain_id: u64, height: u64 },
+
+    #[snafu(display(
+        "block store for rollup {chain_id} has {actual} blocks, but the snapshot has {expected}"
+    ))]
+    HeightMismatch {
+        chain_id: u64,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> SnapshotError + '_ {
+    move |source| SnapshotError::Io {
+        path: path.into(),
+        source,
+    }
+}
+
+/// A snapshot or restore requested on the command line, run instead of the adaptor itself.
+#[derive(Clone, Debug, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Save the block stores, and a manifest of the L1 state they refer to, in DIR.
+    Snapshot {
+        /// Directory for the snapshot, which must be empty or not exist.
+        #[clap(value_name = "DIR")]
+        dir: PathBuf,
+    },
+    /// Restore the block stores from the snapshot in DIR.
+    Restore {
+        /// Directory containing the snapshot.
+        #[clap(value_name = "DIR")]
+        dir: PathBuf,
+    },
+}
+
+impl Command {
+    pub async fn run(&self, opt: &Options) -> Result<Manifest, SnapshotError> {
+        match self {
+            Self::Snapshot { dir } => snapshot(opt, dir).await,
+            Self::Restore { dir } => restore(opt, dir).await,
+        }
+    }
+}
+
+/// Description of a snapshot, stored alongside the block stores.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Manifest {
+    pub version: u32,
+    /// Time at which the snapshot was taken, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub l1: L1Reference,
+    pub hotshot_address: Option<Address>,
+    pub rollups: Vec<RollupSnapshot>,
+}
+
+/// The L1 block which was the latest block when a snapshot was taken.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct L1Reference {
+    pub chain_id: U256,
+    pub block_number: u64,
+    pub block_hash: H256,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RollupSnapshot {
+    pub chain_id: u64,
+    /// Number of blocks in the block store.
+    pub height: u64,
+    /// The L1 block referenced by the last block in the store.
+    pub l1_block: Option<u64>,
+    /// The commitment to the last block in the store, as read from the HotShot contract.
+    pub hotshot_commitment: Option<U256>,
+}
+
+/// Take a snapshot of the adaptor state in `dir`, which must be empty or not exist.
+///
+/// The adaptor may keep running while the snapshot is taken.
+pub async fn snapshot(opt: &Options, dir: &Path) -> Result<Manifest, SnapshotError> {
+    let storage_path = opt
+        .storage_path
+        .as_ref()
+        .ok_or(SnapshotError::NoStoragePath)?;
+    if fs::read_dir(dir).map_or(false, |mut entries| entries.next().is_some()) {
+        return Err(SnapshotError::DirectoryNotEmpty { path: dir.into() });
+    }
+
+    // Copy the stores before reading the L1, so that every L1 block they refer to is at or below
+    // the L1 reference.
+    let mut rollups = copy_stores(storage_path, dir, &chain_ids(opt))?;
+
+    let provider = l1_provider(opt);
+    let l1 = l1_reference(&provider).await?;
+    if let Some(address) = opt.hotshot_address {
+        let hotshot = IHotShot::new(address, Arc::new(provider));
+        for rollup in &mut rollups {
+            rollup.hotshot_commitment = hotshot_commitment(&hotshot, rollup.height).await?;
+        }
+    }
+
+    let manifest = Manifest {
+        version: SNAPSHOT_VERSION,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        l1,
+        hotshot_address: opt.hotshot_address,
+        rollups,
+    };
+    write_manifest(dir, &manifest)?;
+    tracing::info!(
+        "snapshot of {} rollups at L1 block {} saved to {}",
+        manifest.rollups.len(),
+        manifest.l1.block_number,
+        dir.display()
+    );
+    Ok(manifest)
+}
+
+/// Restore the adaptor state from a snapshot in `dir`.
+///
+/// The adaptor must not be running, and its block stores must be empty.
+pub async fn restore(opt: &Options, dir: &Path) -> Result<Manifest, SnapshotError> {
+    let storage_path = opt
+        .storage_path
+        .as_ref()
+        .ok_or(SnapshotError::NoStoragePath)?;
+    let manifest = read_manifest(dir)?;
+    check_rollups(&manifest, &chain_ids(opt))?;
+
+    // Make sure the L1 has been restored to (a descendant of) the state the snapshot refers to,
+    // since the zkEVM node will look for the L1 blocks referenced by the restored blocks.
+    let provider = l1_provider(opt);
+    let chain_id = provider
+        .get_chainid()
+        .await
+        .map_err(|source| SnapshotError::Provider { source })?;
+    if chain_id != manifest.l1.chain_id {
+        return Err(SnapshotError::L1Mismatch {
+            reason: format!("expected chain ID {}, got {chain_id}", manifest.l1.chain_id),
+        });
+    }
+    let block = provider
+        .get_block(manifest.l1.block_number)
+        .await
+        .map_err(|source| SnapshotError::Provider { source })?;
+    let hash = block.and_then(|block| block.hash);
+    if hash != Some(manifest.l1.block_hash) {
+        return Err(SnapshotError::L1Mismatch {
+            reason: format!(
+                "expected block {} to have hash {:?}, got {hash:?}",
+                manifest.l1.block_number, manifest.l1.block_hash
+            ),
+        });
+    }
+    if let Some(address) = manifest.hotshot_address {
+        if let Some(configured) = opt
+            .hotshot_address
+            .filter(|&configured| configured != address)
+        {
+            return Err(SnapshotError::L1Mismatch {
+                reason: format!(
+                    "snapshot was taken with HotShot contract {address:?}, \
+                     but {configured:?} is configured"
+                ),
+            });
+        }
+        let hotshot = IHotShot::new(address, Arc::new(provider));
+        for rollup in &manifest.rollups {
+            let Some(expected) = rollup.hotshot_commitment else {
+                continue;
+            };
+            let actual = hotshot_commitment(&hotshot, rollup.height).await?;
+            if actual != Some(expected) {
+                return Err(SnapshotError::L1Mismatch {
+                    reason: format!(
+                        "expected HotShot commitment {expected} for block {}, got {actual:?}",
+                        rollup.height - 1
+                    ),
+                });
+            }
+        }
+    }
+
+    restore_stores(dir, storage_path, &manifest)?;
+    tracing::info!(
+        "restored {} rollups from snapshot at L1 block {}",
+        manifest.rollups.len(),
+        manifest.l1.block_number
+    );
+    Ok(manifest)
+}
+
+fn chain_ids(opt: &Options) -> Vec<u64> {
+    opt.rollups().iter().map(|opt| opt.l2_chain_id).collect()
+}
+
+fn l1_provider(opt: &Options) -> Provider<Http> {
+    Provider::try_from(opt.l1_provider.as_str()).unwrap()
+}
+
+async fn l1_reference(provider: &Provider<Http>) -> Result<L1Reference, SnapshotError> {
+    let chain_id = provider
+        .get_chainid()
+        .await
+        .map_err(|source| SnapshotError::Provider { source })?;
+    let block = provider
+        .get_block(BlockNumber::Latest)
+        .await
+        .map_err(|source| SnapshotError::Provider { source })?;
+    match block.and_then(|block| Some((block.number?, block.hash?))) {
+        Some((number, hash)) => Ok(L1Reference {
+            chain_id,
+            block_number: number.as_u64(),
+            block_hash: hash,
+        }),
+        None => Err(SnapshotError::L1Mismatch {
+            reason: "latest block is not available".into(),
+        }),
+    }
+}
+
+/// The commitment in the HotShot contract to the last block in a store of height `height`.
+async fn hotshot_commitment(
+    hotshot: &IHotShot<Provider<Http>>,
+    height: u64,
+) -> Result<Option<U256>, SnapshotError> {
+    if height == 0 {
+        return Ok(None);
+    }
+    let commitment = hotshot
+        .commitments((height - 1).into())
+        .call()
+        .await
+        .map_err(|source| SnapshotError::Contract { source })?;
+    Ok((!commitment.is_zero()).then_some(commitment))
+}
+
+/// Copy the block store of each of `chain_ids` from `storage_path` to `dir`.
+///
+/// A rollup which has never been synced has no store yet, and is saved as an empty store.
+fn copy_stores(
+    storage_path: &Path,
+    dir: &Path,
+    chain_ids: &[u64],
+) -> Result<Vec<RollupSnapshot>, SnapshotError> {
+    chain_ids
+        .iter()
+        .map(|&chain_id| {
+            let src = storage_path.join(chain_id.to_string());
+            let dst = dir.join(chain_id.to_string());
+            let store = match BlockStore::copy(&src, &dst) {
+                Err(err) if err.kind() == ErrorKind::NotFound => BlockStore::open(&dst),
+                res => res,
+            }
+            .map_err(io_error(&src))?;
+            let l1_block = match store.height() {
+                0 => None,
+                height => store
+                    .get(height - 1)
+                    .map_err(io_error(&dst))?
+                    .map(|block| block.l1_block),
+            };
+            Ok(RollupSnapshot {
+                chain_id,
+                height: store.height(),
+                l1_block,
+                hotshot_commitment: None,
+            })
+        })
+        .collect()
+}
+
+/// Copy the block stores in the snapshot `dir` into `storage_path`.
+fn restore_stores(
+    dir: &Path,
+    storage_path: &Path,
+    manifest: &Manifest,
+) -> Result<(), SnapshotError> {
+    // Check all the stores before touching any, so that a failed restore leaves them as they were.
+    for rollup in &manifest.rollups {
+        let path = storage_path.join(rollup.chain_id.to_string());
+        let height = BlockStore::open(&path).map_err(io_error(&path))?.height();
+        if height > 0 {
+            return Err(SnapshotError::StoreNotEmpty {
+                chain_id: rollup.chain_id,
+                height,
+            });
+        }
+    }
+
+    // Copy the stores to a staging directory, and only move them into place once every copy has
+    // been checked.
+    let staging = storage_path.join(RESTORE_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(io_error(&staging))?;
+    }
+    let res = stage_stores(dir, &staging, manifest).and_then(|()| {
+        for rollup in &manifest.rollups {
+            let src = staging.join(rollup.chain_id.to_string());
+            let dst = storage_path.join(rollup.chain_id.to_string());
+            BlockStore::rename(&src, &dst).map_err(io_error(&dst))?;
+        }
+        Ok(())
+    });
+    if let Err(err) = fs::remove_dir_all(&staging) {
+        tracing::warn!("failed to remove {}: {err}", staging.display());
+    }
+    res
+}
+
+/// Copy the block stores in the snapshot `dir` to `staging`, checking that they are complete.
+fn stage_stores(dir: &Path, staging: &Path, manifest: &Manifest) -> Result<(), SnapshotError> {
+    for rollup in &manifest.rollups {
+        let src = dir.join(rollup.chain_id.to_string());
+        let dst = staging.join(rollup.chain_id.to_string());
+        let mut store = BlockStore::copy(&src, &dst).map_err(io_error(&src))?;
+        store.flush().map_err(io_error(&dst))?;
+        if store.height() != rollup.height {
+            return Err(SnapshotError::HeightMismatch {
+                chain_id: rollup.chain_id,
+                expected: rollup.height,
+                actual: store.height(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Check that the rollups in a snapshot are exactly the configured rollups.
+fn check_rollups(manifest: &Manifest, chain_ids: &[u64]) -> Result<(), SnapshotError> {
+    let snapshot: BTreeSet<_> = manifest.rollups.iter().map(|r| r.chain_id).collect();
+    let configured: BTreeSet<_> = chain_ids.iter().copied().collect();
+    if snapshot != configured {
+        return Err(SnapshotError::RollupMismatch {
+            snapshot: snapshot.into_iter().collect(),
+            configured: configured.into_iter().collect(),
+        });
+    }
+    Ok(())
+}
+
+fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<(), SnapshotError> {
+    let path = dir.join(MANIFEST_FILE);
+    let json =
+        serde_json::to_vec_pretty(manifest).map_err(|source| SnapshotError::Manifest { source })?;
+    fs::write(&path, json).map_err(io_error(&path))
+}
+
+fn read_manifest(dir: &Path) -> Result<Manifest, SnapshotError> {
+    let path = dir.join(MANIFEST_FILE);
+    let json = fs::read(&path).map_err(io_error(&path))?;
+    let manifest: Manifest =
+        serde_json::from_slice(&json).map_err(|source| SnapshotError::Manifest { source })?;
+    if manifest.version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion {
+            version: manifest.version,
+        });
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query_service::PolygonZkevmBlock;
+    use clap::{CommandFactory, Parser};
+    use tempfile::TempDir;
+
+    fn block(height: u64) -> PolygonZkevmBlock {
+        PolygonZkevmBlock {
+            timestamp: 1000 + height,
+            height,
+            l1_block: height / 2,
+            transactions: format!("0x{height:02x}"),
+        }
+    }
+
+    #[test]
+    fn test_command() {
+        let args = [
+            "adaptor",
+            "--sequencer-url",
+            "http://sequencer:50000",
+            "--l1-provider",
+            "http://l1:8545",
+        ];
+        let parse = |command: &[&str]| {
+            Options::try_parse_from(args.iter().chain(command)).map(|opt| opt.command)
+        };
+
+        assert_eq!(parse(&[]).unwrap(), None);
+        assert_eq!(
+            parse(&["snapshot", "snap"]).unwrap(),
+            Some(Command::Snapshot { dir: "snap".into() })
+        );
+        assert_eq!(
+            parse(&["restore", "snap"]).unwrap(),
+            Some(Command::Restore { dir: "snap".into() })
+        );
+        parse(&["restore"]).unwrap_err();
+
+        let help = Options::command().render_help().to_string();
+        assert!(help.contains("snapshot"), "{help}");
+        assert!(help.contains("restore"), "{help}");
+    }
+
+    #[test]
+    fn test_snapshot_stores() {
+        let storage = TempDir::new().unwrap();
+        let snapshot = TempDir::new().unwrap();
+        let chain_ids = [1001, 1002];
+        // Rollup 1002 has never been synced, so it has no store.
+        let mut store = BlockStore::open(&storage.path().join("1001")).unwrap();
+        for i in 0..3 {
+            store.append(&block(i)).unwrap();
+        }
+
+        let rollups = copy_stores(storage.path(), snapshot.path(), &chain_ids).unwrap();
+        assert_eq!(
+            rollups,
+            [
+                RollupSnapshot {
+                    chain_id: 1001,
+                    height: 3,
+                    l1_block: Some(1),
+                    hotshot_commitment: None,
+                },
+                RollupSnapshot {
+                    chain_id: 1002,
+                    height: 0,
+                    l1_block: None,
+                    hotshot_commitment: None,
+                },
+            ]
+        );
+        // Blocks added after the snapshot don't end up in it.
+        store.append(&block(3)).unwrap();
+
+        let manifest = Manifest {
+            version: SNAPSHOT_VERSION,
+            timestamp: 0,
+            l1: L1Reference {
+                chain_id: 1337.into(),
+                block_number: 10,
+                block_hash: H256::repeat_byte(1),
+            },
+            hotshot_address: None,
+            rollups,
+        };
+        write_manifest(snapshot.path(), &manifest).unwrap();
+        assert_eq!(read_manifest(snapshot.path()).unwrap(), manifest);
+        check_rollups(&manifest, &chain_ids).unwrap();
+        check_rollups(&manifest, &[1001]).unwrap_err();
+
+        // Restoring into a non-empty store fails.
+        restore_stores(snapshot.path(), storage.path(), &manifest).unwrap_err();
+
+        // A restore which fails for one rollup doesn't restore any of them.
+        let restored = TempDir::new().unwrap();
+        let mut truncated = manifest.clone();
+        truncated.rollups[1].height = 1;
+        assert!(matches!(
+            restore_stores(snapshot.path(), restored.path(), &truncated),
+            Err(SnapshotError::HeightMismatch { chain_id: 1002, .. })
+        ));
+        let store = BlockStore::open(&restored.path().join("1001")).unwrap();
+        assert_eq!(store.height(), 0);
+        assert!(!restored.path().join(RESTORE_DIR).exists());
+
+        restore_stores(snapshot.path(), restored.path(), &manifest).unwrap();
+        let store = BlockStore::open(&restored.path().join("1001")).unwrap();
+        assert_eq!(store.height(), 3);
+        for i in 0..3 {
+            assert_eq!(store.get(i).unwrap(), Some(block(i)));
+        }
+        let store = BlockStore::open(&restored.path().join("1002")).unwrap();
+        assert_eq!(store.height(), 0);
+    }
+}
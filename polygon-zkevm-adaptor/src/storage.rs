@@ -0,0 +1,424 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Storage for blocks translated by the query service adaptor.
+//!
+//! Blocks are stored in order of height, so the number of stored blocks is also the height of the
+//! next block to sync. When the adaptor is given a storage path, blocks are persisted in an
+//! append-only log file in that directory, so that a restarted adaptor can continue syncing where
+//! it left off, instead of refetching the whole history from the sequencer. Each record in the log
+//! is a little-endian `u64` length followed by the bincode-serialized block. A record which was
+//! only partially written (for example, because the adaptor was killed mid-write) is discarded when
+//! the log is reopened.
+//!
+//! An embedded database such as SQLite or sled would buy nothing for this access pattern. Blocks
+//! are only ever appended, read by height, or removed from the end of the store after an L1 reorg,
+//! and the height of the store is the last synced height, so there is no other state to keep
+//! consistent with the blocks. Each append is synced to disk before it is acknowledged, so the only
+//! damage a crash can do is a torn last record, which [`BlockStore::open`] detects and discards. A
+//! plain log avoids a native SQLite dependency in the adaptor image, and a store whose on-disk
+//! format is not yet stable, while keeping the file easy to copy for [snapshots](crate::snapshot).
+
+use crate::query_service::PolygonZkevmBlock;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+const BLOCKS_FILE: &str = "blocks.bin";
+
+/// Move a store left directly in `storage_path` by an older version of the adaptor, which only
+/// served a single rollup, to the directory `dir` for that rollup.
+///
+/// Returns `true` if a store was moved. An old store is left alone if there is already a store in
+/// `dir`.
+pub fn migrate_legacy_store(storage_path: &Path, dir: &Path) -> io::Result<bool> {
+    let legacy = storage_path.join(BLOCKS_FILE);
+    if !legacy.exists() {
+        return Ok(false);
+    }
+    let target = dir.join(BLOCKS_FILE);
+    if target.exists() {
+        tracing::warn!(
+            "ignoring block store {} from an older version of the adaptor, since {} exists",
+            legacy.display(),
+            target.display()
+        );
+        return Ok(false);
+    }
+    fs::create_dir_all(dir)?;
+    fs::rename(&legacy, &target)?;
+    tracing::info!(
+        "moved block store {} to {}",
+        legacy.display(),
+        target.display()
+    );
+    Ok(true)
+}
+
+#[derive(Debug)]
+pub struct BlockStore {
+    backend: Backend,
+    /// Number of times blocks have been removed from the end of the store.
+    rewinds: u64,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Memory(Vec<PolygonZkevmBlock>),
+    File {
+        file: File,
+        /// Offset of each record in `file`, indexed by block height.
+        offsets: Vec<u64>,
+        /// Offset of the end of the last complete record.
+        end: u64,
+    },
+}
+
+impl BlockStore {
+    /// A store which keeps blocks in memory only.
+    pub fn memory() -> Self {
+        Self {
+            backend: Backend::Memory(vec![]),
+            rewinds: 0,
+        }
+    }
+
+    /// Open (or create) a persistent store in the directory `dir`.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.join(BLOCKS_FILE))?;
+
+        // Scan the log to find the offset of each complete record.
+        let mut offsets = vec![];
+        let mut end = 0;
+        let mut reader = BufReader::new(&file);
+        loop {
+            let mut len = [0; 8];
+            match reader.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+            let len = u64::from_le_bytes(len);
+            match io::copy(&mut (&mut reader).take(len), &mut io::sink()) {
+                Ok(n) if n == len => {}
+                Ok(_) => break,
+                Err(err) => return Err(err),
+            }
+            offsets.push(end);
+            end += 8 + len;
+        }
+
+        let file_len = file.metadata()?.len();
+        if file_len > end {
+            tracing::warn!(
+                "discarding {} bytes of incomplete block data at end of {}",
+                file_len - end,
+                dir.join(BLOCKS_FILE).display()
+            );
+            file.set_len(end)?;
+        }
+        tracing::info!(
+            "opened block store at {} with {} blocks",
+            dir.display(),
+            offsets.len()
+        );
+
+        Ok(Self {
+            backend: Backend::File { file, offsets, end },
+            rewinds: 0,
+        })
+    }
+
+    /// Copy the persistent store in the directory `src` to the directory `dst`, and open the copy.
+    ///
+    /// The original store is only read, so it can be copied while another process is appending to
+    /// it. A record which was only partially written at the time of the copy is discarded from the
+    /// copy.
+    pub fn copy(src: &Path, dst: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dst)?;
+        fs::copy(src.join(BLOCKS_FILE), dst.join(BLOCKS_FILE))?;
+        Self::open(dst)
+    }
+
+    /// Move the persistent store in the directory `src` to the directory `dst`.
+    ///
+    /// Any store already in `dst` is replaced. Neither store may be open.
+    pub fn rename(src: &Path, dst: &Path) -> io::Result<()> {
+        fs::create_dir_all(dst)?;
+        fs::rename(src.join(BLOCKS_FILE), dst.join(BLOCKS_FILE))
+    }
+
+    /// The number of blocks in the store.
+    ///
+    /// This is also the height of the next block to be appended.
+    pub fn height(&self) -> u64 {
+        match &self.backend {
+            Backend::Memory(blocks) => blocks.len() as u64,
+            Backend::File { offsets, .. } => offsets.len() as u64,
+        }
+    }
+
+    /// Get the block at `height`, if it is in the store.
+    pub fn get(&self, height: u64) -> io::Result<Option<PolygonZkevmBlock>> {
+        match &self.backend {
+            Backend::Memory(blocks) => Ok(blocks.get(height as usize).cloned()),
+            Backend::File { file, offsets, .. } => {
+                let Some(offset) = offsets.get(height as usize) else {
+                    return Ok(None);
+                };
+                // Use positional reads, so that concurrent readers don't interfere with each other
+                // through the shared file cursor.
+                let mut len = [0; 8];
+                file.read_exact_at(&mut len, *offset)?;
+                let mut buf = vec![0; u64::from_le_bytes(len) as usize];
+                file.read_exact_at(&mut buf, *offset + 8)?;
+                bincode::deserialize(&buf)
+                    .map(Some)
+                    .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+            }
+        }
+    }
+
+    /// Append the next block to the store.
+    ///
+    /// The height of `block` must be equal to [`height`](Self::height).
+    pub fn append(&mut self, block: &PolygonZkevmBlock) -> io::Result<()> {
+        if block.height != self.height() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "cannot append block {} to store of height {}",
+                    block.height,
+                    self.height()
+                ),
+            ));
+        }
+
+        match &mut self.backend {
+            Backend::Memory(blocks) => blocks.push(block.clone()),
+            Backend::File { file, offsets, end } => {
+                let data = bincode::serialize(block)
+                    .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+                file.seek(SeekFrom::Start(*end))?;
+                file.write_all(&(data.len() as u64).to_le_bytes())?;
+                file.write_all(&data)?;
+                file.sync_data()?;
+                offsets.push(*end);
+                *end += 8 + data.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove all blocks at or above `height` from the store.
+    pub fn truncate(&mut self, height: u64) -> io::Result<()> {
+        if height >= self.height() {
+            return Ok(());
+        }
+
+        match &mut self.backend {
+            Backend::Memory(blocks) => blocks.truncate(height as usize),
+            Backend::File { file, offsets, end } => {
+                *end = offsets[height as usize];
+                file.set_len(*end)?;
+                file.sync_data()?;
+                offsets.truncate(height as usize);
+            }
+        }
+        self.rewinds += 1;
+        Ok(())
+    }
+
+    /// Make sure everything written to the store is persisted.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.backend {
+            Backend::Memory(_) => Ok(()),
+            Backend::File { file, .. } => file.sync_all(),
+        }
+    }
+
+    /// The number of times blocks have been removed from the store by [`truncate`](Self::truncate).
+    ///
+    /// Readers following the end of the store can use this to detect that blocks they have
+    /// already seen have been replaced.
+    pub fn rewinds(&self) -> u64 {
+        self.rewinds
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn block(height: u64) -> PolygonZkevmBlock {
+        PolygonZkevmBlock {
+            timestamp: 1000 + height,
+            height,
+            l1_block: height / 2,
+            transactions: format!("0x{height:02x}"),
+        }
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = BlockStore::memory();
+        for i in 0..3 {
+            store.append(&block(i)).unwrap();
+        }
+        assert_eq!(store.height(), 3);
+        assert_eq!(store.get(1).unwrap(), Some(block(1)));
+        assert_eq!(store.get(3).unwrap(), None);
+        store.append(&block(5)).unwrap_err();
+    }
+
+    #[test]
+    fn test_persistent_store() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut store = BlockStore::open(dir.path()).unwrap();
+            assert_eq!(store.height(), 0);
+            for i in 0..3 {
+                store.append(&block(i)).unwrap();
+            }
+        }
+
+        // Reopen the store and check that the blocks were persisted.
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.height(), 3);
+        for i in 0..3 {
+            assert_eq!(store.get(i).unwrap(), Some(block(i)));
+        }
+        assert_eq!(store.get(3).unwrap(), None);
+
+        // We can continue appending after reopening.
+        store.append(&block(3)).unwrap();
+        assert_eq!(store.get(3).unwrap(), Some(block(3)));
+    }
+
+    #[test]
+    fn test_incomplete_record() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut store = BlockStore::open(dir.path()).unwrap();
+            for i in 0..2 {
+                store.append(&block(i)).unwrap();
+            }
+        }
+
+        // Simulate a crash in the middle of writing a record.
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(BLOCKS_FILE))
+            .unwrap();
+        file.write_all(&100u64.to_le_bytes()).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.height(), 2);
+        store.append(&block(2)).unwrap();
+        drop(store);
+
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.height(), 3);
+        assert_eq!(store.get(2).unwrap(), Some(block(2)));
+    }
+
+    #[test]
+    fn test_incomplete_length() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut store = BlockStore::open(dir.path()).unwrap();
+            for i in 0..2 {
+                store.append(&block(i)).unwrap();
+            }
+        }
+        let len = fs::metadata(dir.path().join(BLOCKS_FILE)).unwrap().len();
+
+        // Simulate a crash in the middle of writing the length of a record.
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(BLOCKS_FILE))
+            .unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.height(), 2);
+        assert_eq!(
+            fs::metadata(dir.path().join(BLOCKS_FILE)).unwrap().len(),
+            len
+        );
+        for i in 0..2 {
+            assert_eq!(store.get(i).unwrap(), Some(block(i)));
+        }
+        store.append(&block(2)).unwrap();
+        drop(store);
+
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.height(), 3);
+        assert_eq!(store.get(2).unwrap(), Some(block(2)));
+    }
+
+    #[test]
+    fn test_migrate_legacy_store() {
+        let storage = TempDir::new().unwrap();
+        let dir = storage.path().join("1001");
+        {
+            let mut store = BlockStore::open(storage.path()).unwrap();
+            for i in 0..2 {
+                store.append(&block(i)).unwrap();
+            }
+        }
+
+        assert!(migrate_legacy_store(storage.path(), &dir).unwrap());
+        assert!(!storage.path().join(BLOCKS_FILE).exists());
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.height(), 2);
+        assert_eq!(store.get(1).unwrap(), Some(block(1)));
+
+        // Once migrated, there is nothing left to do.
+        assert!(!migrate_legacy_store(storage.path(), &dir).unwrap());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let dir = TempDir::new().unwrap();
+        for mut store in [BlockStore::memory(), BlockStore::open(dir.path()).unwrap()] {
+            for i in 0..4 {
+                store.append(&block(i)).unwrap();
+            }
+            store.truncate(2).unwrap();
+            assert_eq!(store.height(), 2);
+            assert_eq!(store.rewinds(), 1);
+            assert_eq!(store.get(2).unwrap(), None);
+
+            // Truncating beyond the end does nothing.
+            store.truncate(5).unwrap();
+            assert_eq!(store.rewinds(), 1);
+
+            // We can append replacement blocks after truncating.
+            let mut replacement = block(2);
+            replacement.l1_block += 1;
+            store.append(&replacement).unwrap();
+            assert_eq!(store.get(2).unwrap(), Some(replacement));
+        }
+
+        // The truncation is persisted.
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.height(), 3);
+    }
+}
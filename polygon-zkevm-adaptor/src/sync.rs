@@ -0,0 +1,387 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Syncing blocks from the sequencer into the adaptor's block store.
+//!
+//! By default, the adaptor subscribes to the sequencer's block stream, so that new blocks are
+//! translated as soon as they are sequenced. Whenever the stream cannot be established, or drops,
+//! the adaptor falls back to polling the sequencer for new blocks, which also fills in any blocks
+//! that were missed while the stream was down, and tries to resubscribe with exponential backoff.
+
+use crate::{
+    confirmations::Confirmations,
+    events::{Event as StreamEvent, EventStream},
+    gas_oracle::{BlockUsage, GasPriceOracle},
+    query_service::PolygonZkevmBlock,
+    storage::BlockStore,
+    verify::{verify_payload, Verifier},
+};
+use async_std::{
+    sync::{Arc, RwLock},
+    task::sleep,
+};
+use clap::Args;
+use event_listener::Event;
+use futures::StreamExt;
+use hotshot_query_service::availability::BlockQueryData;
+use sequencer::SeqTypes;
+use std::{
+    num::ParseIntError,
+    time::{Duration, Instant},
+};
+use tide_disco::error::ServerError;
+use zkevm::ZkEvm;
+
+type HotShotClient = surf_disco::Client<ServerError>;
+
+/// How long to wait between polling the sequencer for new blocks.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait between checking the HotShot contract for the commitment to a block.
+const VERIFY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before the first attempt to resubscribe to the sequencer's block stream.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Args, Clone, Debug)]
+pub struct SyncOptions {
+    /// Only poll the sequencer for new blocks, instead of subscribing to its block stream.
+    #[clap(long, env = "ESPRESSO_ZKEVM_ADAPTOR_SYNC_POLL_ONLY")]
+    pub sync_poll_only: bool,
+
+    /// Maximum delay in milliseconds between attempts to resubscribe to the sequencer's block
+    /// stream.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_SYNC_MAX_BACKOFF",
+        default_value = "30000",
+        value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(Duration::from_millis(arg.parse()?)) }
+    )]
+    pub sync_max_backoff: Duration,
+
+    /// Interval in milliseconds between checks for L1 reorgs affecting synced blocks.
+    #[clap(
+        long,
+        env = "ESPRESSO_ZKEVM_ADAPTOR_L1_REORG_CHECK_INTERVAL",
+        default_value = "5000",
+        value_parser = |arg: &str| -> Result<Duration, ParseIntError> { Ok(Duration::from_millis(arg.parse()?)) }
+    )]
+    pub l1_reorg_check_interval: Duration,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            sync_poll_only: false,
+            sync_max_backoff: Duration::from_secs(30),
+            l1_reorg_check_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Exponential backoff between `MIN_BACKOFF` and a configurable maximum.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(max: Duration) -> Self {
+        Self {
+            next: MIN_BACKOFF.min(max),
+            max,
+        }
+    }
+
+    /// The delay before the next attempt, doubling the delay for the attempt after that.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Go back to the minimum delay, after a successful attempt.
+    pub fn reset(&mut self) {
+        self.next = MIN_BACKOFF.min(self.max);
+    }
+}
+
+/// A rollup whose blocks are synced from the sequencer.
+#[derive(Clone)]
+pub struct Rollup {
+    pub zkevm: ZkEvm,
+    pub store: Arc<RwLock<BlockStore>>,
+    /// Notified whenever a new block is added to `store`.
+    pub new_block: Arc<Event>,
+    pub confirmations: Arc<Confirmations>,
+    pub gas_oracle: Option<Arc<dyn GasPriceOracle>>,
+}
+
+/// Fetch new blocks from the sequencer and add them to the store of each of `rollups`.
+///
+/// All rollups share a single subscription to the sequencer, so the load on the sequencer does not
+/// grow with the number of rollups. Syncing starts from the lowest height of any of the stores, so
+/// that a restarted adaptor picks up where it left off, and blocks are only added to stores which
+/// don't have them yet. This function never returns. If a `verifier` is given, each block is only
+/// stored once it has been verified against the HotShot contract. Transactions in each block are
+/// reported to `confirmations`, and its usage to the gas oracle, once the block is stored.
+pub async fn sync_blocks(
+    opt: SyncOptions,
+    hotshot: HotShotClient,
+    verifier: Option<Verifier>,
+    rollups: Vec<Rollup>,
+    events: Arc<EventStream>,
+) {
+    assert!(!rollups.is_empty(), "no rollups to sync");
+    hotshot.connect(None).await;
+    let syncer = Syncer {
+        hotshot,
+        verifier,
+        rollups,
+        events,
+    };
+
+    if opt.sync_poll_only {
+        loop {
+            syncer.poll().await;
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    let mut backoff = Backoff::new(opt.sync_max_backoff);
+    loop {
+        // Fill in any blocks we missed since the last time we were subscribed, so the stream picks
+        // up right where the stores end.
+        syncer.poll().await;
+        if syncer.stream(&mut backoff).await {
+            tracing::warn!("block stream from sequencer ended, falling back to polling");
+        }
+
+        // Keep polling until it is time to try to resubscribe.
+        let retry_at = Instant::now() + backoff.next_delay();
+        while Instant::now() < retry_at {
+            sleep(POLL_INTERVAL.min(retry_at.saturating_duration_since(Instant::now()))).await;
+            syncer.poll().await;
+        }
+    }
+}
+
+struct Syncer {
+    hotshot: HotShotClient,
+    verifier: Option<Verifier>,
+    rollups: Vec<Rollup>,
+    events: Arc<EventStream>,
+}
+
+impl Syncer {
+    /// The height of the next block needed by any of the rollups.
+    async fn height(&self) -> u64 {
+        let mut height = u64::MAX;
+        for rollup in &self.rollups {
+            height = height.min(rollup.store.read().await.height());
+        }
+        height
+    }
+
+    /// Fetch blocks one at a time until every store is caught up with the sequencer.
+    async fn poll(&self) {
+        let sequencer_height = match self.hotshot.get::<u64>("status/block-height").send().await {
+            Ok(height) => height,
+            Err(err) => {
+                tracing::warn!("unable to get block height from sequencer: {err}");
+                self.sequencer_error(format!("unable to get block height: {err}"));
+                return;
+            }
+        };
+
+        let mut height = self.height().await;
+        while height < sequencer_height {
+            let block: BlockQueryData<SeqTypes> = match self
+                .hotshot
+                .get(&format!("availability/block/{height}"))
+                .send()
+                .await
+            {
+                Ok(block) => block,
+                Err(err) => {
+                    tracing::warn!("unable to fetch block {height} from sequencer: {err}");
+                    self.sequencer_error(format!("unable to fetch block {height}: {err}"));
+                    return;
+                }
+            };
+            if !self.append(&block).await {
+                return;
+            }
+            height += 1;
+        }
+    }
+
+    /// Subscribe to the sequencer's block stream and store blocks until the stream fails.
+    ///
+    /// Returns `true` if the subscription was established. `backoff` is only reset once a block from
+    /// the stream has been stored, so that a stream which fails as soon as it is established is
+    /// retried with increasing delays, like a failed subscription.
+    async fn stream(&self, backoff: &mut Backoff) -> bool {
+        let from = self.height().await;
+        let mut blocks = match self
+            .hotshot
+            .socket(&format!("availability/stream/blocks/{from}"))
+            .subscribe::<BlockQueryData<SeqTypes>>()
+            .await
+        {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                tracing::warn!("unable to subscribe to blocks from sequencer: {err}");
+                self.sequencer_error(format!("unable to subscribe to blocks: {err}"));
+                return false;
+            }
+        };
+        tracing::info!("subscribed to blocks from sequencer starting at {from}");
+        let mut stored = false;
+
+        while let Some(block) = blocks.next().await {
+            let block = match block {
+                Ok(block) => block,
+                Err(err) => {
+                    tracing::warn!("error in block stream from sequencer: {err}");
+                    self.sequencer_error(format!("error in block stream: {err}"));
+                    break;
+                }
+            };
+            let height = self.height().await;
+            if block.height() < height {
+                // We already have this block, e.g. because we polled for it while resubscribing.
+                continue;
+            }
+            if block.height() > height {
+                // Either the stream skipped a block, or a store was rewound by an L1 reorg.
+                tracing::warn!(
+                    "block stream skipped from {height} to {}, resyncing",
+                    block.height()
+                );
+                break;
+            }
+            if !self.append(&block).await {
+                break;
+            }
+            if !stored {
+                backoff.reset();
+                stored = true;
+            }
+        }
+        true
+    }
+
+    /// Translate and store the next block for each rollup which needs it, notifying anyone waiting
+    /// for new blocks.
+    ///
+    /// Returns `false` if the block could not be stored.
+    async fn append(&self, block: &BlockQueryData<SeqTypes>) -> bool {
+        let mut rollups = vec![];
+        for rollup in &self.rollups {
+            if rollup.store.read().await.height() == block.height() {
+                rollups.push(rollup);
+            }
+        }
+        if rollups.is_empty() {
+            return true;
+        }
+
+        if !self.verify(block, &rollups).await {
+            return false;
+        }
+
+        let mut stored = true;
+        for rollup in rollups {
+            let mut store = rollup.store.write().await;
+            if store.height() != block.height() {
+                // The store was rewound while we were verifying the block; we will get back to it.
+                continue;
+            }
+            if let Err(err) = store.append(&PolygonZkevmBlock::new(rollup.zkevm, block)) {
+                tracing::error!(
+                    "failed to store block {} for rollup {}: {err}",
+                    block.height(),
+                    rollup.zkevm.chain_id
+                );
+                stored = false;
+                continue;
+            }
+            drop(store);
+            // Only report transactions once the block is verified, so that clients are never told
+            // a transaction is confirmed on the strength of a block we refuse to serve.
+            rollup.confirmations.record_block(rollup.zkevm, block);
+            if let Some(oracle) = &rollup.gas_oracle {
+                oracle.record_block(BlockUsage::new(rollup.zkevm, block));
+            }
+            rollup.new_block.notify(usize::MAX);
+            self.events.emit(StreamEvent::BlockForwarded {
+                chain_id: rollup.zkevm.chain_id,
+                height: block.height(),
+                transactions: rollup.zkevm.vm_transactions(block.payload()).len(),
+            });
+        }
+        stored
+    }
+
+    fn sequencer_error(&self, error: String) {
+        for rollup in &self.rollups {
+            self.events.emit(StreamEvent::SequencerError {
+                chain_id: rollup.zkevm.chain_id,
+                error: error.clone(),
+            });
+        }
+    }
+
+    /// Check the transactions in `block` for each of `rollups` against its header, then wait until
+    /// the header is committed in the HotShot contract and check that it matches.
+    ///
+    /// Returns `true` if the block was verified, or if verification is disabled.
+    async fn verify(&self, block: &BlockQueryData<SeqTypes>, rollups: &[&Rollup]) -> bool {
+        let Some(verifier) = &self.verifier else {
+            return true;
+        };
+        for rollup in rollups {
+            if let Err(err) = verify_payload(rollup.zkevm, block) {
+                tracing::error!("refusing to serve unverified block: {err}");
+                return false;
+            }
+        }
+        loop {
+            match verifier.verify(block).await {
+                Ok(true) => return true,
+                Ok(false) => {
+                    tracing::debug!("waiting for block {} to be committed", block.height());
+                    sleep(VERIFY_INTERVAL).await;
+                }
+                Err(err) => {
+                    tracing::error!("refusing to serve unverified block: {err}");
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Harness for end-to-end tests against the full stack.
+//!
+//! [`TestHarness`] starts the L1, the Espresso sequencer, the adaptor and the zkEVM nodes using
+//! [`SequencerZkEvmDemo`], waits until every service is ready to handle requests, and hands out
+//! clients and funded wallets for each of them. Everything is torn down when the harness is dropped.
+//!
+//! ```ignore
+//! #[async_std::test]
+//! async fn test_transfer() {
+//!     let harness = TestHarness::start("test-transfer").await;
+//!     let l2 = harness.l2_wallet(0).await;
+//!     // ...
+//! }
+//! ```
+#![cfg(any(test, feature = "testing"))]
+
+use crate::{SequencerZkEvmDemo, SequencerZkEvmDemoOptions, ZkEvmEnv};
+use async_compatibility_layer::logging::{setup_backtrace, setup_logging};
+use async_std::task::sleep;
+use ethers::{prelude::*, providers::Middleware};
+use futures::stream::{TryStream, TryStreamExt};
+use hotshot_query_service::availability::BlockQueryData;
+use http_types::Url;
+use sequencer::SeqTypes;
+use sequencer_utils::{connect_rpc, wait_for_http, Signer};
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+use tide_disco::error::ServerError;
+use zkevm::ZkEvm;
+use zkevm_contract_bindings::TestPolygonContracts;
+
+pub type SequencerClient = surf_disco::Client<hotshot_query_service::Error>;
+pub type AdaptorClient = surf_disco::Client<ServerError>;
+
+/// How long to wait for each service to become ready.
+const READY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long to wait between readiness checks.
+const READY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A running instance of the full stack.
+pub struct TestHarness {
+    demo: SequencerZkEvmDemo,
+    sequencer: SequencerClient,
+    adaptor: AdaptorClient,
+}
+
+impl TestHarness {
+    /// Start the full stack with the default options, and wait for it to be ready.
+    ///
+    /// `name` is used as the docker compose project name, so it must be unique among tests which
+    /// may run at the same time.
+    pub async fn start(name: &str) -> Self {
+        Self::start_with(name, SequencerZkEvmDemoOptions::default()).await
+    }
+
+    /// Start the full stack with custom options, and wait for it to be ready.
+    pub async fn start_with(name: &str, opt: SequencerZkEvmDemoOptions) -> Self {
+        setup_logging();
+        setup_backtrace();
+
+        // This waits for the L1 and the L2 nodes.
+        let demo = opt.start(name.to_string()).await;
+        let env = demo.env();
+        let harness = Self {
+            sequencer: SequencerClient::new(env.sequencer()),
+            adaptor: AdaptorClient::new(env.l2_adaptor_query()),
+            demo,
+        };
+        harness.wait_for_ready().await;
+        harness
+    }
+
+    async fn wait_for_ready(&self) {
+        let env = self.env();
+
+        tracing::info!("connecting to sequencer at {}", env.sequencer());
+        assert!(
+            self.sequencer.connect(Some(READY_TIMEOUT)).await,
+            "sequencer did not start"
+        );
+
+        // The adaptor is not a full RPC, so we can't use `wait_for_rpc`. Instead, wait until it is
+        // serving HTTP, and then until it reports that it is ready.
+        tracing::info!("connecting to adaptor RPC at {}", env.l2_adaptor_rpc());
+        let attempts = (READY_TIMEOUT.as_secs() / READY_INTERVAL.as_secs()) as usize;
+        wait_for_http(&env.l2_adaptor_rpc(), READY_INTERVAL, attempts)
+            .await
+            .expect("adaptor RPC did not start");
+        tracing::info!(
+            "connecting to adaptor query service at {}",
+            env.l2_adaptor_query()
+        );
+        assert!(
+            self.adaptor.connect(Some(READY_TIMEOUT)).await,
+            "adaptor query service did not start"
+        );
+        wait_for_ready(&env.l2_adaptor_rpc().join("readyz").unwrap()).await;
+
+        tracing::info!("all services ready");
+    }
+
+    pub fn demo(&self) -> &SequencerZkEvmDemo {
+        &self.demo
+    }
+
+    pub fn env(&self) -> &ZkEvmEnv {
+        self.demo.env()
+    }
+
+    /// The contracts deployed to the L1.
+    pub fn contracts(&self) -> &TestPolygonContracts {
+        self.demo.l1()
+    }
+
+    /// Client for the sequencer API.
+    pub fn sequencer(&self) -> &SequencerClient {
+        &self.sequencer
+    }
+
+    /// Client for the adaptor's query service.
+    pub fn adaptor(&self) -> &AdaptorClient {
+        &self.adaptor
+    }
+
+    /// The L2 rollup served by the stack.
+    pub async fn zkevm(&self) -> ZkEvm {
+        let chain_id = match self.env().l2_chain_id() {
+            Some(chain_id) => chain_id,
+            None => self
+                .l2_wallet(0)
+                .await
+                .get_chainid()
+                .await
+                .unwrap()
+                .as_u64(),
+        };
+        ZkEvm { chain_id }
+    }
+
+    /// A wallet on the L1, for account `index` of the funded mnemonic.
+    pub async fn l1_wallet(&self, index: u32) -> Signer {
+        self.wallet(&self.env().l1_provider(), index).await
+    }
+
+    /// A wallet on the L2 node, for account `index` of the funded mnemonic.
+    pub async fn l2_wallet(&self, index: u32) -> Signer {
+        self.wallet(&self.env().l2_provider(), index).await
+    }
+
+    /// A wallet on the L2 preconfirmations node, for account `index` of the funded mnemonic.
+    pub async fn l2_preconfirmations_wallet(&self, index: u32) -> Signer {
+        self.wallet(&self.env().l2_preconfirmations_provider(), index)
+            .await
+    }
+
+    async fn wallet(&self, provider: &Url, index: u32) -> Signer {
+        connect_rpc(provider, self.env().funded_mnemonic(), index, None)
+            .await
+            .unwrap_or_else(|| panic!("unable to connect wallet {index} to {provider}"))
+    }
+}
+
+/// Poll a health check endpoint until it reports success.
+async fn wait_for_ready(url: &Url) {
+    let start = Instant::now();
+    loop {
+        match surf::get(url).await {
+            Ok(res) if res.status().is_success() => return,
+            Ok(res) => tracing::info!("{url} not ready yet: {}", res.status()),
+            Err(err) => tracing::info!("{url} not ready yet: {err}"),
+        }
+        assert!(
+            start.elapsed() < READY_TIMEOUT,
+            "{url} not ready after {READY_TIMEOUT:?}"
+        );
+        sleep(READY_INTERVAL).await;
+    }
+}
+
+/// Wait for a block containing the L2 transaction `hash`, returning its height.
+pub async fn wait_for_block_containing_txn<B>(mut blocks: B, zkevm: ZkEvm, hash: H256) -> u64
+where
+    B: TryStream<Ok = BlockQueryData<SeqTypes>> + Unpin,
+    B::Error: Debug,
+{
+    loop {
+        let block = blocks.try_next().await.unwrap().unwrap();
+        tracing::info!("got block {:?}", block);
+        for txn in zkevm.vm_transactions(block.payload()) {
+            let sequenced_hash = txn.hash();
+            if sequenced_hash == hash {
+                tracing::info!("transaction {hash} sequenced");
+                return block.height();
+            } else {
+                tracing::info!("unknown transaction {sequenced_hash} sequenced");
+            }
+        }
+    }
+}
+
+/// Wait for `rpc` to have a receipt for transaction `hash`, returning when the receipt arrived.
+pub async fn await_transaction(rpc: &impl Middleware, hash: H256) -> Instant {
+    // Note that awaiting a [PendingTransaction] will not work here -- [PendingTransaction] returns
+    // [None] if the transaction is thrown out of the mempool, but since we bypassed the sequencer,
+    // our transactions were never in the mempool in the first place.
+    loop {
+        if let Some(receipt) = rpc.get_transaction_receipt(hash).await.unwrap() {
+            tracing::info!("transfer {hash} completed: {receipt:?}");
+            break;
+        }
+        tracing::info!("Waiting for transfer {hash} to complete");
+        sleep(Duration::from_secs(1)).await;
+    }
+    Instant::now()
+}
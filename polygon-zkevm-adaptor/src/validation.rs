@@ -0,0 +1,281 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Validation of raw transactions before they are forwarded to the sequencer.
+//!
+//! The sequencer treats transactions as opaque bytes, so anything the RPC adaptor forwards will be
+//! sequenced, even if the zkEVM node is going to throw it away when it executes the block. To avoid
+//! wasting sequencer space, and to give users a useful error instead of a hash for a transaction
+//! that will never execute, the adaptor performs the stateless checks that the zkEVM node would
+//! otherwise perform after the fact: size limits, decoding, signature recovery, replay protection
+//! and intrinsic gas. Stateful checks (nonce, balance) are left to the node, since the adaptor does
+//! not track L2 state.
+
+use ethers::{
+    types::{transaction::eip2718::TypedTransaction, Address, H256, U256},
+    utils::{keccak256, rlp::Rlp},
+};
+use jsonrpc_v2::Error as RpcError;
+use snafu::Snafu;
+
+/// Default maximum size of an encoded transaction, matching the zkEVM node's pool limit.
+pub const DEFAULT_MAX_TRANSACTION_SIZE: usize = 100132;
+
+/// Gas charged for every transaction which is not a contract creation.
+pub const TX_GAS: u64 = 21000;
+/// Gas charged for every contract creation transaction.
+pub const TX_GAS_CONTRACT_CREATION: u64 = 53000;
+/// Gas charged per zero byte of calldata.
+pub const TX_DATA_ZERO_GAS: u64 = 4;
+/// Gas charged per non-zero byte of calldata.
+pub const TX_DATA_NON_ZERO_GAS: u64 = 16;
+/// Gas charged per address in an EIP-2930 access list.
+pub const TX_ACCESS_LIST_ADDRESS_GAS: u64 = 2400;
+/// Gas charged per storage key in an EIP-2930 access list.
+pub const TX_ACCESS_LIST_STORAGE_KEY_GAS: u64 = 1900;
+
+/// JSON-RPC error code for requests with invalid parameters.
+pub const INVALID_PARAMS: i64 = -32602;
+/// JSON-RPC error code for well-formed transactions which are rejected (EIP-1474).
+pub const TRANSACTION_REJECTED: i64 = -32003;
+
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum ValidationError {
+    #[snafu(display("oversized data: transaction size {size}, limit {limit}"))]
+    TooLarge { size: usize, limit: usize },
+
+    #[snafu(display("rlp: {reason}"))]
+    Decode { reason: String },
+
+    #[snafu(display("invalid sender: {reason}"))]
+    InvalidSignature { reason: String },
+
+    #[snafu(display("only replay-protected (EIP-155) transactions allowed"))]
+    MissingChainId,
+
+    #[snafu(display("invalid chain id: have {actual}, want {expected}"))]
+    WrongChainId { expected: u64, actual: u64 },
+
+    #[snafu(display("intrinsic gas too low: have {gas}, want {intrinsic}"))]
+    IntrinsicGasTooLow { gas: U256, intrinsic: u64 },
+}
+
+impl ValidationError {
+    /// The JSON-RPC error code used to report this error to the client.
+    pub fn code(&self) -> i64 {
+        match self {
+            Self::TooLarge { .. } | Self::Decode { .. } | Self::InvalidSignature { .. } => {
+                INVALID_PARAMS
+            }
+            Self::MissingChainId | Self::WrongChainId { .. } | Self::IntrinsicGasTooLow { .. } => {
+                TRANSACTION_REJECTED
+            }
+        }
+    }
+}
+
+impl From<ValidationError> for RpcError {
+    fn from(err: ValidationError) -> Self {
+        RpcError::Full {
+            code: err.code(),
+            message: err.to_string(),
+            data: None,
+        }
+    }
+}
+
+/// A transaction which has passed validation.
+#[derive(Clone, Debug)]
+pub struct ValidatedTransaction {
+    pub tx: TypedTransaction,
+    pub sender: Address,
+    pub hash: H256,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TransactionValidator {
+    chain_id: u64,
+    max_size: usize,
+}
+
+impl TransactionValidator {
+    pub fn new(chain_id: u64, max_size: usize) -> Self {
+        Self { chain_id, max_size }
+    }
+
+    /// Check that `raw` is a transaction the zkEVM node would be willing to execute.
+    pub fn validate(&self, raw: &[u8]) -> Result<ValidatedTransaction, ValidationError> {
+        if raw.len() > self.max_size {
+            return Err(ValidationError::TooLarge {
+                size: raw.len(),
+                limit: self.max_size,
+            });
+        }
+
+        let (tx, sig) = TypedTransaction::decode_signed(&Rlp::new(raw)).map_err(|err| {
+            ValidationError::Decode {
+                reason: err.to_string(),
+            }
+        })?;
+
+        match tx.chain_id() {
+            None => return Err(ValidationError::MissingChainId),
+            Some(id) if id.as_u64() != self.chain_id => {
+                return Err(ValidationError::WrongChainId {
+                    expected: self.chain_id,
+                    actual: id.as_u64(),
+                })
+            }
+            Some(_) => {}
+        }
+
+        let sender =
+            sig.recover(tx.sighash())
+                .map_err(|err| ValidationError::InvalidSignature {
+                    reason: err.to_string(),
+                })?;
+
+        let intrinsic = intrinsic_gas(&tx);
+        let gas = tx.gas().copied().unwrap_or_default();
+        if gas < intrinsic.into() {
+            return Err(ValidationError::IntrinsicGasTooLow { gas, intrinsic });
+        }
+
+        Ok(ValidatedTransaction {
+            tx,
+            sender,
+            hash: keccak256(raw).into(),
+        })
+    }
+}
+
+/// The minimum amount of gas a transaction must pay for before it executes any code.
+pub fn intrinsic_gas(tx: &TypedTransaction) -> u64 {
+    let mut gas = if tx.to().is_none() {
+        TX_GAS_CONTRACT_CREATION
+    } else {
+        TX_GAS
+    };
+    if let Some(data) = tx.data() {
+        let zeros = data.iter().filter(|b| **b == 0).count() as u64;
+        let non_zeros = data.len() as u64 - zeros;
+        gas += zeros * TX_DATA_ZERO_GAS + non_zeros * TX_DATA_NON_ZERO_GAS;
+    }
+    if let Some(access_list) = tx.access_list() {
+        for item in &access_list.0 {
+            gas += TX_ACCESS_LIST_ADDRESS_GAS
+                + item.storage_keys.len() as u64 * TX_ACCESS_LIST_STORAGE_KEY_GAS;
+        }
+    }
+    gas
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ethers::{
+        signers::{LocalWallet, Signer},
+        types::{Bytes, TransactionRequest},
+    };
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+    const CHAIN_ID: u64 = 1001;
+
+    fn wallet() -> LocalWallet {
+        LocalWallet::new(&mut ChaChaRng::seed_from_u64(0)).with_chain_id(CHAIN_ID)
+    }
+
+    fn transfer() -> TransactionRequest {
+        TransactionRequest::new()
+            .to(Address::zero())
+            .value(1)
+            .nonce(0)
+            .gas(TX_GAS)
+            .gas_price(1)
+            .chain_id(CHAIN_ID)
+    }
+
+    fn sign(wallet: &LocalWallet, tx: impl Into<TypedTransaction>) -> Bytes {
+        let tx = tx.into();
+        let sig = wallet.sign_transaction_sync(&tx).unwrap();
+        tx.rlp_signed(&sig)
+    }
+
+    #[test]
+    fn test_valid_transaction() {
+        let wallet = wallet();
+        let raw = sign(&wallet, transfer());
+        let validator = TransactionValidator::new(CHAIN_ID, DEFAULT_MAX_TRANSACTION_SIZE);
+        let validated = validator.validate(&raw).unwrap();
+        assert_eq!(validated.sender, wallet.address());
+        assert_eq!(validated.hash, H256::from(keccak256(&raw)));
+    }
+
+    #[test]
+    fn test_malformed_transaction() {
+        let validator = TransactionValidator::new(CHAIN_ID, DEFAULT_MAX_TRANSACTION_SIZE);
+        let err = validator.validate(b"\xde\xad\xbe\xef").unwrap_err();
+        assert!(matches!(err, ValidationError::Decode { .. }), "{err}");
+        assert_eq!(err.code(), INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_oversized_transaction() {
+        let wallet = wallet();
+        let raw = sign(&wallet, transfer().data(vec![1; 1000]).gas(100000));
+        let validator = TransactionValidator::new(CHAIN_ID, 500);
+        assert_eq!(
+            validator.validate(&raw).unwrap_err(),
+            ValidationError::TooLarge {
+                size: raw.len(),
+                limit: 500
+            }
+        );
+    }
+
+    #[test]
+    fn test_wrong_chain_id() {
+        let wallet = wallet().with_chain_id(CHAIN_ID + 1);
+        let raw = sign(&wallet, transfer().chain_id(CHAIN_ID + 1));
+        let validator = TransactionValidator::new(CHAIN_ID, DEFAULT_MAX_TRANSACTION_SIZE);
+        assert_eq!(
+            validator.validate(&raw).unwrap_err(),
+            ValidationError::WrongChainId {
+                expected: CHAIN_ID,
+                actual: CHAIN_ID + 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_intrinsic_gas() {
+        let wallet = wallet();
+        let validator = TransactionValidator::new(CHAIN_ID, DEFAULT_MAX_TRANSACTION_SIZE);
+
+        // A plain transfer needs exactly the base transaction gas.
+        let raw = sign(&wallet, transfer().gas(TX_GAS - 1));
+        assert_eq!(
+            validator.validate(&raw).unwrap_err(),
+            ValidationError::IntrinsicGasTooLow {
+                gas: (TX_GAS - 1).into(),
+                intrinsic: TX_GAS
+            }
+        );
+
+        // Calldata is charged per byte, with zero bytes being cheaper.
+        let tx = transfer().data(vec![0, 1]).gas(TX_GAS);
+        let intrinsic = TX_GAS + TX_DATA_ZERO_GAS + TX_DATA_NON_ZERO_GAS;
+        assert_eq!(intrinsic_gas(&tx.clone().into()), intrinsic);
+        let raw = sign(&wallet, tx.clone());
+        assert!(matches!(
+            validator.validate(&raw).unwrap_err(),
+            ValidationError::IntrinsicGasTooLow { .. }
+        ));
+        let raw = sign(&wallet, tx.gas(intrinsic));
+        validator.validate(&raw).unwrap();
+    }
+}
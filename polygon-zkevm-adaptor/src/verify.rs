@@ -0,0 +1,142 @@
+// Copyright (c) 2023 Espresso Systems (espressosys.com)
+// This file is part of the Espresso Sequencer-Polygon zkEVM integration demo.
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU Affero General Public License as published by the Free Software Foundation, either version 3 of the License, or any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Verification of sequencer blocks against the HotShot contract on L1.
+//!
+//! In verification mode, the adaptor does not trust the sequencer query service it fetches blocks
+//! from. Before a block is stored, and thus before it can be served to the zkEVM node, the adaptor
+//! waits for the HotShot commitment service to post a commitment for that block height to the L1,
+//! and checks that the commitment of the block it fetched matches. Blocks which have not been
+//! committed yet are not served at all, so in this mode the adaptor lags behind the sequencer by
+//! the latency of the commitment service and the L1.
+//!
+//! The contract only commits to block headers, so the adaptor also checks that the transactions it
+//! serves for each rollup are the ones committed to by the transactions root in the header, using
+//! the namespace proof for the rollup. Otherwise, the query service could pair a genuine header
+//! with forged transactions.
+
+use commit::Committable;
+use ethers::{
+    contract::ContractError,
+    providers::{Http, Provider},
+    types::{Address, U256},
+};
+use hotshot_query_service::availability::BlockQueryData;
+use jf_primitives::merkle_tree::namespaced_merkle_tree::NamespaceProof;
+use sequencer::{SeqTypes, Vm};
+use snafu::Snafu;
+use std::sync::Arc;
+use surf_disco::Url;
+use zkevm::ZkEvm;
+use zkevm_contract_bindings::i_hot_shot::IHotShot;
+
+#[derive(Debug, Snafu)]
+pub enum VerificationError {
+    #[snafu(display("unable to read commitment from HotShot contract: {source}"))]
+    Contract {
+        source: ContractError<Provider<Http>>,
+    },
+
+    #[snafu(display(
+        "block {height} does not match HotShot contract (expected commitment {expected}, got {actual})"
+    ))]
+    Mismatch {
+        height: u64,
+        expected: U256,
+        actual: U256,
+    },
+
+    #[snafu(display(
+        "transactions for rollup {chain_id} in block {height} do not match the block header"
+    ))]
+    PayloadMismatch { height: u64, chain_id: u64 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Verifier {
+    hotshot: IHotShot<Provider<Http>>,
+}
+
+impl Verifier {
+    pub fn new(l1_provider: &Url, hotshot_address: Address) -> Self {
+        let provider = Provider::try_from(l1_provider.as_str()).unwrap();
+        Self {
+            hotshot: IHotShot::new(hotshot_address, Arc::new(provider)),
+        }
+    }
+
+    /// Check `block` against the commitment for its height in the HotShot contract.
+    ///
+    /// Returns `Ok(false)` if no commitment has been posted for this height yet.
+    pub async fn verify(
+        &self,
+        block: &BlockQueryData<SeqTypes>,
+    ) -> Result<bool, VerificationError> {
+        let expected = self.commitment(block.height()).await?;
+        if expected.is_zero() {
+            return Ok(false);
+        }
+
+        let actual = block_commitment(block);
+        if actual != expected {
+            return Err(VerificationError::Mismatch {
+                height: block.height(),
+                expected,
+                actual,
+            });
+        }
+        Ok(true)
+    }
+
+    /// The number of blocks committed in the HotShot contract, given that it is at least `from`
+    /// and at most `to`.
+    pub async fn committed_height(
+        &self,
+        mut from: u64,
+        mut to: u64,
+    ) -> Result<u64, VerificationError> {
+        // Commitments are posted in order of height, so we can search for the first missing one.
+        while from < to {
+            let mid = from + (to - from) / 2;
+            if self.commitment(mid).await?.is_zero() {
+                to = mid;
+            } else {
+                from = mid + 1;
+            }
+        }
+        Ok(from)
+    }
+
+    /// The commitment for `height` in the HotShot contract, or zero if there is none yet.
+    async fn commitment(&self, height: u64) -> Result<U256, VerificationError> {
+        self.hotshot
+            .commitments(height.into())
+            .call()
+            .await
+            .map_err(|source| VerificationError::Contract { source })
+    }
+}
+
+/// The commitment to `block` as it is stored in the HotShot contract.
+pub fn block_commitment(block: &BlockQueryData<SeqTypes>) -> U256 {
+    U256::from_little_endian(block.header().commit().as_ref())
+}
+
+/// Check that the transactions for `zkevm` in the payload of `block` are the ones committed to by
+/// the header of `block`.
+pub fn verify_payload(
+    zkevm: ZkEvm,
+    block: &BlockQueryData<SeqTypes>,
+) -> Result<(), VerificationError> {
+    let proof = block.payload().get_namespace_proof(zkevm.id());
+    proof
+        .verify(&block.header().transactions_root.root(), zkevm.id())
+        .map_err(|_| VerificationError::PayloadMismatch {
+            height: block.height(),
+            chain_id: zkevm.chain_id,
+        })
+}
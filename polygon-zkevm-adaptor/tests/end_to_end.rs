@@ -5,19 +5,27 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Affero General Public License for more details.
 // You should have received a copy of the GNU Affero General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use async_compatibility_layer::logging::{setup_backtrace, setup_logging};
-use async_std::{sync::Arc, task::sleep};
-use ethers::{prelude::*, providers::Middleware};
-use futures::stream::{StreamExt, TryStream, TryStreamExt};
+use async_std::sync::Arc;
+use ethers::{prelude::*, providers::Middleware, utils::keccak256};
+use futures::stream::StreamExt;
 use hotshot_query_service::availability::BlockQueryData;
-use polygon_zkevm_adaptor::{Layer1Backend, SequencerZkEvmDemo, SequencerZkEvmDemoOptions};
-use sequencer::SeqTypes;
-use sequencer_utils::{connect_rpc, wait_for_http, NonceManager};
-use std::fmt::Debug;
-use std::time::{Duration, Instant};
+use polygon_zkevm_adaptor::{
+    await_transaction, wait_for_block_containing_txn, Layer1Backend, SequencerZkEvmDemoOptions,
+    TestHarness,
+};
+use sequencer::{SeqTypes, Transaction, Vm};
+use sequencer_utils::NonceManager;
+use std::time::Duration;
 use zkevm::ZkEvm;
 use zkevm_contract_bindings::PolygonZkEVM;
 
+#[cfg(feature = "slow-tests")]
+use {
+    async_compatibility_layer::logging::{setup_backtrace, setup_logging},
+    async_std::task::sleep,
+    std::time::Instant,
+};
+
 #[cfg(feature = "slow-tests")]
 struct ReorgMe {
     ports: [u16; 3],
@@ -81,17 +89,11 @@ impl Drop for ReorgMe {
 
 #[async_std::test]
 async fn test_end_to_end() {
-    let node = setup_test("test-end-to-end", Duration::from_secs(1)).await;
-
-    // Get test setup from environment.
-    let env = node.env();
-    let l1_provider = env.l1_provider();
-    let l2_provider = env.l2_provider();
-    let mnemonic = env.funded_mnemonic();
-    let rollup_address = node.l1().rollup.address();
+    let harness = setup_test("test-end-to-end", Duration::from_secs(1)).await;
+    let rollup_address = harness.contracts().rollup.address();
 
-    let l1 = Arc::new(connect_rpc(&l1_provider, mnemonic, 0, None).await.unwrap());
-    let l2_signer = connect_rpc(&l2_provider, mnemonic, 0, None).await.unwrap();
+    let l1 = Arc::new(harness.l1_wallet(0).await);
+    let l2_signer = harness.l2_wallet(0).await;
     let l2_addr = l2_signer.address();
     let l2 = Arc::new(NonceManager::new(l2_signer, l2_addr));
     let zkevm = ZkEvm {
@@ -102,38 +104,32 @@ async fn test_end_to_end() {
     let l2_initial_balance = l2.get_balance(l2_addr, None).await.unwrap();
 
     // Subscribe to a block stream so we can find the blocks that end up including our transactions.
-    tracing::info!("connecting to sequencer at {}", env.sequencer());
-    let sequencer = surf_disco::Client::<hotshot_query_service::Error>::new(env.sequencer());
-    sequencer.connect(None).await;
+    let sequencer = harness.sequencer();
     let mut blocks = sequencer
         .socket("availability/stream/blocks/0")
         .subscribe::<BlockQueryData<SeqTypes>>()
         .await
         .unwrap();
 
-    // Wait for the adaptor to start serving.
-    tracing::info!("connecting to adaptor RPC at {}", env.l2_adaptor_rpc());
-    // The adaptor is not a full RPC, therefore we can't use `wait_for_rpc`.`
-    wait_for_http(&env.l2_adaptor_rpc(), Duration::from_secs(1), 100)
-        .await
-        .unwrap();
-    tracing::info!(
-        "connecting to adaptor query service at {}",
-        env.l2_adaptor_query()
-    );
-    wait_for_http(&env.l2_adaptor_query(), Duration::from_secs(1), 100)
-        .await
-        .unwrap();
-
-    // Send a malformed transaction to test that the system can handle it
-    // gracefully and remains operational.
+    // Send a malformed transaction through the L2 RPC. The adaptor should reject it before it is
+    // forwarded to the sequencer.
     let malformed_tx_payload = b"\xde\xad\xbe\xef";
-    let malformed_tx_hash = l2
+    let err = l2
         .send_raw_transaction(malformed_tx_payload.into())
         .await
+        .unwrap_err();
+    tracing::info!("malformed transaction rejected: {err}");
+    let malformed_tx_hash = H256::from(keccak256(malformed_tx_payload));
+
+    // Submit the same transaction directly to the sequencer, bypassing the adaptor, to test that
+    // the system can handle malformed transactions in a block gracefully and remains operational.
+    sequencer
+        .post::<()>("submit/submit")
+        .body_json(&Transaction::new(zkevm.id(), malformed_tx_payload.to_vec()))
         .unwrap()
-        .tx_hash();
-    tracing::info!("malformed transaction hash: {:?}", malformed_tx_hash);
+        .send()
+        .await
+        .unwrap();
 
     // Wait for the malformed transaction to be included in a block.
     'block: loop {
@@ -218,47 +214,20 @@ async fn test_end_to_end() {
 #[cfg(feature = "slow-tests")]
 #[async_std::test]
 async fn test_preconfirmations() {
-    setup_logging();
-    setup_backtrace();
-
-    let node = setup_test("test-preconfirmations", Duration::from_secs(10)).await;
-    let env = node.env();
-    let mnemonic = env.funded_mnemonic();
-    let l2 = connect_rpc(&env.l2_provider(), mnemonic, 0, None)
-        .await
-        .unwrap();
-    let l2_preconf = connect_rpc(&env.l2_preconfirmations_provider(), mnemonic, 0, None)
-        .await
-        .unwrap();
-    let zkevm = ZkEvm {
-        chain_id: l2.get_chainid().await.unwrap().as_u64(),
-    };
+    let harness = setup_test("test-preconfirmations", Duration::from_secs(10)).await;
+    let l2 = harness.l2_wallet(0).await;
+    let l2_preconf = harness.l2_preconfirmations_wallet(0).await;
+    let zkevm = harness.zkevm().await;
     let l2_initial_balance = l2.get_balance(l2.address(), None).await.unwrap();
 
     // Subscribe to a block stream so we can find the block that ends up including our transaction.
-    tracing::info!("connecting to sequencer at {}", env.sequencer());
-    let sequencer = surf_disco::Client::<hotshot_query_service::Error>::new(env.sequencer());
-    sequencer.connect(None).await;
-    let mut blocks = sequencer
+    let mut blocks = harness
+        .sequencer()
         .socket("availability/stream/blocks/0")
         .subscribe::<BlockQueryData<SeqTypes>>()
         .await
         .unwrap();
 
-    // Wait for the adaptor to start serving.
-    tracing::info!("connecting to adaptor RPC at {}", env.l2_adaptor_rpc());
-    // The adaptor is not a full RPC, therefore we can't use `wait_for_rpc`.`
-    wait_for_http(&env.l2_adaptor_rpc(), Duration::from_secs(1), 100)
-        .await
-        .unwrap();
-    tracing::info!(
-        "connecting to adaptor query service at {}",
-        env.l2_adaptor_query()
-    );
-    wait_for_http(&env.l2_adaptor_query(), Duration::from_secs(1), 100)
-        .await
-        .unwrap();
-
     // Create a test transaction.
     let transfer_amount = 1.into();
     let txn_hash = l2
@@ -347,49 +316,20 @@ async fn test_reorg() {
     setup_backtrace();
 
     let reorgme = ReorgMe::start();
-    let node = setup_test_with_host_l1("test-reorg", reorgme.rpc_port()).await;
+    let harness = setup_test_with_host_l1("test-reorg", reorgme.rpc_port()).await;
 
     tracing::info!("Separating L1 node from the network");
     reorgme.fork();
 
-    let env = node.env();
-    let mnemonic = env.funded_mnemonic();
-    let rollup_address = node.l1().rollup.address();
+    let rollup_address = harness.contracts().rollup.address();
 
-    let l1 = Arc::new(
-        connect_rpc(&env.l1_provider(), mnemonic, 0, None)
-            .await
-            .unwrap(),
-    );
-    let l2 = Arc::new(
-        connect_rpc(&env.l2_provider(), mnemonic, 0, None)
-            .await
-            .unwrap(),
-    );
-    let l2_preconf = connect_rpc(&env.l2_preconfirmations_provider(), mnemonic, 0, None)
-        .await
-        .unwrap();
+    let l1 = Arc::new(harness.l1_wallet(0).await);
+    let l2 = Arc::new(harness.l2_wallet(0).await);
+    let l2_preconf = harness.l2_preconfirmations_wallet(0).await;
     let l2_initial_balance = l2.get_balance(l2.address(), None).await.unwrap();
     let rollup = PolygonZkEVM::new(rollup_address, l1.clone());
 
-    // Wait for the sequencer API to start before we try submitting transactions.
-    tracing::info!("connecting to sequencer at {}", env.sequencer());
-    let sequencer = surf_disco::Client::<hotshot_query_service::Error>::new(env.sequencer());
-    sequencer.connect(None).await;
-
-    // Wait for the adaptor to start serving.
-    tracing::info!("connecting to adaptor RPC at {}", env.l2_adaptor_rpc());
-    // The adaptor is not a full RPC, therefore we can't use `wait_for_rpc`.`
-    wait_for_http(&env.l2_adaptor_rpc(), Duration::from_secs(1), 100)
-        .await
-        .unwrap();
-    tracing::info!(
-        "connecting to adaptor query service at {}",
-        env.l2_adaptor_query()
-    );
-    wait_for_http(&env.l2_adaptor_query(), Duration::from_secs(1), 100)
-        .await
-        .unwrap();
+    let sequencer = harness.sequencer();
 
     // Create a test transaction.
     let transfer_amount = 1.into();
@@ -515,60 +455,23 @@ async fn test_reorg() {
     }
 }
 
-async fn wait_for_block_containing_txn<B>(mut blocks: B, zkevm: ZkEvm, hash: H256) -> u64
-where
-    B: TryStream<Ok = BlockQueryData<SeqTypes>> + Unpin,
-    B::Error: Debug,
-{
-    loop {
-        let block = blocks.try_next().await.unwrap().unwrap();
-        tracing::info!("got block {:?}", block);
-        for txn in zkevm.vm_transactions(block.payload()) {
-            let sequenced_hash = txn.hash();
-            if sequenced_hash == hash {
-                tracing::info!("transaction {hash} sequenced");
-                return block.height();
-            } else {
-                tracing::info!("unknown transaction {sequenced_hash} sequenced");
-            }
-        }
-    }
-}
-
-async fn await_transaction(rpc: &impl Middleware, hash: H256) -> Instant {
-    // Note that awaiting a [PendingTransaction] will not work here -- [PendingTransaction] returns
-    // [None] if the transaction is thrown out of the mempool, but since we bypassed the sequencer,
-    // our transactions were never in the mempool in the first place.
-    loop {
-        if let Some(receipt) = rpc.get_transaction_receipt(hash).await.unwrap() {
-            tracing::info!("transfer {hash} completed: {receipt:?}");
-            break;
-        }
-        tracing::info!("Waiting for transfer {hash} to complete");
-        sleep(Duration::from_secs(1)).await;
-    }
-    Instant::now()
-}
-
-async fn setup_test(name: &str, l1_block_time: Duration) -> SequencerZkEvmDemo {
-    setup_logging();
-    setup_backtrace();
-
-    SequencerZkEvmDemoOptions::default()
-        .l1_backend(Layer1Backend::Anvil)
-        .l1_block_period(l1_block_time)
-        .start(name.to_string())
-        .await
+async fn setup_test(name: &str, l1_block_time: Duration) -> TestHarness {
+    TestHarness::start_with(
+        name,
+        SequencerZkEvmDemoOptions::default()
+            .l1_backend(Layer1Backend::Anvil)
+            .l1_block_period(l1_block_time),
+    )
+    .await
 }
 
 #[cfg(feature = "slow-tests")]
-async fn setup_test_with_host_l1(name: &str, l1_port: u16) -> SequencerZkEvmDemo {
-    setup_logging();
-    setup_backtrace();
-
-    SequencerZkEvmDemoOptions::default()
-        .use_host_l1(l1_port)
-        .l1_backend(Layer1Backend::Anvil)
-        .start(name.to_string())
-        .await
+async fn setup_test_with_host_l1(name: &str, l1_port: u16) -> TestHarness {
+    TestHarness::start_with(
+        name,
+        SequencerZkEvmDemoOptions::default()
+            .use_host_l1(l1_port)
+            .l1_backend(Layer1Backend::Anvil),
+    )
+    .await
 }